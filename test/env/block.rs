@@ -0,0 +1,526 @@
+//! Typed view of the ICRC-1 and ICRC-2 block schemas defined in the
+//! ICRC-3 standard (see standards/ICRC-3/README.md).
+
+use crate::{Account, Value};
+use candid::{Nat, Principal};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// Fields shared by all ICRC-1 and ICRC-2 blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// The `btype` field, absent for blocks using the legacy `tx.op` schema.
+    pub btype: Option<String>,
+    /// The hash of the parent block (`phash`), absent for the first block.
+    pub parent_hash: Option<[u8; 32]>,
+    /// The time when the ledger added the block (`ts`).
+    pub timestamp: u64,
+    /// The fee set by the ledger (`fee`).
+    pub fee: Option<Nat>,
+    /// The fee specified by the caller (`tx.fee`).
+    pub tx_fee: Option<Nat>,
+    /// The account collecting the fees (`fee_col`).
+    pub fee_collector: Option<Account>,
+    /// The index of the block that set the fee collector (`fee_col_block`).
+    pub fee_collector_block: Option<u64>,
+    /// The memo specified by the caller (`tx.memo`).
+    pub memo: Option<Vec<u8>>,
+    /// The creation time specified by the caller (`tx.ts`).
+    pub created_at_time: Option<u64>,
+}
+
+impl BlockInfo {
+    /// Returns the fee paid for the block: the fee specified by the
+    /// caller if present, otherwise the fee set by the ledger.
+    pub fn effective_fee(&self) -> Option<&Nat> {
+        self.tx_fee.as_ref().or(self.fee.as_ref())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Block {
+    Mint {
+        info: BlockInfo,
+        to: Account,
+        amount: Nat,
+    },
+    Burn {
+        info: BlockInfo,
+        from: Account,
+        spender: Option<Account>,
+        amount: Nat,
+    },
+    Transfer {
+        info: BlockInfo,
+        from: Account,
+        to: Account,
+        spender: Option<Account>,
+        amount: Nat,
+    },
+    Approve {
+        info: BlockInfo,
+        from: Account,
+        spender: Account,
+        amount: Nat,
+        expected_allowance: Option<Nat>,
+        expires_at: Option<u64>,
+    },
+}
+
+impl Block {
+    pub fn info(&self) -> &BlockInfo {
+        match self {
+            Block::Mint { info, .. }
+            | Block::Burn { info, .. }
+            | Block::Transfer { info, .. }
+            | Block::Approve { info, .. } => info,
+        }
+    }
+
+    pub fn amount(&self) -> &Nat {
+        match self {
+            Block::Mint { amount, .. }
+            | Block::Burn { amount, .. }
+            | Block::Transfer { amount, .. }
+            | Block::Approve { amount, .. } => amount,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum BlockDecodeError {
+    #[error("block field {path} is missing")]
+    MissingField { path: String },
+    #[error("block field {path} has type {actual}, expected {expected}")]
+    UnexpectedType {
+        path: String,
+        expected: &'static str,
+        actual: &'static str,
+    },
+    #[error("block field {path} is invalid: {reason}")]
+    InvalidField { path: String, reason: String },
+    #[error("block field {path} has unknown block type {block_type:?}")]
+    UnknownBlockType { path: String, block_type: String },
+}
+
+enum Kind {
+    Mint,
+    Burn,
+    Transfer,
+    Approve,
+}
+
+impl TryFrom<&Value> for Block {
+    type Error = BlockDecodeError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let block = Fields::new(String::new(), value)?;
+        let tx = block.map("tx")?;
+
+        let btype = block.text("btype")?;
+        let kind = match btype {
+            Some(btype) => match btype {
+                "1mint" => Kind::Mint,
+                "1burn" => Kind::Burn,
+                "1xfer" | "2xfer" => Kind::Transfer,
+                "2approve" => Kind::Approve,
+                _ => return Err(block.unknown_block_type("btype", btype)),
+            },
+            None => match tx.required_text("op")? {
+                "mint" => Kind::Mint,
+                "burn" => Kind::Burn,
+                "xfer" => Kind::Transfer,
+                "approve" => Kind::Approve,
+                op => return Err(tx.unknown_block_type("op", op)),
+            },
+        };
+
+        let info = BlockInfo {
+            btype: btype.map(str::to_string),
+            parent_hash: block.hash("phash")?,
+            timestamp: block.required("ts", Fields::as_u64)?,
+            fee: block.nat("fee")?,
+            tx_fee: tx.nat("fee")?,
+            fee_collector: block.account("fee_col")?,
+            fee_collector_block: block.u64("fee_col_block")?,
+            memo: tx.blob("memo")?.map(<[u8]>::to_vec),
+            created_at_time: tx.u64("ts")?,
+        };
+        let amount = tx.required("amt", Fields::as_nat)?;
+
+        Ok(match kind {
+            Kind::Mint => Block::Mint {
+                info,
+                to: tx.required("to", Fields::as_account)?,
+                amount,
+            },
+            Kind::Burn => Block::Burn {
+                info,
+                from: tx.required("from", Fields::as_account)?,
+                spender: tx.account("spender")?,
+                amount,
+            },
+            Kind::Transfer => Block::Transfer {
+                info,
+                from: tx.required("from", Fields::as_account)?,
+                to: tx.required("to", Fields::as_account)?,
+                spender: tx.account("spender")?,
+                amount,
+            },
+            Kind::Approve => Block::Approve {
+                info,
+                from: tx.required("from", Fields::as_account)?,
+                spender: tx.required("spender", Fields::as_account)?,
+                amount,
+                expected_allowance: tx.nat("expected_allowance")?,
+                expires_at: tx.u64("expires_at")?,
+            },
+        })
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Text(_) => "Text",
+        Value::Blob(_) => "Blob",
+        Value::Nat(_) => "Nat",
+        Value::Int(_) => "Int",
+        Value::Array(_) => "Array",
+        Value::Map(_) => "Map",
+    }
+}
+
+/// A map value together with its path within the block, so that
+/// decoding errors can point at the offending field.
+struct Fields<'a> {
+    path: String,
+    entries: &'a [(String, Value)],
+}
+
+impl<'a> Fields<'a> {
+    fn new(path: String, value: &'a Value) -> Result<Self, BlockDecodeError> {
+        match value.as_map() {
+            Some(entries) => Ok(Self { path, entries }),
+            None => Err(BlockDecodeError::UnexpectedType {
+                path: if path.is_empty() {
+                    "<block>".to_string()
+                } else {
+                    path
+                },
+                expected: "Map",
+                actual: type_name(value),
+            }),
+        }
+    }
+
+    fn path_of(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.entries
+            .iter()
+            .find_map(|(k, v)| (k == key).then_some(v))
+    }
+
+    fn optional<T>(
+        &self,
+        key: &str,
+        decode: impl FnOnce(String, &'a Value) -> Result<T, BlockDecodeError>,
+    ) -> Result<Option<T>, BlockDecodeError> {
+        self.get(key)
+            .map(|value| decode(self.path_of(key), value))
+            .transpose()
+    }
+
+    fn required<T>(
+        &self,
+        key: &str,
+        decode: impl FnOnce(String, &'a Value) -> Result<T, BlockDecodeError>,
+    ) -> Result<T, BlockDecodeError> {
+        self.optional(key, decode)?
+            .ok_or_else(|| BlockDecodeError::MissingField {
+                path: self.path_of(key),
+            })
+    }
+
+    fn unknown_block_type(&self, key: &str, block_type: &str) -> BlockDecodeError {
+        BlockDecodeError::UnknownBlockType {
+            path: self.path_of(key),
+            block_type: block_type.to_string(),
+        }
+    }
+
+    fn map(&self, key: &str) -> Result<Fields<'a>, BlockDecodeError> {
+        self.required(key, Fields::new)
+    }
+
+    fn text(&self, key: &str) -> Result<Option<&'a str>, BlockDecodeError> {
+        self.optional(key, Self::as_text)
+    }
+
+    fn required_text(&self, key: &str) -> Result<&'a str, BlockDecodeError> {
+        self.required(key, Self::as_text)
+    }
+
+    fn blob(&self, key: &str) -> Result<Option<&'a [u8]>, BlockDecodeError> {
+        self.optional(key, Self::as_blob)
+    }
+
+    fn nat(&self, key: &str) -> Result<Option<Nat>, BlockDecodeError> {
+        self.optional(key, Self::as_nat)
+    }
+
+    fn u64(&self, key: &str) -> Result<Option<u64>, BlockDecodeError> {
+        self.optional(key, Self::as_u64)
+    }
+
+    fn account(&self, key: &str) -> Result<Option<Account>, BlockDecodeError> {
+        self.optional(key, Self::as_account)
+    }
+
+    fn hash(&self, key: &str) -> Result<Option<[u8; 32]>, BlockDecodeError> {
+        self.optional(key, |path, value| {
+            let bytes = Self::as_blob(path.clone(), value)?;
+            <[u8; 32]>::try_from(bytes).map_err(|_| BlockDecodeError::InvalidField {
+                path,
+                reason: format!("expected a 32-byte hash, got {} bytes", bytes.len()),
+            })
+        })
+    }
+
+    fn as_text(path: String, value: &'a Value) -> Result<&'a str, BlockDecodeError> {
+        value
+            .as_text()
+            .ok_or_else(|| unexpected(path, "Text", value))
+    }
+
+    fn as_blob(path: String, value: &'a Value) -> Result<&'a [u8], BlockDecodeError> {
+        value
+            .as_blob()
+            .ok_or_else(|| unexpected(path, "Blob", value))
+    }
+
+    fn as_nat(path: String, value: &'a Value) -> Result<Nat, BlockDecodeError> {
+        value
+            .as_nat()
+            .cloned()
+            .ok_or_else(|| unexpected(path, "Nat", value))
+    }
+
+    fn as_u64(path: String, value: &'a Value) -> Result<u64, BlockDecodeError> {
+        let nat = Self::as_nat(path.clone(), value)?;
+        u64::try_from(&nat.0).map_err(|_| BlockDecodeError::InvalidField {
+            path,
+            reason: format!("{} does not fit into 64 bits", nat),
+        })
+    }
+
+    /// Decodes an account encoded as an array of the owner bytes and
+    /// optionally the subaccount bytes.
+    fn as_account(path: String, value: &'a Value) -> Result<Account, BlockDecodeError> {
+        let parts = value
+            .as_array()
+            .ok_or_else(|| unexpected(path.clone(), "Array", value))?;
+        if parts.is_empty() || parts.len() > 2 {
+            return Err(BlockDecodeError::InvalidField {
+                path,
+                reason: format!("expected 1 or 2 elements, got {}", parts.len()),
+            });
+        }
+
+        let owner_path = format!("{}[0]", path);
+        let owner_bytes = Self::as_blob(owner_path.clone(), &parts[0])?;
+        let owner =
+            Principal::try_from_slice(owner_bytes).map_err(|e| BlockDecodeError::InvalidField {
+                path: owner_path,
+                reason: e.to_string(),
+            })?;
+
+        let subaccount = match parts.get(1) {
+            Some(part) => {
+                let subaccount_path = format!("{}[1]", path);
+                let bytes = Self::as_blob(subaccount_path.clone(), part)?;
+                Some(
+                    <[u8; 32]>::try_from(bytes).map_err(|_| BlockDecodeError::InvalidField {
+                        path: subaccount_path,
+                        reason: format!("expected 32 bytes, got {}", bytes.len()),
+                    })?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Account { owner, subaccount })
+    }
+}
+
+fn unexpected(path: String, expected: &'static str, value: &Value) -> BlockDecodeError {
+    BlockDecodeError::UnexpectedType {
+        path,
+        expected,
+        actual: type_name(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nat(n: u64) -> Value {
+        Value::Nat(Nat::from(n))
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    fn account_value(owner: Principal, subaccount: Option<[u8; 32]>) -> Value {
+        let mut parts = vec![Value::Blob(owner.as_slice().to_vec())];
+        if let Some(subaccount) = subaccount {
+            parts.push(Value::Blob(subaccount.to_vec()));
+        }
+        Value::Array(parts)
+    }
+
+    #[test]
+    fn decode_transfer_with_btype() {
+        let from = Principal::from_slice(&[1, 2, 3]);
+        let to = Principal::from_slice(&[4, 5, 6]);
+        let block = map(vec![
+            ("btype", text("1xfer")),
+            ("fee", nat(10)),
+            ("phash", Value::Blob(vec![7; 32])),
+            ("ts", nat(1_701_109_006_692_276_133)),
+            (
+                "tx",
+                map(vec![
+                    ("amt", nat(609_618)),
+                    ("from", account_value(from, Some([0; 32]))),
+                    ("to", account_value(to, None)),
+                    ("memo", Value::Blob(vec![1, 2])),
+                ]),
+            ),
+        ]);
+
+        assert_eq!(
+            Block::try_from(&block),
+            Ok(Block::Transfer {
+                info: BlockInfo {
+                    btype: Some("1xfer".to_string()),
+                    parent_hash: Some([7; 32]),
+                    timestamp: 1_701_109_006_692_276_133,
+                    fee: Some(Nat::from(10u8)),
+                    tx_fee: None,
+                    fee_collector: None,
+                    fee_collector_block: None,
+                    memo: Some(vec![1, 2]),
+                    created_at_time: None,
+                },
+                from: Account {
+                    owner: from,
+                    subaccount: Some([0; 32]),
+                },
+                to: Account::from(to),
+                spender: None,
+                amount: Nat::from(609_618u32),
+            })
+        );
+    }
+
+    #[test]
+    fn decode_legacy_approve() {
+        let from = Principal::from_slice(&[1]);
+        let spender = Principal::from_slice(&[2]);
+        let block = map(vec![
+            ("ts", nat(1)),
+            (
+                "tx",
+                map(vec![
+                    ("op", text("approve")),
+                    ("amt", nat(100)),
+                    ("fee", nat(10)),
+                    ("from", account_value(from, None)),
+                    ("spender", account_value(spender, None)),
+                    ("expires_at", nat(42)),
+                ]),
+            ),
+        ]);
+
+        let block = Block::try_from(&block).unwrap();
+        assert_eq!(block.info().effective_fee(), Some(&Nat::from(10u8)));
+        match block {
+            Block::Approve {
+                info,
+                from: decoded_from,
+                spender: decoded_spender,
+                expires_at,
+                ..
+            } => {
+                assert_eq!(info.btype, None);
+                assert_eq!(decoded_from, Account::from(from));
+                assert_eq!(decoded_spender, Account::from(spender));
+                assert_eq!(expires_at, Some(42));
+            }
+            other => panic!("expected an approve block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_errors_name_the_field_path() {
+        let block = map(vec![
+            ("btype", text("1mint")),
+            ("ts", nat(1)),
+            ("tx", map(vec![("amt", text("100"))])),
+        ]);
+        assert_eq!(
+            Block::try_from(&block),
+            Err(BlockDecodeError::UnexpectedType {
+                path: "tx.amt".to_string(),
+                expected: "Nat",
+                actual: "Text",
+            })
+        );
+
+        let block = map(vec![
+            ("btype", text("1mint")),
+            ("ts", nat(1)),
+            (
+                "tx",
+                map(vec![
+                    ("amt", nat(100)),
+                    ("to", Value::Array(vec![Value::Blob(vec![1]), nat(0)])),
+                ]),
+            ),
+        ]);
+        assert_eq!(
+            Block::try_from(&block),
+            Err(BlockDecodeError::UnexpectedType {
+                path: "tx.to[1]".to_string(),
+                expected: "Blob",
+                actual: "Nat",
+            })
+        );
+
+        let block = map(vec![("ts", nat(1)), ("tx", map(vec![("amt", nat(1))]))]);
+        assert_eq!(
+            Block::try_from(&block),
+            Err(BlockDecodeError::MissingField {
+                path: "tx.op".to_string(),
+            })
+        );
+    }
+}
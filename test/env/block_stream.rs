@@ -0,0 +1,467 @@
+//! Streaming of the ICRC-3 block log, including the blocks moved to archives.
+
+use crate::icrc3::{get_archives, get_blocks};
+use crate::{
+    ArchiveInfo, ArchivedBlocks, BlockWithId, GetArchivesArgs, GetBlocksArgs, GetBlocksRequest,
+    GetBlocksResult, LedgerEnv, Value,
+};
+use anyhow::Context;
+use candid::Nat;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use std::cmp::min;
+use std::collections::VecDeque;
+use thiserror::Error;
+
+/// The number of blocks requested at once.
+const BATCH_SIZE: u64 = 1_000;
+
+/// The maximum number of nested archive callbacks followed for a single request.
+const MAX_CALLBACK_DEPTH: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum BlockStreamError {
+    #[error("expected block {expected}, got block {actual}")]
+    Gap { expected: Nat, actual: Nat },
+    #[error("block {index} was returned more than once")]
+    Overlap { index: Nat },
+    #[error("the log length is {log_length}, but neither the ledger nor its archives returned block {expected}")]
+    MissingBlock { expected: Nat, log_length: Nat },
+    #[error("archive callbacks are nested deeper than {0} levels")]
+    CallbackDepthExceeded(usize),
+}
+
+struct State<'a, L> {
+    ledger: &'a L,
+    next: Nat,
+    /// The log length observed on the first request.
+    end: Option<Nat>,
+    buffer: VecDeque<(Nat, Value)>,
+    archives: Option<Vec<ArchiveInfo>>,
+}
+
+/// Returns the stream of blocks from the specified index up to the tip of
+/// the block log at the time of the first request.
+///
+/// The stream follows the archive callbacks returned by `icrc3_get_blocks`,
+/// falls back to the archives listed by `icrc3_get_archives` for the blocks
+/// the ledger does not return, and ends with a [BlockStreamError] if the
+/// returned ranges have gaps or overlap.
+pub fn block_stream<L: LedgerEnv>(
+    ledger: &L,
+    start: Nat,
+) -> LocalBoxStream<'_, anyhow::Result<(Nat, Value)>> {
+    let state = State {
+        ledger,
+        next: start,
+        end: None,
+        buffer: VecDeque::new(),
+        archives: None,
+    };
+    stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        if state.buffer.is_empty() {
+            if state.is_done() {
+                return None;
+            }
+            if let Err(err) = state.fill().await {
+                return Some((Err(err), None));
+            }
+        }
+        let block = state.buffer.pop_front()?;
+        Some((Ok(block), Some(state)))
+    })
+    .boxed_local()
+}
+
+impl<L: LedgerEnv> State<'_, L> {
+    fn is_done(&self) -> bool {
+        matches!(&self.end, Some(end) if &self.next >= end)
+    }
+
+    fn batch_length(&self) -> Nat {
+        match &self.end {
+            Some(end) => min(end.clone() - self.next.clone(), Nat::from(BATCH_SIZE)),
+            None => Nat::from(BATCH_SIZE),
+        }
+    }
+
+    /// Fetches the next batch of blocks into the buffer.
+    async fn fill(&mut self) -> anyhow::Result<()> {
+        let args = blocks_request(self.next.clone(), self.batch_length());
+        let result = get_blocks(self.ledger, args).await?;
+        let end = self.end.get_or_insert(result.log_length.clone()).clone();
+        let mut blocks = resolve_archived_blocks(self.ledger, result).await?;
+
+        if self.next < end && !blocks.iter().any(|b| b.id == self.next) {
+            // The ledger did not return the next block, look it up in the archives directly.
+            if let Some(archive) = self.find_archive().await? {
+                let archive_end = min(archive.end.clone() + 1u8, end.clone());
+                let args = blocks_request(
+                    self.next.clone(),
+                    min(archive_end - self.next.clone(), Nat::from(BATCH_SIZE)),
+                );
+                let (result,): (GetBlocksResult,) = self
+                    .ledger
+                    .query_canister(archive.canister_id, "icrc3_get_blocks", (args,))
+                    .await
+                    .with_context(|| {
+                        format!("failed to get blocks from archive {}", archive.canister_id)
+                    })?;
+                blocks.extend(resolve_archived_blocks(self.ledger, result).await?);
+            }
+        }
+        self.push_blocks(blocks, &end)?;
+
+        if self.buffer.is_empty() && self.next < end {
+            return Err(BlockStreamError::MissingBlock {
+                expected: self.next.clone(),
+                log_length: end,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Appends the blocks that continue the stream to the buffer.
+    fn push_blocks(
+        &mut self,
+        mut blocks: Vec<BlockWithId>,
+        end: &Nat,
+    ) -> Result<(), BlockStreamError> {
+        blocks.sort_by(|lhs, rhs| lhs.id.cmp(&rhs.id));
+        for BlockWithId { id, block } in blocks {
+            if &id >= end {
+                break;
+            }
+            if id < self.next {
+                return Err(BlockStreamError::Overlap { index: id });
+            }
+            if id > self.next {
+                return Err(BlockStreamError::Gap {
+                    expected: self.next.clone(),
+                    actual: id,
+                });
+            }
+            self.next = id.clone() + 1u8;
+            self.buffer.push_back((id, block));
+        }
+        Ok(())
+    }
+
+    /// Returns the archive holding the next block according to `icrc3_get_archives`.
+    async fn find_archive(&mut self) -> anyhow::Result<Option<ArchiveInfo>> {
+        if self.archives.is_none() {
+            self.archives = Some(list_archives(self.ledger).await?);
+        }
+        let next = &self.next;
+        Ok(self
+            .archives
+            .iter()
+            .flatten()
+            .find(|archive| &archive.start <= next && next <= &archive.end)
+            .cloned())
+    }
+}
+
+fn blocks_request(start: Nat, length: Nat) -> GetBlocksArgs {
+    vec![GetBlocksRequest { start, length }]
+}
+
+/// Collects the blocks returned by the ledger and by the archive callbacks.
+async fn resolve_archived_blocks(
+    ledger: &impl LedgerEnv,
+    result: GetBlocksResult,
+) -> anyhow::Result<Vec<BlockWithId>> {
+    let mut blocks = result.blocks;
+    let mut pending: Vec<(usize, ArchivedBlocks)> = result
+        .archived_blocks
+        .into_iter()
+        .map(|archived| (1, archived))
+        .collect();
+    while let Some((depth, archived)) = pending.pop() {
+        if depth > MAX_CALLBACK_DEPTH {
+            return Err(BlockStreamError::CallbackDepthExceeded(MAX_CALLBACK_DEPTH).into());
+        }
+        let callback = archived.callback.0;
+        let (result,): (GetBlocksResult,) = ledger
+            .query_canister(callback.principal, &callback.method, (archived.args,))
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to call archive callback {} on {}",
+                    callback.method, callback.principal
+                )
+            })?;
+        blocks.extend(result.blocks);
+        pending.extend(
+            result
+                .archived_blocks
+                .into_iter()
+                .map(|archived| (depth + 1, archived)),
+        );
+    }
+    Ok(blocks)
+}
+
+/// Lists all archives of the ledger, following the `icrc3_get_archives` pagination.
+async fn list_archives(ledger: &impl LedgerEnv) -> anyhow::Result<Vec<ArchiveInfo>> {
+    let mut archives: Vec<ArchiveInfo> = vec![];
+    let mut from = None;
+    loop {
+        let mut page = get_archives(ledger, GetArchivesArgs { from }).await?;
+        // Some ledgers ignore the `from` argument and return all archives.
+        page.retain(|archive| {
+            !archives
+                .iter()
+                .any(|known| known.canister_id == archive.canister_id)
+        });
+        match page.last() {
+            Some(last) => from = Some(last.canister_id),
+            None => return Ok(archives),
+        }
+        archives.extend(page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GetBlocksFn;
+    use async_trait::async_trait;
+    use candid::types::reference::Func;
+    use candid::utils::{decode_args, encode_args};
+    use candid::Principal;
+    use futures::executor::block_on;
+    use futures::TryStreamExt;
+    use std::convert::TryFrom;
+
+    const ARCHIVE_METHOD: &str = "get_archived_blocks";
+
+    /// A ledger serving `log_length` blocks, the first `archived` of which
+    /// live in an archive canister.
+    #[derive(Clone)]
+    struct ArchivingLedger {
+        log_length: u64,
+        archived: u64,
+        /// Blocks the ledger and the archive pretend they do not have.
+        missing: Vec<u64>,
+        /// Blocks the ledger and the archive return twice.
+        duplicated: Vec<u64>,
+        /// Whether `icrc3_get_blocks` omits the archived ranges.
+        hide_archived_ranges: bool,
+    }
+
+    impl ArchivingLedger {
+        fn new(log_length: u64, archived: u64) -> Self {
+            Self {
+                log_length,
+                archived,
+                missing: vec![],
+                duplicated: vec![],
+                hide_archived_ranges: false,
+            }
+        }
+
+        fn archive_id() -> Principal {
+            Principal::from_slice(&[2])
+        }
+
+        fn blocks(&self, start: u64, end: u64) -> Vec<BlockWithId> {
+            let mut blocks = vec![];
+            for id in start..end {
+                if self.missing.contains(&id) {
+                    continue;
+                }
+                let block = BlockWithId {
+                    id: Nat::from(id),
+                    block: Value::Nat(Nat::from(id)),
+                };
+                if self.duplicated.contains(&id) {
+                    blocks.push(block.clone());
+                }
+                blocks.push(block);
+            }
+            blocks
+        }
+
+        fn range(&self, args: &GetBlocksArgs, max: u64) -> (u64, u64) {
+            let start = u64::try_from(&args[0].start.0).unwrap().min(max);
+            let length = u64::try_from(&args[0].length.0).unwrap();
+            (start, (start + length).min(max))
+        }
+
+        fn get_blocks(&self, args: GetBlocksArgs) -> GetBlocksResult {
+            let (start, end) = self.range(&args, self.log_length);
+            let mut archived_blocks = vec![];
+            if start < self.archived && !self.hide_archived_ranges {
+                archived_blocks.push(ArchivedBlocks {
+                    args: vec![GetBlocksRequest {
+                        start: Nat::from(start),
+                        length: Nat::from(end.min(self.archived) - start),
+                    }],
+                    callback: GetBlocksFn(Func {
+                        principal: Self::archive_id(),
+                        method: ARCHIVE_METHOD.to_string(),
+                    }),
+                });
+            }
+            GetBlocksResult {
+                log_length: Nat::from(self.log_length),
+                blocks: self.blocks(start.max(self.archived), end),
+                archived_blocks,
+            }
+        }
+
+        fn get_archived_blocks(&self, args: GetBlocksArgs) -> GetBlocksResult {
+            let (start, end) = self.range(&args, self.archived);
+            GetBlocksResult {
+                log_length: Nat::from(self.archived),
+                blocks: self.blocks(start, end),
+                archived_blocks: vec![],
+            }
+        }
+
+        fn get_archives(&self, args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+            if self.archived == 0 || args.from.is_some() {
+                return vec![];
+            }
+            vec![ArchiveInfo {
+                canister_id: Self::archive_id(),
+                start: Nat::from(0u8),
+                end: Nat::from(self.archived - 1),
+            }]
+        }
+    }
+
+    fn call<Arg, Ret>(arg: &[u8], f: impl FnOnce(Arg) -> Ret) -> Vec<u8>
+    where
+        Arg: for<'a> candid::Deserialize<'a> + candid::CandidType,
+        Ret: candid::CandidType,
+    {
+        let (arg,): (Arg,) = decode_args(arg).unwrap();
+        encode_args((f(arg),)).unwrap()
+    }
+
+    #[async_trait(?Send)]
+    impl LedgerEnv for ArchivingLedger {
+        fn fork(&self) -> Self {
+            self.clone()
+        }
+
+        fn principal(&self) -> Principal {
+            Principal::anonymous()
+        }
+
+        async fn time(&self) -> std::time::SystemTime {
+            std::time::SystemTime::UNIX_EPOCH
+        }
+
+        async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Ok(match method {
+                "icrc3_get_blocks" => call(&arg, |args| self.get_blocks(args)),
+                "icrc3_get_archives" => call(&arg, |args| self.get_archives(args)),
+                _ => anyhow::bail!("unexpected call to {} on the ledger", method),
+            })
+        }
+
+        async fn query_canister_raw(
+            &self,
+            canister_id: Principal,
+            method: &str,
+            arg: Vec<u8>,
+        ) -> anyhow::Result<Vec<u8>> {
+            Ok(match method {
+                ARCHIVE_METHOD | "icrc3_get_blocks" if canister_id == Self::archive_id() => {
+                    call(&arg, |args| self.get_archived_blocks(args))
+                }
+                _ => anyhow::bail!("unexpected call to {} on {}", method, canister_id),
+            })
+        }
+
+        async fn update_raw(&self, method: &str, _arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("unexpected update call to {}", method)
+        }
+    }
+
+    fn collect(ledger: &ArchivingLedger, start: u64) -> anyhow::Result<Vec<u64>> {
+        let blocks: Vec<(Nat, Value)> =
+            block_on(block_stream(ledger, Nat::from(start)).try_collect())?;
+        Ok(blocks
+            .into_iter()
+            .map(|(id, block)| {
+                assert_eq!(block, Value::Nat(id.clone()));
+                u64::try_from(&id.0).unwrap()
+            })
+            .collect())
+    }
+
+    fn stream_error(ledger: &ArchivingLedger) -> BlockStreamError {
+        collect(ledger, 0)
+            .unwrap_err()
+            .downcast::<BlockStreamError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn streams_local_blocks() {
+        let ledger = ArchivingLedger::new(2_500, 0);
+        assert_eq!(collect(&ledger, 0).unwrap(), (0..2_500).collect::<Vec<_>>());
+        assert_eq!(
+            collect(&ledger, 2_400).unwrap(),
+            (2_400..2_500).collect::<Vec<_>>()
+        );
+        assert_eq!(collect(&ledger, 3_000).unwrap(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn follows_archive_callbacks() {
+        let ledger = ArchivingLedger::new(2_500, 1_500);
+        assert_eq!(collect(&ledger, 0).unwrap(), (0..2_500).collect::<Vec<_>>());
+        assert_eq!(
+            collect(&ledger, 1_400).unwrap(),
+            (1_400..2_500).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn falls_back_to_listed_archives() {
+        let mut ledger = ArchivingLedger::new(2_500, 1_500);
+        ledger.hide_archived_ranges = true;
+        assert_eq!(collect(&ledger, 0).unwrap(), (0..2_500).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn detects_gaps() {
+        let mut ledger = ArchivingLedger::new(2_500, 1_500);
+        ledger.missing = vec![1_200];
+        assert_eq!(
+            stream_error(&ledger),
+            BlockStreamError::Gap {
+                expected: Nat::from(1_200u64),
+                actual: Nat::from(1_201u64),
+            }
+        );
+
+        let mut ledger = ArchivingLedger::new(2_500, 0);
+        ledger.missing = (2_000..2_500).collect();
+        assert_eq!(
+            stream_error(&ledger),
+            BlockStreamError::MissingBlock {
+                expected: Nat::from(2_000u64),
+                log_length: Nat::from(2_500u64),
+            }
+        );
+    }
+
+    #[test]
+    fn detects_overlaps() {
+        let mut ledger = ArchivingLedger::new(2_500, 1_500);
+        ledger.duplicated = vec![1_700];
+        assert_eq!(
+            stream_error(&ledger),
+            BlockStreamError::Overlap {
+                index: Nat::from(1_700u64),
+            }
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! Verification of the ICRC-3 tip certificate returned by
+//! `icrc3_get_tip_certificate`.
+
+use crate::DataCertificate;
+use candid::Principal;
+use ic_certification::{Certificate, HashTree, LookupResult};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// The DER prefix of a BLS12-381 public key as used by the Internet Computer.
+const BLS_DER_PREFIX: [u8; 37] = [
+    0x30, 0x81, 0x82, 0x30, 0x1d, 0x06, 0x0d, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xdc, 0x7c, 0x05,
+    0x03, 0x01, 0x02, 0x01, 0x06, 0x0c, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xdc, 0x7c, 0x05, 0x03,
+    0x02, 0x01, 0x03, 0x61, 0x00,
+];
+
+/// The length of a raw BLS12-381 public key.
+const BLS_KEY_LENGTH: usize = 96;
+
+/// The domain separator for signatures over the state tree root hash.
+const IC_STATE_ROOT_DOMAIN_SEPARATOR: &[u8] = b"\x0Dic-state-root";
+
+/// The certified tip of an ICRC-3 block log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tip {
+    pub last_block_index: u64,
+    pub last_block_hash: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum CertificateError {
+    #[error("failed to decode the certificate: {0}")]
+    MalformedCertificate(String),
+    #[error("failed to decode the hash tree: {0}")]
+    MalformedHashTree(String),
+    #[error("invalid root key: {0}")]
+    InvalidRootKey(String),
+    #[error("invalid certificate delegation: {0}")]
+    InvalidDelegation(String),
+    #[error("the certificate signature does not match the public key")]
+    InvalidSignature,
+    #[error("the certificate does not contain the certified data of canister {0}")]
+    MissingCertifiedData(Principal),
+    #[error("the hash tree root {tree_hash} does not match the certified data {certified_data}")]
+    CertifiedDataMismatch {
+        tree_hash: String,
+        certified_data: String,
+    },
+    #[error("the hash tree does not contain the {0} label")]
+    MissingLabel(&'static str),
+    #[error("invalid {label} label: {reason}")]
+    InvalidLabel { label: &'static str, reason: String },
+}
+
+/// Verifies the tip certificate of the ledger with the specified canister id
+/// against the DER-encoded root key of the network and returns the certified tip.
+pub fn verify_tip_certificate(
+    data_certificate: &DataCertificate,
+    canister_id: Principal,
+    root_key: &[u8],
+) -> Result<Tip, CertificateError> {
+    let certificate: Certificate = serde_cbor::from_slice(&data_certificate.certificate)
+        .map_err(|e| CertificateError::MalformedCertificate(e.to_string()))?;
+    verify_certificate(&certificate, canister_id, root_key)?;
+
+    let certified_data = match certificate.tree.lookup_path([
+        b"canister".as_ref(),
+        canister_id.as_slice(),
+        b"certified_data".as_ref(),
+    ]) {
+        LookupResult::Found(data) => data,
+        _ => return Err(CertificateError::MissingCertifiedData(canister_id)),
+    };
+
+    let hash_tree: HashTree = serde_cbor::from_slice(&data_certificate.hash_tree)
+        .map_err(|e| CertificateError::MalformedHashTree(e.to_string()))?;
+    let tree_hash = hash_tree.digest();
+    if certified_data != tree_hash {
+        return Err(CertificateError::CertifiedDataMismatch {
+            tree_hash: hex::encode(tree_hash),
+            certified_data: hex::encode(certified_data),
+        });
+    }
+
+    let last_block_index = lookup_label(&hash_tree, "last_block_index")?;
+    let last_block_index =
+        decode_leb128_u64(last_block_index).ok_or_else(|| CertificateError::InvalidLabel {
+            label: "last_block_index",
+            reason: format!(
+                "{} is not a LEB128-encoded 64-bit number",
+                hex::encode(last_block_index)
+            ),
+        })?;
+
+    let last_block_hash = lookup_label(&hash_tree, "last_block_hash")?;
+    let last_block_hash =
+        <[u8; 32]>::try_from(last_block_hash).map_err(|_| CertificateError::InvalidLabel {
+            label: "last_block_hash",
+            reason: format!("expected 32 bytes, got {}", last_block_hash.len()),
+        })?;
+
+    Ok(Tip {
+        last_block_index,
+        last_block_hash,
+    })
+}
+
+fn lookup_label<'a>(
+    hash_tree: &'a HashTree,
+    label: &'static str,
+) -> Result<&'a [u8], CertificateError> {
+    match hash_tree.lookup_path([label]) {
+        LookupResult::Found(value) => Ok(value),
+        _ => Err(CertificateError::MissingLabel(label)),
+    }
+}
+
+/// Checks the certificate signature, following the subnet delegation if
+/// the certificate has one.
+fn verify_certificate(
+    certificate: &Certificate,
+    canister_id: Principal,
+    root_key: &[u8],
+) -> Result<(), CertificateError> {
+    let public_key = match &certificate.delegation {
+        None => extract_der(root_key).map_err(CertificateError::InvalidRootKey)?,
+        Some(delegation) => {
+            let delegation_certificate: Certificate =
+                serde_cbor::from_slice(&delegation.certificate)
+                    .map_err(|e| CertificateError::InvalidDelegation(e.to_string()))?;
+            if delegation_certificate.delegation.is_some() {
+                return Err(CertificateError::InvalidDelegation(
+                    "nested delegations are not allowed".to_string(),
+                ));
+            }
+            verify_certificate(&delegation_certificate, canister_id, root_key)?;
+            check_canister_ranges(&delegation_certificate, &delegation.subnet_id, canister_id)?;
+
+            let der_key = match delegation_certificate.tree.lookup_path([
+                b"subnet".as_ref(),
+                delegation.subnet_id.as_slice(),
+                b"public_key".as_ref(),
+            ]) {
+                LookupResult::Found(key) => key,
+                _ => {
+                    return Err(CertificateError::InvalidDelegation(
+                        "the delegation does not contain the subnet public key".to_string(),
+                    ))
+                }
+            };
+            extract_der(der_key).map_err(CertificateError::InvalidDelegation)?
+        }
+    };
+
+    let mut message = IC_STATE_ROOT_DOMAIN_SEPARATOR.to_vec();
+    message.extend_from_slice(&certificate.tree.digest());
+    ic_verify_bls_signature::verify_bls_signature(&certificate.signature, &message, &public_key)
+        .map_err(|_| CertificateError::InvalidSignature)
+}
+
+/// Checks that the canister ranges of the subnet the certificate was
+/// delegated to include the canister.
+fn check_canister_ranges(
+    delegation_certificate: &Certificate,
+    subnet_id: &[u8],
+    canister_id: Principal,
+) -> Result<(), CertificateError> {
+    let ranges = match delegation_certificate.tree.lookup_path([
+        b"subnet".as_ref(),
+        subnet_id,
+        b"canister_ranges".as_ref(),
+    ]) {
+        LookupResult::Found(ranges) => ranges,
+        _ => {
+            return Err(CertificateError::InvalidDelegation(
+                "the delegation does not contain the canister ranges of the subnet".to_string(),
+            ))
+        }
+    };
+    let ranges: Vec<(Principal, Principal)> = serde_cbor::from_slice(ranges)
+        .map_err(|e| CertificateError::InvalidDelegation(e.to_string()))?;
+    let canister = canister_id.as_slice();
+    if ranges
+        .iter()
+        .any(|(low, high)| low.as_slice() <= canister && canister <= high.as_slice())
+    {
+        Ok(())
+    } else {
+        Err(CertificateError::InvalidDelegation(format!(
+            "the subnet is not authorized to certify canister {}",
+            canister_id
+        )))
+    }
+}
+
+fn extract_der(der_key: &[u8]) -> Result<Vec<u8>, String> {
+    if der_key.len() != BLS_DER_PREFIX.len() + BLS_KEY_LENGTH
+        || der_key[..BLS_DER_PREFIX.len()] != BLS_DER_PREFIX
+    {
+        return Err(format!(
+            "expected a DER-encoded BLS public key, got {}",
+            hex::encode(der_key)
+        ));
+    }
+    Ok(der_key[BLS_DER_PREFIX.len()..].to_vec())
+}
+
+/// Decodes an unsigned LEB128 number, such as the certified time or the
+/// last block index of a tip certificate. Returns `None` if the bytes are
+/// not exactly one LEB128 number fitting into `u64`.
+pub fn decode_leb128_u64(bytes: &[u8]) -> Option<u64> {
+    let mut result: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let shift = 7 * i as u32;
+        let part = (byte & 0x7f) as u64;
+        if shift >= 64 || (shift == 63 && part > 1) {
+            return None;
+        }
+        result |= part << shift;
+        if byte & 0x80 == 0 {
+            return (i + 1 == bytes.len()).then_some(result);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_certification::hash_tree::{fork, label, leaf};
+    use ic_certification::Delegation;
+    use ic_verify_bls_signature::PrivateKey;
+
+    /// The root key of the Internet Computer mainnet.
+    const MAINNET_ROOT_KEY: &str = concat!(
+        "308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100814c0e6ec71fab",
+        "583b08bd81373c255c3c371b2e84863c98a4f1e08b74235d14fb5d9c0cd546d9685f913a0c0b2cc5341583bf",
+        "4b4392e467db96d65b9bb4cb717112f8472e0d5a4d14505ffd7484b01291091c5f87b98883463f98091a0baa",
+        "ae",
+    );
+
+    /// A certificate delegated to the subnet of canister
+    /// `ivg37-qiaaa-aaaab-aaaga-cai`, recorded from a `read_state` request
+    /// to the mainnet.
+    const MAINNET_CERTIFICATE: &str = concat!(
+        "d9d9f7a364747265658301830182045820250f5e26868d9c1ea7ab29cbe9c15bf1c47c0d7605e803e39e375a",
+        "7fe09c6ebb830183024e726571756573745f7374617475738301820458204b268227774ec77ff2b37ecb1215",
+        "7329d54cf376694bdd59ded7803efd82386f83025820edad510eaaa08ed2acd4781324e6446269da6753ec17",
+        "760f206bbe81c465ff528301830183024b72656a6563745f636f64658203410383024e72656a6563745f6d65",
+        "73736167658203584443616e69737465722069766733372d71696161612d61616161622d61616167612d6361",
+        "6920686173206e6f20757064617465206d6574686f6420277265676973746572278302467374617475738203",
+        "4872656a65637465648204582097232f31f6ab7ca4fe53eb6568fc3e02bc22fe94ab31d010e5fb3c642301f1",
+        "608301820458203a48d1fc213d49307103104f7d72c2b5930edba8787b90631f343b3aa68a5f0a8302447469",
+        "6d65820349e2dc939091c696eb16697369676e6174757265583089a2be21b5fa8ac9fab1527e041327ce899d",
+        "7da971436a1f2165393947b4d942365bfe5488710e61a619ba48388a21b16a64656c65676174696f6ea26973",
+        "75626e65745f6964581dd77b2a2f7199b9a8aec93fe6fb588661358cf12223e9a3af7b4ebac4026b63657274",
+        "69666963617465590231d9d9f7a26474726565830182045820ae023f28c3b9d966c8fb09f9ed755c828aadb5",
+        "152e00aaf700b18c9c067294b483018302467375626e6574830182045820e83bb025f6574c8f31233dc0fe28",
+        "9ff546dfa1e49bd6116dd6e8896d90a4946e830182045820e782619092d69d5bebf0924138bd4116b0156b5a",
+        "95e25c358ea8cf7e7161a661830183018204582062513fa926c9a9ef803ac284d620f303189588e1d3904349",
+        "ab63b6470856fc4883018204582060e9a344ced2c9c4a96a0197fd585f2d259dbd193e4eada56239cac26087",
+        "f9c58302581dd77b2a2f7199b9a8aec93fe6fb588661358cf12223e9a3af7b4ebac402830183024f63616e69",
+        "737465725f72616e6765738203581bd9d9f781824a000000000020000001014a00000000002fffff01018302",
+        "4a7075626c69635f6b657982035885308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc",
+        "7c050302010361009933e1f89e8a3c4d7fdcccdbd518089e2bd4d8180a261f18d9c247a52768ebce98dc7328",
+        "a39814a8f911086a1dd50cbe015e2a53b7bf78b55288893daa15c346640e8831d72a12bdedd979d28470c348",
+        "23b8d1c3f4795d9c3984a247132e94fe82045820996f17bb926be3315745dea7282005a793b58e76afeb5d43",
+        "d1a28ce29d2d158583024474696d6582034995b8aac0e4eda2ea16697369676e61747572655830ace9fcdd9b",
+        "c977e05d6328f889dc4e7c99114c737a494653cb27a1f55c06f4555e0f160980af5ead098acc195010b2f7",
+    );
+
+    fn decode_certificate(hex_certificate: &str) -> Certificate {
+        serde_cbor::from_slice(&hex::decode(hex_certificate).unwrap()).unwrap()
+    }
+
+    fn mainnet_canister() -> Principal {
+        Principal::from_text("ivg37-qiaaa-aaaab-aaaga-cai").unwrap()
+    }
+
+    /// Signs the tree with the key.
+    fn sign(key: &PrivateKey, tree: HashTree, delegation: Option<Delegation>) -> Certificate {
+        let mut message = IC_STATE_ROOT_DOMAIN_SEPARATOR.to_vec();
+        message.extend_from_slice(&tree.digest());
+        Certificate {
+            tree,
+            signature: key.sign(&message).serialize().to_vec(),
+            delegation,
+        }
+    }
+
+    /// Delegates the root key to the subnet key, listing the canister
+    /// ranges of the subnet if there are any.
+    fn delegate(
+        root_key: &PrivateKey,
+        subnet_key: &PrivateKey,
+        canister_ranges: Option<Vec<(Principal, Principal)>>,
+    ) -> Delegation {
+        let subnet_id = Principal::from_slice(&[9]).as_slice().to_vec();
+        let mut subnet: HashTree = label("public_key", leaf(der_encode(subnet_key)));
+        if let Some(ranges) = canister_ranges {
+            let ranges = serde_cbor::to_vec(&ranges).unwrap();
+            subnet = fork(label("canister_ranges", leaf(ranges)), subnet);
+        }
+        let tree = label("subnet", label(subnet_id.clone(), subnet));
+        Delegation {
+            subnet_id,
+            certificate: serde_cbor::to_vec(&sign(root_key, tree, None)).unwrap(),
+        }
+    }
+
+    /// Certifies the tip with a certificate signed by the key.
+    fn certify_tip(
+        key: &PrivateKey,
+        delegation: Option<Delegation>,
+        canister_id: Principal,
+        last_block_index: &[u8],
+        last_block_hash: &[u8],
+    ) -> DataCertificate {
+        let hash_tree: HashTree = fork(
+            label("last_block_hash", leaf(last_block_hash.to_vec())),
+            label("last_block_index", leaf(last_block_index.to_vec())),
+        );
+        let tree: HashTree = label(
+            "canister",
+            label(
+                canister_id.as_slice().to_vec(),
+                label("certified_data", leaf(hash_tree.digest().to_vec())),
+            ),
+        );
+        DataCertificate {
+            certificate: serde_cbor::to_vec(&sign(key, tree, delegation)).unwrap(),
+            hash_tree: serde_cbor::to_vec(&hash_tree).unwrap(),
+        }
+    }
+
+    fn der_encode(key: &PrivateKey) -> Vec<u8> {
+        let mut der_key = BLS_DER_PREFIX.to_vec();
+        der_key.extend_from_slice(&key.public_key().serialize());
+        der_key
+    }
+
+    #[test]
+    fn test_verifies_a_mainnet_delegation() {
+        let root_key = hex::decode(MAINNET_ROOT_KEY).unwrap();
+        let certificate = decode_certificate(MAINNET_CERTIFICATE);
+        assert_eq!(
+            verify_certificate(&certificate, mainnet_canister(), &root_key),
+            Ok(())
+        );
+
+        let other_canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        assert!(matches!(
+            verify_certificate(&certificate, other_canister, &root_key),
+            Err(CertificateError::InvalidDelegation(_))
+        ));
+
+        let other_root_key = der_encode(&PrivateKey::deserialize(&[1; 32]).unwrap());
+        assert_eq!(
+            verify_certificate(&certificate, mainnet_canister(), &other_root_key),
+            Err(CertificateError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_verifies_a_tip_certificate() {
+        let root_key = PrivateKey::deserialize(&[1; 32]).unwrap();
+        let canister_id = mainnet_canister();
+        let data_certificate =
+            certify_tip(&root_key, None, canister_id, &[0xe5, 0x8e, 0x26], &[7; 32]);
+        assert_eq!(
+            verify_tip_certificate(&data_certificate, canister_id, &der_encode(&root_key)),
+            Ok(Tip {
+                last_block_index: 624_485,
+                last_block_hash: [7; 32],
+            })
+        );
+
+        let other_key = der_encode(&PrivateKey::deserialize(&[2; 32]).unwrap());
+        assert_eq!(
+            verify_tip_certificate(&data_certificate, canister_id, &other_key),
+            Err(CertificateError::InvalidSignature)
+        );
+
+        let other_canister = Principal::from_slice(&[1]);
+        assert_eq!(
+            verify_tip_certificate(&data_certificate, other_canister, &der_encode(&root_key)),
+            Err(CertificateError::MissingCertifiedData(other_canister))
+        );
+
+        let mut tampered = data_certificate;
+        tampered.hash_tree = serde_cbor::to_vec(&fork::<Vec<u8>>(
+            label("last_block_hash", leaf(vec![8; 32])),
+            label("last_block_index", leaf(vec![0xe5, 0x8e, 0x26])),
+        ))
+        .unwrap();
+        assert!(matches!(
+            verify_tip_certificate(&tampered, canister_id, &der_encode(&root_key)),
+            Err(CertificateError::CertifiedDataMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_leb128_u64() {
+        assert_eq!(decode_leb128_u64(&[0x00]), Some(0));
+        assert_eq!(decode_leb128_u64(&[0x7f]), Some(127));
+        assert_eq!(decode_leb128_u64(&[0xe5, 0x8e, 0x26]), Some(624_485));
+        assert_eq!(
+            decode_leb128_u64(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            Some(u64::MAX)
+        );
+        assert_eq!(decode_leb128_u64(&[]), None);
+        assert_eq!(decode_leb128_u64(&[0x80]), None);
+        assert_eq!(decode_leb128_u64(&[0x01, 0x00]), None);
+        assert_eq!(
+            decode_leb128_u64(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_der() {
+        let mut der_key = BLS_DER_PREFIX.to_vec();
+        der_key.extend_from_slice(&[7; BLS_KEY_LENGTH]);
+        assert_eq!(extract_der(&der_key), Ok(vec![7; BLS_KEY_LENGTH]));
+        assert!(extract_der(&der_key[1..]).is_err());
+        assert!(extract_der(&[7; BLS_KEY_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_certificate() {
+        let data_certificate = DataCertificate {
+            certificate: vec![0xff],
+            hash_tree: vec![],
+        };
+        assert!(matches!(
+            verify_tip_certificate(&data_certificate, Principal::anonymous(), &[]),
+            Err(CertificateError::MalformedCertificate(_))
+        ));
+    }
+
+    #[test]
+    fn test_requires_the_canister_ranges_of_the_delegated_subnet() {
+        let root_key = PrivateKey::deserialize(&[1; 32]).unwrap();
+        let subnet_key = PrivateKey::deserialize(&[2; 32]).unwrap();
+        let canister_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 5, 1, 1]);
+        let verify = |canister_ranges| {
+            let delegation = delegate(&root_key, &subnet_key, canister_ranges);
+            let data_certificate =
+                certify_tip(&subnet_key, Some(delegation), canister_id, &[1], &[7; 32]);
+            verify_tip_certificate(&data_certificate, canister_id, &der_encode(&root_key))
+        };
+
+        let range = |low: u8, high: u8| {
+            (
+                Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, low, 1, 1]),
+                Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, high, 1, 1]),
+            )
+        };
+        assert_eq!(
+            verify(Some(vec![range(0, 1), range(4, 6)])),
+            Ok(Tip {
+                last_block_index: 1,
+                last_block_hash: [7; 32],
+            })
+        );
+        assert_eq!(
+            verify(Some(vec![range(0, 4)])),
+            Err(CertificateError::InvalidDelegation(format!(
+                "the subnet is not authorized to certify canister {}",
+                canister_id
+            )))
+        );
+        assert_eq!(
+            verify(None),
+            Err(CertificateError::InvalidDelegation(
+                "the delegation does not contain the canister ranges of the subnet".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! An executor-independent timer.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Whether a sleep has completed and the waker of the task awaiting it.
+type SleepState = Arc<Mutex<(bool, Option<Waker>)>>;
+
+/// A pending sleep, ordered by its deadline and then by registration.
+struct Entry {
+    deadline: Instant,
+    seq: u64,
+    state: SleepState,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deadline, self.seq) == (other.deadline, other.seq)
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: BinaryHeap<Reverse<Entry>>,
+    next_seq: u64,
+}
+
+/// The sleeps of the process, completed by a single helper thread.
+struct Timer {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+impl Timer {
+    /// Returns the timer, starting its thread on first use.
+    fn get() -> &'static Timer {
+        static TIMER: OnceLock<Timer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            std::thread::Builder::new()
+                .name("icrc1-test-env-timer".to_string())
+                .spawn(|| Timer::get().run())
+                .expect("failed to spawn the timer thread");
+            Timer {
+                queue: Mutex::new(Queue::default()),
+                changed: Condvar::new(),
+            }
+        })
+    }
+
+    fn schedule(&self, deadline: Instant, state: SleepState) {
+        let mut queue = self.queue.lock().expect("failed to grab a lock");
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.entries.push(Reverse(Entry {
+            deadline,
+            seq,
+            state,
+        }));
+        self.changed.notify_one();
+    }
+
+    /// Completes the sleeps as their deadlines pass.
+    fn run(&self) {
+        let mut queue = self.queue.lock().expect("failed to grab a lock");
+        loop {
+            let now = Instant::now();
+            match queue.entries.peek() {
+                Some(Reverse(entry)) if entry.deadline <= now => {
+                    let Reverse(entry) = queue.entries.pop().expect("the queue is not empty");
+                    let mut state = entry.state.lock().expect("failed to grab a lock");
+                    state.0 = true;
+                    if let Some(waker) = state.1.take() {
+                        waker.wake();
+                    }
+                }
+                Some(Reverse(entry)) => {
+                    let timeout = entry.deadline - now;
+                    queue = self
+                        .changed
+                        .wait_timeout(queue, timeout)
+                        .expect("failed to grab a lock")
+                        .0;
+                }
+                None => {
+                    queue = self.changed.wait(queue).expect("failed to grab a lock");
+                }
+            }
+        }
+    }
+}
+
+/// A future completing after the duration, woken by a helper thread so that
+/// it works with any executor. All sleeps share the same thread.
+pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    struct Sleep {
+        state: SleepState,
+    }
+
+    impl Future for Sleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut state = self.state.lock().expect("failed to grab a lock");
+            if state.0 {
+                Poll::Ready(())
+            } else {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    let state = Arc::new(Mutex::new((false, None::<Waker>)));
+    Timer::get().schedule(Instant::now() + duration, state.clone());
+    Sleep { state }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::join_all;
+
+    #[test]
+    fn test_sleeps_complete_in_deadline_order() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let sleeps = [30u64, 10, 20].iter().map(|ms| {
+            let order = order.clone();
+            async move {
+                sleep(Duration::from_millis(*ms)).await;
+                order.lock().unwrap().push(*ms);
+            }
+        });
+        let start = Instant::now();
+        block_on(join_all(sleeps));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(*order.lock().unwrap(), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn test_zero_sleep_completes() {
+        block_on(sleep(Duration::from_millis(0)));
+    }
+}
@@ -0,0 +1,417 @@
+//! A [LedgerEnv] decorator injecting failures into the calls of the
+//! wrapped environment.
+
+use crate::delay::sleep;
+use crate::{ApproveError, LedgerEnv, TimeControl, TransferError, TransferFromError};
+use async_trait::async_trait;
+use candid::utils::encode_args;
+use candid::{Nat, Principal};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The method name matching every method without a plan of its own.
+pub const ANY_METHOD: &str = "*";
+
+/// A failure injected into a single call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The call is rejected without reaching the ledger.
+    Reject(String),
+    /// The ledger replies with `TemporarilyUnavailable` without applying
+    /// the call. Only applies to `icrc1_transfer`, `icrc2_approve` and
+    /// `icrc2_transfer_from`.
+    TemporarilyUnavailable,
+    /// The ledger replies with `GenericError` without applying the call.
+    /// Only applies to `icrc1_transfer`, `icrc2_approve` and
+    /// `icrc2_transfer_from`.
+    GenericError { error_code: Nat, message: String },
+    /// The call succeeds after the delay.
+    Latency(Duration),
+    /// The ledger applies the call but the caller never gets the reply.
+    ReplyLost,
+}
+
+/// When to inject faults into the calls of a method.
+#[derive(Clone, Debug)]
+enum FaultPlan {
+    /// The faults for successive calls; `None` lets a call through.
+    /// Calls after the end of the schedule are not affected.
+    Scripted(VecDeque<Option<Fault>>),
+    /// Faults injected with their probabilities, at most one per call.
+    Random(Vec<(f64, Fault)>),
+}
+
+struct Faults {
+    plans: BTreeMap<String, FaultPlan>,
+    rng: StdRng,
+}
+
+impl Faults {
+    fn next_fault(&mut self, method: &str) -> Option<Fault> {
+        let method = if self.plans.contains_key(method) {
+            method
+        } else {
+            ANY_METHOD
+        };
+        let plan = self.plans.get_mut(method)?;
+        match plan {
+            FaultPlan::Scripted(schedule) => schedule.pop_front().flatten(),
+            FaultPlan::Random(faults) => {
+                let mut roll: f64 = self.rng.gen();
+                for (probability, fault) in faults.iter() {
+                    if roll < *probability {
+                        return Some(fault.clone());
+                    }
+                    roll -= probability;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Wraps a ledger environment and injects rejects, error replies,
+/// latency and lost replies into its calls, either following scripted
+/// schedules or at random with configurable probabilities.
+///
+/// Forks share the schedules and the random number generator.
+#[derive(Clone)]
+pub struct FaultyLedger<L> {
+    inner: L,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl<L: LedgerEnv> FaultyLedger<L> {
+    /// Wraps the environment without injecting any faults. The seed makes
+    /// the random faults reproducible.
+    pub fn new(inner: L, seed: u64) -> Self {
+        Self {
+            inner,
+            faults: Arc::new(Mutex::new(Faults {
+                plans: BTreeMap::new(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Injects the faults into successive calls of the method, replacing
+    /// any previous plan for the method. `None` lets a call through.
+    pub fn with_schedule(
+        self,
+        method: impl Into<String>,
+        schedule: impl IntoIterator<Item = Option<Fault>>,
+    ) -> Self {
+        self.set_plan(
+            method.into(),
+            FaultPlan::Scripted(schedule.into_iter().collect()),
+        );
+        self
+    }
+
+    /// Injects the fault into calls of the method with the probability.
+    /// Faults added for the same method are mutually exclusive, so their
+    /// probabilities must not add up to more than 1.
+    pub fn with_probability(
+        self,
+        method: impl Into<String>,
+        probability: f64,
+        fault: Fault,
+    ) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "invalid fault probability {}",
+            probability
+        );
+        let method = method.into();
+        {
+            let mut faults = self.faults.lock().expect("failed to grab a lock");
+            let plan = faults
+                .plans
+                .entry(method.clone())
+                .or_insert_with(|| FaultPlan::Random(vec![]));
+            match plan {
+                FaultPlan::Random(random) => random.push((probability, fault)),
+                FaultPlan::Scripted(_) => *plan = FaultPlan::Random(vec![(probability, fault)]),
+            }
+            if let FaultPlan::Random(random) = plan {
+                let total: f64 = random.iter().map(|(probability, _)| probability).sum();
+                assert!(
+                    total <= 1.0,
+                    "the fault probabilities of {} add up to {}",
+                    method,
+                    total
+                );
+            }
+        }
+        self
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    fn set_plan(&self, method: String, plan: FaultPlan) {
+        self.faults
+            .lock()
+            .expect("failed to grab a lock")
+            .plans
+            .insert(method, plan);
+    }
+
+    fn next_fault(&self, method: &str) -> Option<Fault> {
+        self.faults
+            .lock()
+            .expect("failed to grab a lock")
+            .next_fault(method)
+    }
+}
+
+/// Encodes the error reply of an ICRC-1 or ICRC-2 update method.
+fn error_reply(method: &str, fault: &Fault) -> anyhow::Result<Vec<u8>> {
+    macro_rules! reply {
+        ($error:ident) => {{
+            let error = match fault {
+                Fault::TemporarilyUnavailable => $error::TemporarilyUnavailable,
+                Fault::GenericError {
+                    error_code,
+                    message,
+                } => $error::GenericError {
+                    error_code: error_code.clone(),
+                    message: message.clone(),
+                },
+                _ => unreachable!("{:?} is not an error reply", fault),
+            };
+            encode_args((Err::<Nat, $error>(error),))?
+        }};
+    }
+    Ok(match method {
+        "icrc1_transfer" => reply!(TransferError),
+        "icrc2_approve" => reply!(ApproveError),
+        "icrc2_transfer_from" => reply!(TransferFromError),
+        _ => anyhow::bail!("cannot inject {:?} into the reply of {}", fault, method),
+    })
+}
+
+/// Injects the fault into a call, executing it with `call` if the fault
+/// lets the call reach the ledger.
+async fn inject<F>(
+    fault: Option<Fault>,
+    kind: &str,
+    target: &str,
+    method: &str,
+    call: F,
+) -> anyhow::Result<Vec<u8>>
+where
+    F: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    match fault {
+        None => call.await,
+        Some(Fault::Reject(message)) => Err(anyhow::Error::msg(format!(
+            "{} call to {} was rejected: {}",
+            kind, target, message
+        ))),
+        Some(fault @ Fault::TemporarilyUnavailable) | Some(fault @ Fault::GenericError { .. }) => {
+            error_reply(method, &fault)
+        }
+        Some(Fault::Latency(delay)) => {
+            sleep(delay).await;
+            call.await
+        }
+        Some(Fault::ReplyLost) => {
+            let _ = call.await;
+            Err(anyhow::Error::msg(format!(
+                "{} call {} to {} timed out: the reply was lost",
+                kind, method, target
+            )))
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<L: LedgerEnv> LedgerEnv for FaultyLedger<L> {
+    fn fork(&self) -> Self {
+        Self {
+            inner: self.inner.fork(),
+            faults: self.faults.clone(),
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.inner.principal()
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        self.inner.canister_id()
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        self.inner.root_key().await
+    }
+
+    async fn time(&self) -> SystemTime {
+        self.inner.time().await
+    }
+
+    fn time_control(&self) -> Option<&dyn TimeControl> {
+        self.inner.time_control()
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let fault = self.next_fault(method);
+        let call = self.inner.query_raw(method, arg);
+        inject(fault, "Query", "the ledger", method, call).await
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let fault = self.next_fault(method);
+        let call = self.inner.query_canister_raw(canister_id, method, arg);
+        let target = format!("canister {}", canister_id);
+        inject(fault, "Query", &target, method, call).await
+    }
+
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let fault = self.next_fault(method);
+        let call = self.inner.update_raw(method, arg);
+        inject(fault, "Update", "the ledger", method, call).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc1::{balance_of, transfer};
+    use crate::{Account, Transfer};
+    use futures::executor::block_on;
+    use std::time::Instant;
+
+    /// A ledger counting the transfers it applies.
+    #[derive(Clone, Default)]
+    struct CountingLedger {
+        transfers: Arc<Mutex<u64>>,
+    }
+
+    impl CountingLedger {
+        fn transfers(&self) -> u64 {
+            *self.transfers.lock().unwrap()
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl LedgerEnv for CountingLedger {
+        fn fork(&self) -> Self {
+            self.clone()
+        }
+
+        fn principal(&self) -> Principal {
+            Principal::anonymous()
+        }
+
+        async fn time(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        async fn query_raw(&self, _method: &str, _arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Ok(encode_args((Nat::from(self.transfers()),))?)
+        }
+
+        async fn update_raw(&self, _method: &str, _arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            let mut transfers = self.transfers.lock().unwrap();
+            *transfers += 1;
+            Ok(encode_args((Ok::<Nat, TransferError>(Nat::from(
+                *transfers,
+            )),))?)
+        }
+    }
+
+    fn transfer_arg() -> Transfer {
+        Transfer::amount_to(1u8, Account::from(Principal::anonymous()))
+    }
+
+    #[test]
+    fn test_scripted_faults() {
+        let inner = CountingLedger::default();
+        let ledger = FaultyLedger::new(inner.clone(), 0).with_schedule(
+            "icrc1_transfer",
+            vec![
+                Some(Fault::Reject("out of cycles".to_string())),
+                Some(Fault::TemporarilyUnavailable),
+                Some(Fault::ReplyLost),
+                None,
+            ],
+        );
+
+        assert!(block_on(transfer(&ledger, transfer_arg())).is_err());
+        assert_eq!(inner.transfers(), 0);
+
+        assert_eq!(
+            block_on(transfer(&ledger, transfer_arg())).unwrap(),
+            Err(TransferError::TemporarilyUnavailable)
+        );
+        assert_eq!(inner.transfers(), 0);
+
+        assert!(block_on(transfer(&ledger, transfer_arg())).is_err());
+        assert_eq!(inner.transfers(), 1);
+
+        assert_eq!(
+            block_on(transfer(&ledger, transfer_arg())).unwrap(),
+            Ok(Nat::from(2u8))
+        );
+        // The schedule is exhausted.
+        assert_eq!(
+            block_on(transfer(&ledger.fork(), transfer_arg())).unwrap(),
+            Ok(Nat::from(3u8))
+        );
+    }
+
+    #[test]
+    fn test_error_replies_only_apply_to_updates() {
+        let ledger = FaultyLedger::new(CountingLedger::default(), 0)
+            .with_schedule(ANY_METHOD, vec![Some(Fault::TemporarilyUnavailable)]);
+        assert!(block_on(balance_of(&ledger, Principal::anonymous())).is_err());
+    }
+
+    #[test]
+    fn test_random_faults() {
+        let inner = CountingLedger::default();
+        let always = FaultyLedger::new(inner.clone(), 0).with_probability(
+            "icrc1_transfer",
+            1.0,
+            Fault::ReplyLost,
+        );
+        for _ in 0..10 {
+            assert!(block_on(transfer(&always, transfer_arg())).is_err());
+        }
+        assert_eq!(inner.transfers(), 10);
+
+        let never = FaultyLedger::new(inner, 0).with_probability(
+            "icrc1_transfer",
+            0.0,
+            Fault::Reject("rejected".to_string()),
+        );
+        for _ in 0..10 {
+            assert!(block_on(transfer(&never, transfer_arg())).unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_latency() {
+        let delay = Duration::from_millis(50);
+        let ledger = FaultyLedger::new(CountingLedger::default(), 0)
+            .with_schedule(ANY_METHOD, vec![Some(Fault::Latency(delay))]);
+        let started = Instant::now();
+        assert_eq!(
+            block_on(balance_of(&ledger, Principal::anonymous())).unwrap(),
+            Nat::from(0u8)
+        );
+        assert!(started.elapsed() >= delay);
+    }
+}
@@ -0,0 +1,428 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use candid::utils::{decode_args, encode_args, ArgumentDecoder};
+use candid::{CandidType, Principal};
+use icrc1_test_env::{
+    Account, AllowanceArgs, ApproveArgs, DataCertificate, GetArchivesArgs, GetArchivesResult,
+    GetBlocksArgs, LedgerEnv, TimeControl, Transfer, TransferFromArgs,
+};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+mod mutation;
+mod state;
+
+pub use mutation::Mutation;
+pub use state::InitArgs;
+use state::State;
+
+fn new_principal(n: u64) -> Principal {
+    let mut bytes = n.to_le_bytes().to_vec();
+    bytes.push(0xfe);
+    bytes.push(0x01);
+    Principal::try_from_slice(&bytes[..]).unwrap()
+}
+
+fn system_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("the system time is before the UNIX epoch")
+        .as_nanos() as u64
+}
+
+/// Decodes the call arguments, applies the method and encodes its result.
+fn dispatch<Args, Ret>(
+    method: &str,
+    in_bytes: &[u8],
+    f: impl FnOnce(Args) -> Ret,
+) -> anyhow::Result<Vec<u8>>
+where
+    Args: for<'a> ArgumentDecoder<'a>,
+    Ret: CandidType,
+{
+    let args = decode_args(in_bytes).with_context(|| {
+        format!(
+            "Failed to decode the arguments of method {}, bytes: {}",
+            method,
+            hex::encode(in_bytes)
+        )
+    })?;
+    encode_args((f(args),))
+        .with_context(|| format!("Failed to encode the response of method {}", method))
+}
+
+/// A ledger implementing the ICRC-1, ICRC-2 and ICRC-3 semantics in
+/// memory, without a canister.
+#[derive(Clone)]
+pub struct InMemoryLedger {
+    counter: Arc<AtomicU64>,
+    state: Arc<Mutex<State>>,
+    /// The difference between the ledger time and the system time.
+    time_offset_nanos: Arc<AtomicI64>,
+    sender: Principal,
+    canister_id: Principal,
+    archive_id: Principal,
+}
+
+#[async_trait(?Send)]
+impl LedgerEnv for InMemoryLedger {
+    fn fork(&self) -> Self {
+        Self {
+            counter: self.counter.clone(),
+            state: self.state.clone(),
+            time_offset_nanos: self.time_offset_nanos.clone(),
+            sender: new_principal(self.counter.fetch_add(1, Ordering::Relaxed)),
+            canister_id: self.canister_id,
+            archive_id: self.archive_id,
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.sender
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        Some(self.canister_id)
+    }
+
+    async fn time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(self.now_nanos())
+    }
+
+    fn time_control(&self) -> Option<&dyn TimeControl> {
+        Some(self)
+    }
+
+    async fn query_raw(&self, method: &str, in_bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let state = self.state.lock().expect("failed to grab a lock");
+        let now = self.now_nanos();
+        match method {
+            "icrc1_name" => dispatch(method, &in_bytes, |()| state.token_name()),
+            "icrc1_symbol" => dispatch(method, &in_bytes, |()| state.token_symbol()),
+            "icrc1_decimals" => dispatch(method, &in_bytes, |()| state.decimals()),
+            "icrc1_fee" => dispatch(method, &in_bytes, |()| state.transfer_fee()),
+            "icrc1_metadata" => dispatch(method, &in_bytes, |()| state.metadata()),
+            "icrc1_total_supply" => dispatch(method, &in_bytes, |()| state.total_supply()),
+            "icrc1_minting_account" => dispatch(method, &in_bytes, |()| state.minting_account()),
+            "icrc1_supported_standards" => {
+                dispatch(method, &in_bytes, |()| state.supported_standards())
+            }
+            "icrc1_balance_of" => dispatch(method, &in_bytes, |(account,): (Account,)| {
+                state.balance_of(&account)
+            }),
+            "icrc2_allowance" => dispatch(method, &in_bytes, |(args,): (AllowanceArgs,)| {
+                state.allowance(&args, now)
+            }),
+            "icrc3_get_blocks" => dispatch(method, &in_bytes, |(args,): (GetBlocksArgs,)| {
+                state.get_blocks(args, self.archive_id)
+            }),
+            "icrc3_get_archives" => {
+                dispatch(
+                    method,
+                    &in_bytes,
+                    |(args,): (GetArchivesArgs,)| match args.from {
+                        Some(_) => GetArchivesResult::new(),
+                        None => state.get_archives(self.archive_id),
+                    },
+                )
+            }
+            "icrc3_get_tip_certificate" => {
+                dispatch(method, &in_bytes, |()| Option::<DataCertificate>::None)
+            }
+            "icrc3_supported_block_types" => {
+                dispatch(method, &in_bytes, |()| state.supported_block_types())
+            }
+            _ => anyhow::bail!(
+                "Query call to ledger {:?} was rejected: unknown method {}",
+                self.canister_id,
+                method
+            ),
+        }
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        in_bytes: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        if canister_id == self.canister_id {
+            return self.query_raw(method, in_bytes).await;
+        }
+        if canister_id == self.archive_id && method == "icrc3_get_blocks" {
+            let state = self.state.lock().expect("failed to grab a lock");
+            return dispatch(method, &in_bytes, |(args,): (GetBlocksArgs,)| {
+                state.get_archived_blocks(args)
+            });
+        }
+        anyhow::bail!(
+            "Query call to canister {:?} was rejected: the canister does not exist",
+            canister_id
+        )
+    }
+
+    async fn update_raw(&self, method: &str, in_bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let mut state = self.state.lock().expect("failed to grab a lock");
+        let now = self.now_nanos();
+        let caller = self.sender;
+        match method {
+            "icrc1_transfer" => dispatch(method, &in_bytes, |(args,): (Transfer,)| {
+                state.transfer(caller, args, now)
+            }),
+            "icrc2_approve" => dispatch(method, &in_bytes, |(args,): (ApproveArgs,)| {
+                state.approve(caller, args, now)
+            }),
+            "icrc2_transfer_from" => dispatch(method, &in_bytes, |(args,): (TransferFromArgs,)| {
+                state.transfer_from(caller, args, now)
+            }),
+            _ => anyhow::bail!(
+                "Update call to ledger {:?} was rejected: unknown method {}",
+                self.canister_id,
+                method
+            ),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl TimeControl for InMemoryLedger {
+    async fn advance_time(&self, duration: Duration) {
+        self.time_offset_nanos
+            .fetch_add(duration.as_nanos() as i64, Ordering::Relaxed);
+    }
+
+    async fn set_time(&self, time: SystemTime) {
+        let nanos = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("the time is before the UNIX epoch")
+            .as_nanos() as i64;
+        self.time_offset_nanos
+            .store(nanos - system_nanos() as i64, Ordering::Relaxed);
+    }
+}
+
+impl InMemoryLedger {
+    /// Returns the ledger time in nanoseconds since the UNIX epoch.
+    fn now_nanos(&self) -> u64 {
+        (system_nanos() as i64 + self.time_offset_nanos.load(Ordering::Relaxed)) as u64
+    }
+
+    pub fn new(init: InitArgs, sender: Principal) -> Self {
+        Self::build(init, None, sender)
+    }
+
+    /// Creates a ledger that deliberately violates the standards in the way
+    /// described by the mutation.
+    pub fn with_mutation(init: InitArgs, mutation: Mutation, sender: Principal) -> Self {
+        Self::build(init, Some(mutation), sender)
+    }
+
+    /// Moves the blocks below the index to an archive canister, which
+    /// `icrc3_get_blocks` then points to with archive callbacks.
+    pub fn archive_blocks(&self, up_to: u64) {
+        self.state
+            .lock()
+            .expect("failed to grab a lock")
+            .archive_blocks(up_to);
+    }
+
+    fn build(init: InitArgs, mutation: Option<Mutation>, sender: Principal) -> Self {
+        Self {
+            counter: Arc::new(AtomicU64::new(0)),
+            state: Arc::new(Mutex::new(State::new(init, mutation, system_nanos()))),
+            time_offset_nanos: Arc::new(AtomicI64::new(0)),
+            sender,
+            canister_id: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]),
+            archive_id: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 3, 1, 1]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use futures::TryStreamExt;
+    use icrc1_test_env::icrc1::{balance_of, transfer, transfer_fee};
+    use icrc1_test_env::icrc3::get_blocks;
+    use icrc1_test_env::{block_stream, GetBlocksRequest};
+    use icrc1_test_suite::{
+        execute_tests, execute_tests_with_reporters, icrc3_test_get_blocks, optional_test_suite,
+        test_suite, ExecutionConfig, Outcome, Reporter, TapReporter, TestCase, TestFailure,
+        TestFilter,
+    };
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Records whether a test was skipped.
+    struct Skipped(Rc<Cell<bool>>);
+
+    impl Reporter for Skipped {
+        fn run_started(&mut self, _tests: usize, _header: &[String]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn test_passed(&mut self, _test: &TestCase<'_>, _duration: Duration) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn test_skipped(
+            &mut self,
+            _test: &TestCase<'_>,
+            _reason: &str,
+            _duration: Duration,
+        ) -> io::Result<()> {
+            self.0.set(true);
+            Ok(())
+        }
+
+        fn test_failed(
+            &mut self,
+            _test: &TestCase<'_>,
+            _failure: &TestFailure,
+            _duration: Duration,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn run_finished(&mut self, _duration: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_ledger() -> InMemoryLedger {
+        let sender = new_principal(u64::MAX);
+        InMemoryLedger::new(
+            InitArgs {
+                initial_mints: vec![(Account::from(sender), Nat::from(100_000_000_000u64))],
+                minting_account: Account::from(Principal::from_slice(&[
+                    0, 0, 0, 0, 0, 0, 0, 2, 1, 1,
+                ])),
+                token_name: "In-Memory Token".to_string(),
+                token_symbol: "IMT".to_string(),
+                decimals: 8,
+                transfer_fee: Nat::from(10_000u32),
+            },
+            sender,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_block_stream_follows_the_archive() {
+        let env = test_ledger();
+        for n in 0..5u8 {
+            let to = Account::from(new_principal(n.into()));
+            transfer(&env, Transfer::amount_to(1_000u32, to))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let all_blocks = || async {
+            block_stream(&env, Nat::from(0u8))
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        };
+        let expected = all_blocks().await;
+        assert_eq!(expected.len(), 6);
+
+        env.archive_blocks(4);
+        let result = get_blocks(
+            &env,
+            vec![GetBlocksRequest {
+                start: Nat::from(0u8),
+                length: Nat::from(6u8),
+            }],
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.blocks.len(), 2);
+        assert_eq!(result.archived_blocks.len(), 1);
+        assert_eq!(all_blocks().await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_get_blocks_checks_archived_blocks() {
+        let env = test_ledger();
+        for _ in 0..5 {
+            let to = Account::from(env.fork().principal());
+            transfer(&env, Transfer::amount_to(1_000u32, to))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        env.archive_blocks(6);
+        match icrc3_test_get_blocks(env).await {
+            Ok(Outcome::Passed) => {}
+            Ok(Outcome::Skipped { reason }) => panic!("the test was skipped: {}", reason),
+            Err(err) => panic!("the test failed: {:#}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_ledger_passes_the_suite() {
+        let env = test_ledger();
+        let mut tests = test_suite(env.clone(), None).await;
+        tests.extend(optional_test_suite(env));
+        assert!(execute_tests(tests).await);
+    }
+
+    #[tokio::test]
+    async fn test_declared_costs_match_the_spend() {
+        let env = test_ledger();
+        let mut names: Vec<String> = test_suite(env.clone(), None)
+            .await
+            .iter()
+            .map(|test| test.name().to_string())
+            .collect();
+        names.extend(
+            optional_test_suite(env)
+                .iter()
+                .map(|test| test.name().to_string()),
+        );
+
+        let mut mismatches = vec![];
+        for name in names {
+            let env = test_ledger();
+            let filter = TestFilter::default().include(name.as_str());
+            let mut tests = test_suite(env.clone(), Some(&filter)).await;
+            tests.extend(filter.apply(optional_test_suite(env.clone())));
+            assert_eq!(tests.len(), 1, "no test is called {}", name);
+            let cost = tests[0].metadata().cost;
+
+            let fee = transfer_fee(&env).await.unwrap();
+            let before = balance_of(&env, env.principal()).await.unwrap();
+            let skipped = Rc::new(Cell::new(false));
+            let mut reporters: Vec<Box<dyn Reporter>> = vec![
+                Box::new(TapReporter::stdout()),
+                Box::new(Skipped(skipped.clone())),
+            ];
+            let passed =
+                execute_tests_with_reporters(tests, &ExecutionConfig::default(), &mut reporters)
+                    .await;
+            assert!(passed, "{} failed", name);
+            let after = balance_of(&env, env.principal()).await.unwrap();
+
+            // A skipped test may stop before spending all it declares.
+            let spent = before - after;
+            let mismatch = if skipped.get() {
+                spent > cost.total(&fee)
+            } else {
+                spent != cost.total(&fee)
+            };
+            if mismatch {
+                mismatches.push(format!(
+                    "{} spent {} tokens but declares {:?}, {} tokens",
+                    name,
+                    spent,
+                    cost,
+                    cost.total(&fee)
+                ));
+            }
+        }
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    }
+}
@@ -0,0 +1,47 @@
+//! Intentional ledger bugs used to measure what the test suite catches.
+
+use std::fmt;
+
+/// A deviation from the ICRC-1 and ICRC-2 semantics that the in-memory
+/// ledger can be built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    /// Transfers, approvals and transfers from do not charge the fee.
+    FeeNotCharged,
+    /// Deduplication ignores the memo, so transactions that differ only in
+    /// the memo are reported as duplicates.
+    DedupIgnoresMemo,
+    /// A missing subaccount and the default subaccount `[0; 32]` are
+    /// treated as different accounts.
+    DistinctDefaultSubaccount,
+    /// `icrc2_transfer_from` spends allowances after they expire.
+    ExpiredApprovalSpendable,
+    /// `BadFee` errors report an `expected_fee` one token off.
+    WrongExpectedFee,
+}
+
+impl Mutation {
+    pub const ALL: [Mutation; 5] = [
+        Mutation::FeeNotCharged,
+        Mutation::DedupIgnoresMemo,
+        Mutation::DistinctDefaultSubaccount,
+        Mutation::ExpiredApprovalSpendable,
+        Mutation::WrongExpectedFee,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mutation::FeeNotCharged => "fee_not_charged",
+            Mutation::DedupIgnoresMemo => "dedup_ignores_memo",
+            Mutation::DistinctDefaultSubaccount => "distinct_default_subaccount",
+            Mutation::ExpiredApprovalSpendable => "expired_approval_spendable",
+            Mutation::WrongExpectedFee => "wrong_expected_fee",
+        }
+    }
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,720 @@
+//! The ledger state machine behind [crate::InMemoryLedger].
+
+use crate::Mutation;
+use candid::{Nat, Principal};
+use icrc1_test_env::{
+    Account, Allowance, AllowanceArgs, ApproveArgs, ApproveError, ArchiveInfo, ArchivedBlocks,
+    BlockWithId, GetBlocksArgs, GetBlocksFn, GetBlocksRequest, GetBlocksResult, Subaccount,
+    SupportedBlockType, SupportedStandard, Transfer, TransferError, TransferFromArgs,
+    TransferFromError, Value,
+};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// The time window in which the ledger deduplicates transactions.
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The tolerated difference between the caller and the ledger clocks.
+const PERMITTED_DRIFT: u64 = 60 * 1_000_000_000;
+
+/// The maximum memo length in bytes.
+const MAX_MEMO_LENGTH: usize = 32;
+
+/// The maximum number of blocks returned by a single `icrc3_get_blocks` call.
+const MAX_BLOCKS_PER_RESPONSE: u64 = 1_000;
+
+/// The initial configuration of the ledger.
+#[derive(Clone, Debug)]
+pub struct InitArgs {
+    pub initial_mints: Vec<(Account, Nat)>,
+    pub minting_account: Account,
+    pub token_name: String,
+    pub token_symbol: String,
+    pub decimals: u8,
+    pub transfer_fee: Nat,
+}
+
+/// An account with the missing subaccount replaced by the default one,
+/// unless the ledger runs with [Mutation::DistinctDefaultSubaccount].
+type AccountKey = (Principal, Option<Subaccount>);
+
+/// Encodes the account as in ICRC-3 blocks, keeping the subaccount exactly
+/// as the caller specified it so that it is part of the transaction identity.
+fn account_value(account: &Account) -> Value {
+    let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = account.subaccount {
+        parts.push(Value::Blob(subaccount.to_vec()));
+    }
+    Value::Array(parts)
+}
+
+fn nat_value(n: impl Into<Nat>) -> Value {
+    Value::Nat(n.into())
+}
+
+/// The errors common to all ledger operations, converted into the
+/// endpoint-specific error types.
+enum TxError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    Generic { message: String },
+}
+
+impl From<TxError> for TransferError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            TxError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+            TxError::TooOld => TransferError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => {
+                TransferError::CreatedInFuture { ledger_time }
+            }
+            TxError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            TxError::Generic { message } => TransferError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        }
+    }
+}
+
+impl From<TxError> for ApproveError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            TxError::InsufficientFunds { balance } => ApproveError::InsufficientFunds { balance },
+            TxError::TooOld => ApproveError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => {
+                ApproveError::CreatedInFuture { ledger_time }
+            }
+            TxError::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            TxError::Generic { message } => ApproveError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        }
+    }
+}
+
+impl From<TxError> for TransferFromError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::BadFee { expected_fee } => TransferFromError::BadFee { expected_fee },
+            TxError::InsufficientFunds { balance } => {
+                TransferFromError::InsufficientFunds { balance }
+            }
+            TxError::TooOld => TransferFromError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+            TxError::Duplicate { duplicate_of } => TransferFromError::Duplicate { duplicate_of },
+            TxError::Generic { message } => TransferFromError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        }
+    }
+}
+
+/// A transaction recorded for deduplication.
+struct RecordedTx {
+    created_at_time: u64,
+    block_index: u64,
+}
+
+pub struct State {
+    minting_account: Account,
+    token_name: String,
+    token_symbol: String,
+    decimals: u8,
+    transfer_fee: Nat,
+    balances: BTreeMap<AccountKey, Nat>,
+    allowances: BTreeMap<(AccountKey, AccountKey), Allowance>,
+    blocks: Vec<Value>,
+    /// The number of blocks moved to the archive.
+    archived: u64,
+    /// Transactions with `created_at_time` set, keyed by their hash.
+    transactions: BTreeMap<[u8; 32], RecordedTx>,
+    mutation: Option<Mutation>,
+}
+
+impl State {
+    pub fn new(init: InitArgs, mutation: Option<Mutation>, now: u64) -> Self {
+        let mut state = Self {
+            minting_account: init.minting_account,
+            token_name: init.token_name,
+            token_symbol: init.token_symbol,
+            decimals: init.decimals,
+            transfer_fee: init.transfer_fee,
+            balances: BTreeMap::new(),
+            allowances: BTreeMap::new(),
+            blocks: vec![],
+            archived: 0,
+            transactions: BTreeMap::new(),
+            mutation,
+        };
+        for (account, amount) in init.initial_mints {
+            state.credit(&account, &amount);
+            let tx = vec![
+                ("to".to_string(), account_value(&account)),
+                ("amt".to_string(), Value::Nat(amount)),
+            ];
+            state.append_block("1mint", tx, None, now);
+        }
+        state
+    }
+
+    pub fn token_name(&self) -> String {
+        self.token_name.clone()
+    }
+
+    pub fn token_symbol(&self) -> String {
+        self.token_symbol.clone()
+    }
+
+    pub fn decimals(&self) -> u8 {
+        self.decimals
+    }
+
+    pub fn transfer_fee(&self) -> Nat {
+        self.transfer_fee.clone()
+    }
+
+    pub fn minting_account(&self) -> Option<Account> {
+        Some(self.minting_account.clone())
+    }
+
+    pub fn metadata(&self) -> Vec<(String, Value)> {
+        vec![
+            ("icrc1:name".to_string(), Value::Text(self.token_name())),
+            ("icrc1:symbol".to_string(), Value::Text(self.token_symbol())),
+            ("icrc1:decimals".to_string(), nat_value(self.decimals)),
+            ("icrc1:fee".to_string(), Value::Nat(self.transfer_fee())),
+        ]
+    }
+
+    pub fn supported_standards(&self) -> Vec<SupportedStandard> {
+        ["ICRC-1", "ICRC-2", "ICRC-3"]
+            .iter()
+            .map(|name| SupportedStandard {
+                name: name.to_string(),
+                url: format!(
+                    "https://github.com/dfinity/ICRC-1/tree/main/standards/{}",
+                    name
+                ),
+            })
+            .collect()
+    }
+
+    pub fn supported_block_types(&self) -> Vec<SupportedBlockType> {
+        ["1mint", "1burn", "1xfer", "2xfer", "2approve"]
+            .iter()
+            .map(|block_type| SupportedBlockType {
+                block_type: block_type.to_string(),
+                url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+            })
+            .collect()
+    }
+
+    pub fn total_supply(&self) -> Nat {
+        self.balances
+            .values()
+            .fold(Nat::from(0u8), |total, balance| total + balance.clone())
+    }
+
+    pub fn balance_of(&self, account: &Account) -> Nat {
+        self.balances
+            .get(&self.key(account))
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0u8))
+    }
+
+    pub fn allowance(&self, args: &AllowanceArgs, now: u64) -> Allowance {
+        match self
+            .allowances
+            .get(&(self.key(&args.account), self.key(&args.spender)))
+        {
+            Some(allowance) if !is_expired(allowance, now) => allowance.clone(),
+            _ => Allowance {
+                allowance: Nat::from(0u8),
+                expires_at: None,
+            },
+        }
+    }
+
+    /// Moves the blocks below the index to the archive.
+    pub fn archive_blocks(&mut self, up_to: u64) {
+        self.archived = self.archived.max(up_to.min(self.blocks.len() as u64));
+    }
+
+    pub fn get_archives(&self, archive_id: Principal) -> Vec<ArchiveInfo> {
+        if self.archived == 0 {
+            return vec![];
+        }
+        vec![ArchiveInfo {
+            canister_id: archive_id,
+            start: Nat::from(0u8),
+            end: Nat::from(self.archived - 1),
+        }]
+    }
+
+    /// Returns the requested blocks held by the ledger and callbacks into
+    /// the archive for the archived ones.
+    pub fn get_blocks(&self, args: GetBlocksArgs, archive_id: Principal) -> GetBlocksResult {
+        let log_length = self.blocks.len() as u64;
+        let mut blocks = vec![];
+        let mut archived_blocks = vec![];
+        for request in args {
+            let (start, end) = self.block_range(&request, log_length, blocks.len());
+            if start < self.archived {
+                let archived_end = end.min(self.archived);
+                archived_blocks.push(ArchivedBlocks {
+                    args: vec![GetBlocksRequest {
+                        start: Nat::from(start),
+                        length: Nat::from(archived_end - start),
+                    }],
+                    callback: GetBlocksFn::new(archive_id, "icrc3_get_blocks".to_string()),
+                });
+            }
+            self.push_blocks(&mut blocks, start.max(self.archived), end);
+        }
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks,
+            archived_blocks,
+        }
+    }
+
+    /// Serves `icrc3_get_blocks` calls to the archive.
+    pub fn get_archived_blocks(&self, args: GetBlocksArgs) -> GetBlocksResult {
+        let mut blocks = vec![];
+        for request in args {
+            let (start, end) = self.block_range(&request, self.archived, blocks.len());
+            self.push_blocks(&mut blocks, start, end);
+        }
+        GetBlocksResult {
+            log_length: Nat::from(self.archived),
+            blocks,
+            archived_blocks: vec![],
+        }
+    }
+
+    /// Returns the range of blocks below `max` to return for the request,
+    /// given the number of blocks already in the response.
+    fn block_range(&self, request: &GetBlocksRequest, max: u64, returned: usize) -> (u64, u64) {
+        let start = u64::try_from(&request.start.0).unwrap_or(u64::MAX);
+        let length = u64::try_from(&request.length.0)
+            .unwrap_or(u64::MAX)
+            .min(MAX_BLOCKS_PER_RESPONSE.saturating_sub(returned as u64));
+        let end = start.saturating_add(length).min(max);
+        (start.min(end), end)
+    }
+
+    fn push_blocks(&self, blocks: &mut Vec<BlockWithId>, start: u64, end: u64) {
+        for id in start..end {
+            blocks.push(BlockWithId {
+                id: Nat::from(id),
+                block: self.blocks[id as usize].clone(),
+            });
+        }
+    }
+
+    pub fn transfer(
+        &mut self,
+        caller: Principal,
+        args: Transfer,
+        now: u64,
+    ) -> Result<Nat, TransferError> {
+        let from = Account {
+            owner: caller,
+            subaccount: args.from_subaccount,
+        };
+        self.check_tx(&args.memo, args.created_at_time, now)?;
+
+        if self.key(&from) == self.key(&self.minting_account) {
+            self.check_fee(&args.fee, Nat::from(0u8))?;
+            let mut tx = vec![
+                ("to".to_string(), account_value(&args.to)),
+                ("amt".to_string(), Value::Nat(args.amount.clone())),
+            ];
+            push_common_fields(&mut tx, &args.fee, &args.memo, args.created_at_time);
+            self.check_duplicate("1mint", &tx, args.created_at_time, now)?;
+            self.credit(&args.to, &args.amount);
+            return Ok(self.record("1mint", tx, None, args.created_at_time, now));
+        }
+
+        if self.key(&args.to) == self.key(&self.minting_account) {
+            self.check_fee(&args.fee, Nat::from(0u8))?;
+            if args.amount < self.transfer_fee {
+                return Err(TransferError::BadBurn {
+                    min_burn_amount: self.transfer_fee.clone(),
+                });
+            }
+            let mut tx = vec![
+                ("from".to_string(), account_value(&from)),
+                ("amt".to_string(), Value::Nat(args.amount.clone())),
+            ];
+            push_common_fields(&mut tx, &args.fee, &args.memo, args.created_at_time);
+            self.check_duplicate("1burn", &tx, args.created_at_time, now)?;
+            self.debit(&from, &args.amount)?;
+            return Ok(self.record("1burn", tx, None, args.created_at_time, now));
+        }
+
+        self.check_fee(&args.fee, self.transfer_fee.clone())?;
+        let mut tx = vec![
+            ("from".to_string(), account_value(&from)),
+            ("to".to_string(), account_value(&args.to)),
+            ("amt".to_string(), Value::Nat(args.amount.clone())),
+        ];
+        push_common_fields(&mut tx, &args.fee, &args.memo, args.created_at_time);
+        self.check_duplicate("1xfer", &tx, args.created_at_time, now)?;
+        self.debit(
+            &from,
+            &(args.amount.clone() + self.charged(&self.transfer_fee)),
+        )?;
+        self.credit(&args.to, &args.amount);
+        let fee = args.fee.is_none().then(|| self.transfer_fee.clone());
+        Ok(self.record("1xfer", tx, fee, args.created_at_time, now))
+    }
+
+    pub fn approve(
+        &mut self,
+        caller: Principal,
+        args: ApproveArgs,
+        now: u64,
+    ) -> Result<Nat, ApproveError> {
+        let from = Account {
+            owner: caller,
+            subaccount: args.from_subaccount,
+        };
+        self.check_tx(&args.memo, args.created_at_time, now)?;
+        if self.key(&from) == self.key(&args.spender) {
+            return Err(TxError::Generic {
+                message: "self approval is not allowed".to_string(),
+            }
+            .into());
+        }
+        self.check_fee(&args.fee, self.transfer_fee.clone())?;
+        if let Some(expires_at) = args.expires_at {
+            if expires_at <= now {
+                return Err(ApproveError::Expired { ledger_time: now });
+            }
+        }
+
+        let mut tx = vec![
+            ("from".to_string(), account_value(&from)),
+            ("spender".to_string(), account_value(&args.spender)),
+            ("amt".to_string(), Value::Nat(args.amount.clone())),
+        ];
+        if let Some(expected_allowance) = &args.expected_allowance {
+            tx.push((
+                "expected_allowance".to_string(),
+                Value::Nat(expected_allowance.clone()),
+            ));
+        }
+        if let Some(expires_at) = args.expires_at {
+            tx.push(("expires_at".to_string(), nat_value(expires_at)));
+        }
+        push_common_fields(&mut tx, &args.fee, &args.memo, args.created_at_time);
+        self.check_duplicate("2approve", &tx, args.created_at_time, now)?;
+
+        if let Some(expected_allowance) = &args.expected_allowance {
+            let current_allowance = self
+                .allowance(
+                    &AllowanceArgs {
+                        account: from.clone(),
+                        spender: args.spender.clone(),
+                    },
+                    now,
+                )
+                .allowance;
+            if &current_allowance != expected_allowance {
+                return Err(ApproveError::AllowanceChanged { current_allowance });
+            }
+        }
+
+        self.debit(&from, &self.charged(&self.transfer_fee))?;
+        let pair = (self.key(&from), self.key(&args.spender));
+        if args.amount == 0u8 {
+            self.allowances.remove(&pair);
+        } else {
+            self.allowances.insert(
+                pair,
+                Allowance {
+                    allowance: args.amount.clone(),
+                    expires_at: args.expires_at,
+                },
+            );
+        }
+        let fee = args.fee.is_none().then(|| self.transfer_fee.clone());
+        Ok(self.record("2approve", tx, fee, args.created_at_time, now))
+    }
+
+    pub fn transfer_from(
+        &mut self,
+        caller: Principal,
+        args: TransferFromArgs,
+        now: u64,
+    ) -> Result<Nat, TransferFromError> {
+        let spender = Account {
+            owner: caller,
+            subaccount: args.spender_subaccount,
+        };
+        self.check_tx(&args.memo, args.created_at_time, now)?;
+
+        let is_burn = self.key(&args.to) == self.key(&self.minting_account);
+        let fee = if is_burn {
+            if args.amount < self.transfer_fee {
+                return Err(TransferFromError::BadBurn {
+                    min_burn_amount: self.transfer_fee.clone(),
+                });
+            }
+            Nat::from(0u8)
+        } else {
+            self.transfer_fee.clone()
+        };
+        self.check_fee(&args.fee, fee.clone())?;
+
+        let btype = if is_burn { "1burn" } else { "2xfer" };
+        let mut tx = vec![
+            ("from".to_string(), account_value(&args.from)),
+            ("spender".to_string(), account_value(&spender)),
+            ("amt".to_string(), Value::Nat(args.amount.clone())),
+        ];
+        if !is_burn {
+            tx.push(("to".to_string(), account_value(&args.to)));
+        }
+        push_common_fields(&mut tx, &args.fee, &args.memo, args.created_at_time);
+        self.check_duplicate(btype, &tx, args.created_at_time, now)?;
+
+        let total = args.amount.clone() + self.charged(&fee);
+        let pair = (self.key(&args.from), self.key(&spender));
+        let uses_allowance = pair.0 != pair.1;
+        if uses_allowance {
+            let allowance_time = if self.mutation == Some(Mutation::ExpiredApprovalSpendable) {
+                0
+            } else {
+                now
+            };
+            let allowance = self
+                .allowance(
+                    &AllowanceArgs {
+                        account: args.from.clone(),
+                        spender: spender.clone(),
+                    },
+                    allowance_time,
+                )
+                .allowance;
+            if allowance < total {
+                return Err(TransferFromError::InsufficientAllowance { allowance });
+            }
+        }
+
+        self.debit(&args.from, &total)?;
+        if !is_burn {
+            self.credit(&args.to, &args.amount);
+        }
+        if uses_allowance {
+            let allowance = self
+                .allowances
+                .get_mut(&pair)
+                .expect("the allowance was checked above");
+            allowance.allowance = allowance.allowance.clone() - total;
+            if allowance.allowance == 0u8 {
+                self.allowances.remove(&pair);
+            }
+        }
+        let block_fee = (args.fee.is_none() && !is_burn).then_some(fee);
+        Ok(self.record(btype, tx, block_fee, args.created_at_time, now))
+    }
+
+    fn key(&self, account: &Account) -> AccountKey {
+        if self.mutation == Some(Mutation::DistinctDefaultSubaccount) {
+            (account.owner, account.subaccount)
+        } else {
+            (account.owner, Some(account.subaccount.unwrap_or([0; 32])))
+        }
+    }
+
+    /// Returns the fee the ledger actually deducts for an operation
+    /// with the specified fee.
+    fn charged(&self, fee: &Nat) -> Nat {
+        if self.mutation == Some(Mutation::FeeNotCharged) {
+            Nat::from(0u8)
+        } else {
+            fee.clone()
+        }
+    }
+
+    /// Identifies a transaction for deduplication by its block type and
+    /// the `tx` field of its block.
+    fn tx_hash(&self, btype: &str, tx: &[(String, Value)]) -> [u8; 32] {
+        let tx = tx
+            .iter()
+            .filter(|(field, _)| {
+                field != "memo" || self.mutation != Some(Mutation::DedupIgnoresMemo)
+            })
+            .cloned()
+            .collect();
+        Value::Map(vec![
+            ("btype".to_string(), Value::Text(btype.to_string())),
+            ("tx".to_string(), Value::Map(tx)),
+        ])
+        .hash()
+    }
+
+    fn credit(&mut self, account: &Account, amount: &Nat) {
+        let key = self.key(account);
+        let balance = self.balances.entry(key).or_insert_with(|| Nat::from(0u8));
+        *balance = balance.clone() + amount.clone();
+    }
+
+    fn debit(&mut self, account: &Account, amount: &Nat) -> Result<(), TxError> {
+        let balance = self.balance_of(account);
+        if balance < *amount {
+            return Err(TxError::InsufficientFunds { balance });
+        }
+        let key = self.key(account);
+        self.balances.insert(key, balance - amount.clone());
+        Ok(())
+    }
+
+    fn check_tx(
+        &self,
+        memo: &Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Result<(), TxError> {
+        if let Some(memo) = memo {
+            if memo.len() > MAX_MEMO_LENGTH {
+                return Err(TxError::Generic {
+                    message: format!(
+                        "the memo is {} bytes long, the maximum is {}",
+                        memo.len(),
+                        MAX_MEMO_LENGTH
+                    ),
+                });
+            }
+        }
+        if let Some(created_at_time) = created_at_time {
+            if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+                return Err(TxError::TooOld);
+            }
+            if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+                return Err(TxError::CreatedInFuture { ledger_time: now });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_fee(&self, fee: &Option<Nat>, expected_fee: Nat) -> Result<(), TxError> {
+        match fee {
+            Some(fee) if fee != &expected_fee => {
+                let expected_fee = if self.mutation == Some(Mutation::WrongExpectedFee) {
+                    expected_fee + 1u8
+                } else {
+                    expected_fee
+                };
+                Err(TxError::BadFee { expected_fee })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_duplicate(
+        &self,
+        btype: &str,
+        tx: &[(String, Value)],
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Result<(), TxError> {
+        if created_at_time.is_none() {
+            return Ok(());
+        }
+        match self.transactions.get(&self.tx_hash(btype, tx)) {
+            Some(recorded)
+                if recorded
+                    .created_at_time
+                    .saturating_add(TX_WINDOW + PERMITTED_DRIFT)
+                    >= now =>
+            {
+                Err(TxError::Duplicate {
+                    duplicate_of: Nat::from(recorded.block_index),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends the block for a transaction and remembers the transaction
+    /// for deduplication. Returns the block index.
+    fn record(
+        &mut self,
+        btype: &str,
+        tx: Vec<(String, Value)>,
+        fee: Option<Nat>,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Nat {
+        let hash = self.tx_hash(btype, &tx);
+        let block_index = self.append_block(btype, tx, fee, now);
+        if let Some(created_at_time) = created_at_time {
+            self.transactions.insert(
+                hash,
+                RecordedTx {
+                    created_at_time,
+                    block_index,
+                },
+            );
+        }
+        Nat::from(block_index)
+    }
+
+    fn append_block(
+        &mut self,
+        btype: &str,
+        tx: Vec<(String, Value)>,
+        fee: Option<Nat>,
+        now: u64,
+    ) -> u64 {
+        let mut block = vec![
+            ("btype".to_string(), Value::Text(btype.to_string())),
+            ("ts".to_string(), nat_value(now)),
+            ("tx".to_string(), Value::Map(tx)),
+        ];
+        if let Some(parent) = self.blocks.last() {
+            block.push(("phash".to_string(), Value::Blob(parent.hash().to_vec())));
+        }
+        if let Some(fee) = fee {
+            block.push(("fee".to_string(), Value::Nat(fee)));
+        }
+        self.blocks.push(Value::Map(block));
+        self.blocks.len() as u64 - 1
+    }
+}
+
+fn push_common_fields(
+    tx: &mut Vec<(String, Value)>,
+    fee: &Option<Nat>,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) {
+    if let Some(fee) = fee {
+        tx.push(("fee".to_string(), Value::Nat(fee.clone())));
+    }
+    if let Some(memo) = memo {
+        tx.push(("memo".to_string(), Value::Blob(memo.clone())));
+    }
+    if let Some(created_at_time) = created_at_time {
+        tx.push(("ts".to_string(), nat_value(created_at_time)));
+    }
+}
+
+fn is_expired(allowance: &Allowance, now: u64) -> bool {
+    allowance
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= now)
+}
@@ -1,10 +1,37 @@
+use anyhow::Context;
 use async_trait::async_trait;
-use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::utils::{decode_args, encode_args, ArgumentDecoder, ArgumentEncoder};
 use candid::Principal;
 use candid::{CandidType, Int, Nat};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
 
+mod block;
+mod block_stream;
+mod certificate;
+mod delay;
+mod fault;
+mod mock;
+mod recording;
+mod replaying;
+mod retry;
+mod sweep;
+mod trace;
+
+pub use block::{Block, BlockDecodeError, BlockInfo};
+pub use block_stream::{block_stream, BlockStreamError};
+pub use certificate::{decode_leb128_u64, verify_tip_certificate, CertificateError, Tip};
+pub use delay::sleep;
+pub use fault::{Fault, FaultyLedger, ANY_METHOD};
+pub use mock::{ExpectationBuilder, MockLedger};
+pub use recording::{correlation_id, with_correlation_id, RecordingLedger};
+pub use replaying::{ReplayingLedger, TraceDivergence};
+pub use retry::{is_retryable_error, ErrorClassifier, RetryPolicy, RetryingLedger};
+pub use sweep::{SweepFailure, SweepReport, SweepingLedger};
+
 pub type Subaccount = [u8; 32];
 
 #[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
@@ -22,6 +49,125 @@ impl From<Principal> for Account {
     }
 }
 
+/// Formats the account using the textual encoding from
+/// standards/ICRC-1/TextualEncoding.md.
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.subaccount {
+            Some(subaccount) if subaccount != &[0; 32] => write!(
+                f,
+                "{}-{}.{}",
+                self.owner,
+                account_checksum(&self.owner, subaccount),
+                hex::encode(subaccount).trim_start_matches('0')
+            ),
+            _ => write!(f, "{}", self.owner),
+        }
+    }
+}
+
+impl FromStr for Account {
+    type Err = AccountParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (principal_and_checksum, subaccount_hex) = match s.split_once('.') {
+            Some(parts) => parts,
+            None => {
+                let owner = Principal::from_text(s)
+                    .map_err(|e| AccountParseError::InvalidPrincipal(e.to_string()))?;
+                return Ok(Account::from(owner));
+            }
+        };
+
+        let (principal_text, checksum) = match principal_and_checksum.rsplit_once('-') {
+            Some((principal_text, checksum)) if checksum.len() == CHECKSUM_TEXT_LEN => {
+                (principal_text, checksum)
+            }
+            _ => return Err(AccountParseError::MissingChecksum),
+        };
+
+        let owner = Principal::from_text(principal_text)
+            .map_err(|e| AccountParseError::InvalidPrincipal(e.to_string()))?;
+
+        if subaccount_hex.starts_with('0') {
+            return Err(AccountParseError::NotCanonical(
+                "leading zeros are not allowed in subaccounts".to_string(),
+            ));
+        }
+        if subaccount_hex.len() > 64 {
+            return Err(AccountParseError::InvalidSubaccount(format!(
+                "expected at most 64 hex digits, got {}",
+                subaccount_hex.len()
+            )));
+        }
+        let mut subaccount = [0u8; 32];
+        hex::decode_to_slice(format!("{:0>64}", subaccount_hex), &mut subaccount)
+            .map_err(|e| AccountParseError::InvalidSubaccount(e.to_string()))?;
+        if subaccount == [0; 32] {
+            return Err(AccountParseError::NotCanonical(
+                "the default subaccount should be omitted".to_string(),
+            ));
+        }
+
+        let expected = account_checksum(&owner, &subaccount);
+        if checksum != expected {
+            return Err(AccountParseError::BadChecksum {
+                expected,
+                actual: checksum.to_string(),
+            });
+        }
+
+        Ok(Account {
+            owner,
+            subaccount: Some(subaccount),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum AccountParseError {
+    #[error("invalid principal: {0}")]
+    InvalidPrincipal(String),
+    #[error("the account checksum is missing")]
+    MissingChecksum,
+    #[error("invalid account checksum, expected {expected}, got {actual}")]
+    BadChecksum { expected: String, actual: String },
+    #[error("invalid subaccount: {0}")]
+    InvalidSubaccount(String),
+    #[error("the representation is not canonical: {0}")]
+    NotCanonical(String),
+}
+
+/// The length of a base32-encoded CRC-32 checksum.
+const CHECKSUM_TEXT_LEN: usize = 7;
+
+fn account_checksum(owner: &Principal, subaccount: &Subaccount) -> String {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    base32_lowercase_no_padding(&hasher.finalize().to_be_bytes())
+}
+
+fn base32_lowercase_no_padding(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut text = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    text
+}
+
 #[derive(CandidType, Deserialize, PartialEq, Clone, Debug)]
 pub struct SupportedStandard {
     pub name: String,
@@ -34,6 +180,100 @@ pub enum Value {
     Blob(Vec<u8>),
     Nat(Nat),
     Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            Value::Blob(blob) => Some(blob),
+            _ => None,
+        }
+    }
+
+    pub fn as_nat(&self) -> Option<&Nat> {
+        match self {
+            Value::Nat(nat) => Some(nat),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<&Int> {
+        match self {
+            Value::Int(int) => Some(int),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Map(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// Returns the value stored under the specified key if this value is a map.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_map()?
+            .iter()
+            .find_map(|(k, v)| (k == key).then_some(v))
+    }
+
+    /// Computes the representation-independent hash of the value as
+    /// specified in ICRC-3 (see standards/ICRC-3/HASHINGVALUES.md).
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+            Value::Blob(blob) => Sha256::digest(blob).into(),
+            Value::Nat(nat) => {
+                let mut buf = vec![];
+                nat.encode(&mut buf)
+                    .expect("failed to write the LEB128 encoding of a Nat");
+                Sha256::digest(&buf).into()
+            }
+            Value::Int(int) => {
+                let mut buf = vec![];
+                int.encode(&mut buf)
+                    .expect("failed to write the SLEB128 encoding of an Int");
+                Sha256::digest(&buf).into()
+            }
+            Value::Array(values) => {
+                let mut hasher = Sha256::new();
+                for value in values {
+                    hasher.update(value.hash());
+                }
+                hasher.finalize().into()
+            }
+            Value::Map(entries) => {
+                let mut hashes: Vec<([u8; 32], [u8; 32])> = entries
+                    .iter()
+                    .map(|(k, v)| (Sha256::digest(k.as_bytes()).into(), v.hash()))
+                    .collect();
+                hashes.sort();
+                let mut hasher = Sha256::new();
+                for (key_hash, value_hash) in hashes {
+                    hasher.update(key_hash);
+                    hasher.update(value_hash);
+                }
+                hasher.finalize().into()
+            }
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, PartialEq, Eq, Debug, Clone, Error)]
@@ -56,14 +296,14 @@ pub enum TransferError {
     GenericError { error_code: Nat, message: String },
 }
 
-#[derive(CandidType, Debug, Clone)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
 pub struct Transfer {
-    from_subaccount: Option<Subaccount>,
-    amount: Nat,
-    to: Account,
-    fee: Option<Nat>,
-    created_at_time: Option<u64>,
-    memo: Option<Vec<u8>>,
+    pub from_subaccount: Option<Subaccount>,
+    pub amount: Nat,
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub created_at_time: Option<u64>,
+    pub memo: Option<Vec<u8>>,
 }
 
 impl Transfer {
@@ -99,7 +339,7 @@ impl Transfer {
     }
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct ApproveArgs {
     pub from_subaccount: Option<Subaccount>,
     pub spender: Account,
@@ -173,7 +413,7 @@ pub enum ApproveError {
     GenericError { error_code: Nat, message: String },
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct TransferFromArgs {
     pub spender_subaccount: Option<Subaccount>,
     pub from: Account,
@@ -244,7 +484,7 @@ pub enum TransferFromError {
     GenericError { error_code: Nat, message: String },
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct AllowanceArgs {
     pub account: Account,
     pub spender: Account,
@@ -257,6 +497,75 @@ pub struct Allowance {
     pub expires_at: Option<u64>,
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GetBlocksRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+pub type GetBlocksArgs = Vec<GetBlocksRequest>;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksFn : (GetBlocksArgs) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: GetBlocksArgs,
+    pub callback: GetBlocksFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+pub type GetArchivesResult = Vec<ArchiveInfo>;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+/// An optional capability of environments that can move the ledger time,
+/// exposed through [LedgerEnv::time_control].
+///
+/// Moving the time affects every environment pointing to the same ledger,
+/// so tests using it must not run concurrently with other tests.
+#[async_trait(?Send)]
+pub trait TimeControl {
+    /// Moves the ledger time forward by the duration.
+    async fn advance_time(&self, duration: std::time::Duration);
+
+    /// Sets the ledger time.
+    async fn set_time(&self, time: std::time::SystemTime);
+}
+
 #[async_trait(?Send)]
 pub trait LedgerEnv {
     /// Creates a new environment pointing to the same ledger but using a new caller.
@@ -265,20 +574,113 @@ pub trait LedgerEnv {
     /// Returns the caller's principal.
     fn principal(&self) -> Principal;
 
+    /// Returns the canister id of the ledger, or None if the environment
+    /// does not expose it.
+    fn canister_id(&self) -> Option<Principal> {
+        None
+    }
+
+    /// Returns the DER-encoded root key of the network hosting the ledger,
+    /// or None if the environment cannot provide it.
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        None
+    }
+
     /// Returns the approximation of the current ledger time.
     async fn time(&self) -> std::time::SystemTime;
 
+    /// Returns the time control of the environment, or None if the
+    /// environment cannot move the ledger time.
+    fn time_control(&self) -> Option<&dyn TimeControl> {
+        None
+    }
+
+    /// Executes a query call with Candid-encoded arguments on the ledger and
+    /// returns the Candid-encoded reply.
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+    /// Executes a query call with Candid-encoded arguments on another
+    /// canister, such as an archive of the ledger, and returns the
+    /// Candid-encoded reply. Fails by default.
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        _arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!(
+            "the environment cannot call {} on canister {}",
+            method,
+            canister_id
+        )
+    }
+
+    /// Executes an update call with Candid-encoded arguments on the ledger
+    /// and returns the Candid-encoded reply.
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
     /// Executes a query call with the specified arguments on the ledger.
     async fn query<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
     where
         Input: ArgumentEncoder + std::fmt::Debug,
-        Output: for<'a> ArgumentDecoder<'a>;
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let in_bytes = encode_input(input)?;
+        let bytes = self.query_raw(method, in_bytes).await?;
+        decode_output(method, &bytes)
+    }
+
+    /// Executes a query call with the specified arguments on another canister,
+    /// such as an archive of the ledger.
+    async fn query_canister<Input, Output>(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        input: Input,
+    ) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let in_bytes = encode_input(input)?;
+        let bytes = self
+            .query_canister_raw(canister_id, method, in_bytes)
+            .await?;
+        decode_output(method, &bytes)
+    }
 
     /// Executes an update call with the specified arguments on the ledger.
     async fn update<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
     where
         Input: ArgumentEncoder + std::fmt::Debug,
-        Output: for<'a> ArgumentDecoder<'a>;
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let in_bytes = encode_input(input)?;
+        let bytes = self.update_raw(method, in_bytes).await?;
+        decode_output(method, &bytes)
+    }
+}
+
+fn encode_input<Input>(input: Input) -> anyhow::Result<Vec<u8>>
+where
+    Input: ArgumentEncoder + std::fmt::Debug,
+{
+    let debug_inputs = format!("{:?}", input);
+    encode_args(input).with_context(|| format!("Failed to encode arguments {}", debug_inputs))
+}
+
+fn decode_output<Output>(method: &str, bytes: &[u8]) -> anyhow::Result<Output>
+where
+    Output: for<'a> ArgumentDecoder<'a>,
+{
+    decode_args(bytes).with_context(|| {
+        format!(
+            "Failed to decode method {} response into type {}, bytes: {}",
+            method,
+            std::any::type_name::<Output>(),
+            hex::encode(bytes)
+        )
+    })
 }
 
 pub mod icrc1 {
@@ -370,3 +772,239 @@ pub mod icrc2 {
         ledger.query("icrc2_allowance", (arg,)).await.map(|(t,)| t)
     }
 }
+
+pub mod icrc3 {
+    use crate::{
+        DataCertificate, GetArchivesArgs, GetArchivesResult, GetBlocksArgs, GetBlocksResult,
+        LedgerEnv, SupportedBlockType,
+    };
+
+    pub async fn get_blocks(
+        ledger: &impl LedgerEnv,
+        args: GetBlocksArgs,
+    ) -> anyhow::Result<GetBlocksResult> {
+        ledger
+            .query("icrc3_get_blocks", (args,))
+            .await
+            .map(|(t,)| t)
+    }
+
+    pub async fn get_archives(
+        ledger: &impl LedgerEnv,
+        args: GetArchivesArgs,
+    ) -> anyhow::Result<GetArchivesResult> {
+        ledger
+            .query("icrc3_get_archives", (args,))
+            .await
+            .map(|(t,)| t)
+    }
+
+    pub async fn get_tip_certificate(
+        ledger: &impl LedgerEnv,
+    ) -> anyhow::Result<Option<DataCertificate>> {
+        ledger
+            .query("icrc3_get_tip_certificate", ())
+            .await
+            .map(|(t,)| t)
+    }
+
+    pub async fn supported_block_types(
+        ledger: &impl LedgerEnv,
+    ) -> anyhow::Result<Vec<SupportedBlockType>> {
+        ledger
+            .query("icrc3_supported_block_types", ())
+            .await
+            .map(|(t,)| t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_hash(value: Value, expected: &str) {
+        assert_eq!(hex::encode(value.hash()), expected, "hash of {:?}", value);
+    }
+
+    #[test]
+    fn hash_nat() {
+        assert_hash(
+            Value::Nat(Nat::from(42u8)),
+            "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1",
+        );
+    }
+
+    #[test]
+    fn hash_int() {
+        assert_hash(
+            Value::Int(Int::from(-42)),
+            "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc",
+        );
+    }
+
+    #[test]
+    fn hash_text() {
+        assert_hash(
+            Value::Text("Hello, World!".to_string()),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f",
+        );
+    }
+
+    #[test]
+    fn hash_blob() {
+        assert_hash(
+            Value::Blob(vec![1, 2, 3, 4]),
+            "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a",
+        );
+    }
+
+    #[test]
+    fn hash_array() {
+        assert_hash(
+            Value::Array(vec![
+                Value::Nat(Nat::from(3u8)),
+                Value::Text("foo".to_string()),
+                Value::Blob(vec![5, 6]),
+            ]),
+            "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6",
+        );
+    }
+
+    #[test]
+    fn hash_map() {
+        assert_hash(
+            Value::Map(vec![
+                (
+                    "from".to_string(),
+                    Value::Blob(
+                        b"\x00\xab\xcd\xef\x00\x12\x34\x00\x56\x78\x9a\x00\xbc\xde\xf0\x00\x01\x23\x45\x67\x89\x00\xab\xcd\xef\x01"
+                            .to_vec(),
+                    ),
+                ),
+                (
+                    "to".to_string(),
+                    Value::Blob(
+                        b"\x00\xab\x0d\xef\x00\x12\x34\x00\x56\x78\x9a\x00\xbc\xde\xf0\x00\x01\x23\x45\x67\x89\x00\xab\xcd\xef\x01"
+                            .to_vec(),
+                    ),
+                ),
+                ("amount".to_string(), Value::Nat(Nat::from(42u8))),
+                ("created_at".to_string(), Value::Nat(Nat::from(1699218263u64))),
+                ("memo".to_string(), Value::Nat(Nat::from(0u8))),
+            ]),
+            "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75",
+        );
+    }
+
+    #[test]
+    fn hash_map_is_order_independent() {
+        let entries = vec![
+            ("a".to_string(), Value::Nat(Nat::from(1u8))),
+            ("b".to_string(), Value::Text("x".to_string())),
+        ];
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        assert_eq!(Value::Map(entries).hash(), Value::Map(reversed).hash());
+    }
+
+    const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+    fn owner() -> Principal {
+        Principal::from_text(OWNER).unwrap()
+    }
+
+    fn sequential_subaccount() -> Subaccount {
+        let mut subaccount = [0u8; 32];
+        for (i, b) in subaccount.iter_mut().enumerate() {
+            *b = i as u8 + 1;
+        }
+        subaccount
+    }
+
+    #[test]
+    fn account_to_text_default_subaccount() {
+        assert_eq!(Account::from(owner()).to_string(), OWNER);
+        assert_eq!(
+            Account {
+                owner: owner(),
+                subaccount: Some([0; 32]),
+            }
+            .to_string(),
+            OWNER
+        );
+    }
+
+    #[test]
+    fn account_to_text_non_default_subaccount() {
+        let account = Account {
+            owner: owner(),
+            subaccount: Some(sequential_subaccount()),
+        };
+        assert_eq!(
+            account.to_string(),
+            format!(
+                "{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+                OWNER
+            )
+        );
+    }
+
+    #[test]
+    fn account_from_text() {
+        assert_eq!(Account::from_str(OWNER), Ok(Account::from(owner())));
+
+        let mut subaccount = [0u8; 32];
+        subaccount[31] = 1;
+        assert_eq!(
+            Account::from_str(&format!("{}-6cc627i.1", OWNER)),
+            Ok(Account {
+                owner: owner(),
+                subaccount: Some(subaccount),
+            })
+        );
+
+        assert_eq!(
+            Account::from_str(&format!(
+                "{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+                OWNER
+            )),
+            Ok(Account {
+                owner: owner(),
+                subaccount: Some(sequential_subaccount()),
+            })
+        );
+    }
+
+    #[test]
+    fn account_from_text_errors() {
+        assert!(matches!(
+            Account::from_str(&format!("{}-q6bn32y.", OWNER)),
+            Err(AccountParseError::NotCanonical(_))
+        ));
+        assert!(matches!(
+            Account::from_str("k2t6j2nvnp4zjm3-25dtz6xhaac7boj5gayfoj3xs-i43lp-teztq-6ae"),
+            Err(AccountParseError::InvalidPrincipal(_))
+        ));
+        assert!(matches!(
+            Account::from_str(&format!("{}-6cc627i.01", OWNER)),
+            Err(AccountParseError::NotCanonical(_))
+        ));
+        assert_eq!(
+            Account::from_str(&format!("{}.1", OWNER)),
+            Err(AccountParseError::MissingChecksum)
+        );
+        assert!(matches!(
+            Account::from_str(&format!("{}-dfxgiyy.1", OWNER)),
+            Err(AccountParseError::BadChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn account_text_roundtrip() {
+        let account = Account {
+            owner: owner(),
+            subaccount: Some(sequential_subaccount()),
+        };
+        assert_eq!(Account::from_str(&account.to_string()), Ok(account));
+    }
+}
@@ -0,0 +1,519 @@
+//! A scriptable [LedgerEnv] for unit tests of code built on top of it.
+
+use crate::LedgerEnv;
+use async_trait::async_trait;
+use candid::utils::{decode_args, encode_args};
+use candid::{CandidType, Principal};
+use serde::de::DeserializeOwned;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CallKind {
+    Query,
+    Update,
+}
+
+impl fmt::Display for CallKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallKind::Query => f.write_str("query"),
+            CallKind::Update => f.write_str("update"),
+        }
+    }
+}
+
+type Matcher = Box<dyn Fn(&[u8]) -> Result<(), String>>;
+
+enum Response {
+    Reply(Vec<u8>),
+    Reject(String),
+}
+
+struct Expectation {
+    kind: CallKind,
+    canister_id: Principal,
+    method: String,
+    arg: Option<(String, Matcher)>,
+    response: Response,
+    times: usize,
+    calls: usize,
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} on {}", self.kind, self.method, self.canister_id)?;
+        if let Some((description, _)) = &self.arg {
+            write!(f, " with arg {}", description)?;
+        }
+        write!(f, " (called {} of {} times)", self.calls, self.times)
+    }
+}
+
+struct Expectations {
+    expectations: Vec<Expectation>,
+}
+
+impl Expectations {
+    fn unmet(&self) -> Vec<String> {
+        self.expectations
+            .iter()
+            .filter(|expectation| expectation.calls < expectation.times)
+            .map(|expectation| expectation.to_string())
+            .collect()
+    }
+
+    /// Explains why no expectation matches the call, showing the difference
+    /// between the expected and the actual argument where possible.
+    fn describe_unexpected(
+        &self,
+        kind: CallKind,
+        canister_id: Principal,
+        method: &str,
+        caller: Principal,
+        arg: &[u8],
+    ) -> String {
+        let mut message = format!(
+            "unexpected mock ledger call: {} {} on {} by {} with arg bytes {}",
+            kind,
+            method,
+            canister_id,
+            caller,
+            hex::encode(arg)
+        );
+        let candidates: Vec<String> = self
+            .expectations
+            .iter()
+            .filter(|expectation| {
+                expectation.kind == kind
+                    && expectation.canister_id == canister_id
+                    && expectation.method == method
+            })
+            .map(|expectation| {
+                let reason = match &expectation.arg {
+                    Some((_, matcher)) => matcher(arg).err(),
+                    None => None,
+                };
+                match reason {
+                    Some(reason) => format!("{}:\n{}", expectation, reason),
+                    None => format!("{}: already satisfied", expectation),
+                }
+            })
+            .collect();
+        if !candidates.is_empty() {
+            message.push_str(&format!("\nexpectations for {}:", method));
+            for candidate in candidates {
+                message.push_str("\n  ");
+                message.push_str(&candidate.replace('\n', "\n    "));
+            }
+        } else {
+            let unmet = self.unmet();
+            if unmet.is_empty() {
+                message.push_str("\nno expectations are left");
+            } else {
+                message.push_str("\nunmet expectations:\n  ");
+                message.push_str(&unmet.join("\n  "));
+            }
+        }
+        message
+    }
+}
+
+impl Drop for Expectations {
+    fn drop(&mut self) {
+        let unmet = self.unmet();
+        if !unmet.is_empty() && !std::thread::panicking() {
+            panic!("unmet mock ledger expectations:\n  {}", unmet.join("\n  "));
+        }
+    }
+}
+
+/// A ledger environment answering calls from declared expectations.
+///
+/// Each call takes the first expectation, in declaration order, that
+/// matches it and has calls left. The mock panics on calls matching no
+/// expectation and, when the last clone or fork is dropped, on unmet
+/// expectations.
+#[derive(Clone)]
+pub struct MockLedger {
+    expectations: Rc<RefCell<Expectations>>,
+    counter: Rc<Cell<u64>>,
+    principal: Principal,
+    canister_id: Principal,
+    time: SystemTime,
+}
+
+impl Default for MockLedger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockLedger {
+    pub fn new() -> Self {
+        Self {
+            expectations: Rc::new(RefCell::new(Expectations {
+                expectations: vec![],
+            })),
+            counter: Rc::new(Cell::new(0)),
+            principal: Principal::anonymous(),
+            canister_id: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]),
+            time: SystemTime::now(),
+        }
+    }
+
+    pub fn with_principal(mut self, principal: Principal) -> Self {
+        self.principal = principal;
+        self
+    }
+
+    pub fn with_canister_id(mut self, canister_id: Principal) -> Self {
+        self.canister_id = canister_id;
+        self
+    }
+
+    pub fn with_time(mut self, time: SystemTime) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Declares an expected query call of the method on the ledger.
+    pub fn expect_query(&self, method: &str) -> ExpectationBuilder<'_> {
+        self.expect(CallKind::Query, method)
+    }
+
+    /// Declares an expected update call of the method on the ledger.
+    pub fn expect_update(&self, method: &str) -> ExpectationBuilder<'_> {
+        self.expect(CallKind::Update, method)
+    }
+
+    fn expect(&self, kind: CallKind, method: &str) -> ExpectationBuilder<'_> {
+        ExpectationBuilder {
+            ledger: self,
+            kind,
+            canister_id: self.canister_id,
+            method: method.to_string(),
+            arg: None,
+            times: 1,
+        }
+    }
+
+    /// Checks that all expectations declared so far have been met.
+    pub fn verify(&self) -> Result<(), String> {
+        let unmet = self.expectations.borrow().unmet();
+        if unmet.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "unmet mock ledger expectations:\n  {}",
+                unmet.join("\n  ")
+            ))
+        }
+    }
+
+    fn respond(
+        &self,
+        kind: CallKind,
+        canister_id: Principal,
+        method: &str,
+        arg: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut expectations = self.expectations.borrow_mut();
+        let index = expectations.expectations.iter().position(|expectation| {
+            expectation.kind == kind
+                && expectation.canister_id == canister_id
+                && expectation.method == method
+                && expectation.calls < expectation.times
+                && expectation
+                    .arg
+                    .as_ref()
+                    .map_or(true, |(_, matcher)| matcher(arg).is_ok())
+        });
+        match index {
+            Some(index) => {
+                let expectation = &mut expectations.expectations[index];
+                expectation.calls += 1;
+                match &expectation.response {
+                    Response::Reply(reply) => Ok(reply.clone()),
+                    Response::Reject(message) => Err(anyhow::Error::msg(format!(
+                        "{} call to canister {:?} was rejected: {}",
+                        kind, canister_id, message
+                    ))),
+                }
+            }
+            None => {
+                let message = expectations.describe_unexpected(
+                    kind,
+                    canister_id,
+                    method,
+                    self.principal,
+                    arg,
+                );
+                drop(expectations);
+                panic!("{}", message)
+            }
+        }
+    }
+}
+
+/// Builds an expectation; the expectation is declared by calling
+/// [ExpectationBuilder::returning] or [ExpectationBuilder::rejecting].
+pub struct ExpectationBuilder<'a> {
+    ledger: &'a MockLedger,
+    kind: CallKind,
+    canister_id: Principal,
+    method: String,
+    arg: Option<(String, Matcher)>,
+    times: usize,
+}
+
+impl<'a> ExpectationBuilder<'a> {
+    /// Expects the call on another canister, such as an archive.
+    pub fn on_canister(mut self, canister_id: Principal) -> Self {
+        self.canister_id = canister_id;
+        self
+    }
+
+    /// Expects the single argument of the call to equal the value.
+    pub fn with_arg_eq<A>(mut self, expected: A) -> Self
+    where
+        A: CandidType + DeserializeOwned + PartialEq + fmt::Debug + 'static,
+    {
+        let description = format!("{:?}", expected);
+        let matcher = move |arg: &[u8]| {
+            let actual: A = decode_arg(arg)?;
+            if actual == expected {
+                Ok(())
+            } else {
+                Err(line_diff(
+                    &format!("{:#?}", expected),
+                    &format!("{:#?}", actual),
+                ))
+            }
+        };
+        self.arg = Some((description, Box::new(matcher)));
+        self
+    }
+
+    /// Expects the single argument of the call to satisfy the predicate.
+    pub fn with_arg<A>(mut self, predicate: impl Fn(&A) -> bool + 'static) -> Self
+    where
+        A: CandidType + DeserializeOwned + fmt::Debug + 'static,
+    {
+        let description = format!("{} matching a predicate", std::any::type_name::<A>());
+        let matcher = move |arg: &[u8]| {
+            let actual: A = decode_arg(arg)?;
+            if predicate(&actual) {
+                Ok(())
+            } else {
+                Err(format!("the predicate rejects {:#?}", actual))
+            }
+        };
+        self.arg = Some((description, Box::new(matcher)));
+        self
+    }
+
+    /// Expects the call exactly the number of times, once by default.
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = times;
+        self
+    }
+
+    /// Declares the expectation, replying with the single value.
+    pub fn returning<T: CandidType>(self, reply: T) -> &'a MockLedger {
+        let reply = encode_args((reply,)).expect("failed to encode the mock reply");
+        self.declare(Response::Reply(reply))
+    }
+
+    /// Declares the expectation, rejecting the call with the message.
+    pub fn rejecting(self, message: impl Into<String>) -> &'a MockLedger {
+        self.declare(Response::Reject(message.into()))
+    }
+
+    fn declare(self, response: Response) -> &'a MockLedger {
+        self.ledger
+            .expectations
+            .borrow_mut()
+            .expectations
+            .push(Expectation {
+                kind: self.kind,
+                canister_id: self.canister_id,
+                method: self.method,
+                arg: self.arg,
+                response,
+                times: self.times,
+                calls: 0,
+            });
+        self.ledger
+    }
+}
+
+fn decode_arg<A>(arg: &[u8]) -> Result<A, String>
+where
+    A: CandidType + DeserializeOwned,
+{
+    decode_args::<(A,)>(arg).map(|(arg,)| arg).map_err(|e| {
+        format!(
+            "failed to decode the argument as {}: {}",
+            std::any::type_name::<A>(),
+            e
+        )
+    })
+}
+
+/// Renders the lines removed from `expected` with `-` and the lines added
+/// in `actual` with `+`.
+fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    // lcs[i][j] is the length of the longest common subsequence of
+    // expected[i..] and actual[j..].
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut diff = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            diff.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    diff.join("\n")
+}
+
+#[async_trait(?Send)]
+impl LedgerEnv for MockLedger {
+    fn fork(&self) -> Self {
+        let n = self.counter.get();
+        self.counter.set(n + 1);
+        let mut bytes = n.to_le_bytes().to_vec();
+        bytes.push(0xfd);
+        bytes.push(0x01);
+        Self {
+            principal: Principal::from_slice(&bytes),
+            ..self.clone()
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.principal
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        Some(self.canister_id)
+    }
+
+    async fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.respond(CallKind::Query, self.canister_id, method, &arg)
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.respond(CallKind::Query, canister_id, method, &arg)
+    }
+
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.respond(CallKind::Update, self.canister_id, method, &arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc1::{balance_of, transfer};
+    use crate::{Account, Transfer, TransferError};
+    use candid::Nat;
+    use futures::executor::block_on;
+
+    fn account(n: u8) -> Account {
+        Account::from(Principal::from_slice(&[n]))
+    }
+
+    #[test]
+    fn test_replies_from_expectations() {
+        let ledger = MockLedger::new();
+        ledger
+            .expect_query("icrc1_balance_of")
+            .with_arg_eq(account(1))
+            .times(2)
+            .returning(Nat::from(10u8));
+        ledger
+            .expect_update("icrc1_transfer")
+            .with_arg(|arg: &Transfer| arg.amount == 5u8)
+            .returning(Ok::<Nat, TransferError>(Nat::from(0u8)))
+            .expect_update("icrc1_transfer")
+            .rejecting("out of cycles");
+
+        for _ in 0..2 {
+            assert_eq!(
+                block_on(balance_of(&ledger, account(1))).unwrap(),
+                Nat::from(10u8)
+            );
+        }
+        assert_eq!(
+            block_on(transfer(&ledger, Transfer::amount_to(5u8, account(2)))).unwrap(),
+            Ok(Nat::from(0u8))
+        );
+        let err = block_on(transfer(
+            &ledger.fork(),
+            Transfer::amount_to(6u8, account(2)),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("out of cycles"));
+        assert_eq!(ledger.verify(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "unmet mock ledger expectations")]
+    fn test_panics_on_unmet_expectations() {
+        let ledger = MockLedger::new();
+        ledger
+            .expect_query("icrc1_balance_of")
+            .returning(Nat::from(10u8));
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected mock ledger call: query icrc1_balance_of")]
+    fn test_panics_on_unexpected_calls() {
+        let ledger = MockLedger::new();
+        ledger
+            .expect_query("icrc1_balance_of")
+            .with_arg_eq(account(1))
+            .returning(Nat::from(10u8));
+        let _ = block_on(balance_of(&ledger, account(2)));
+    }
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(
+            line_diff("a\nb\nc", "a\nx\nc\nd"),
+            "  a\n- b\n+ x\n  c\n+ d"
+        );
+        assert_eq!(line_diff("a", "a"), "  a");
+    }
+}
@@ -1,9 +1,8 @@
-use anyhow::Context;
 use async_trait::async_trait;
-use candid::utils::{decode_args, encode_args, ArgumentDecoder, ArgumentEncoder};
 use candid::Principal;
-use icrc1_test_env::LedgerEnv;
+use icrc1_test_env::{LedgerEnv, TimeControl};
 use pocket_ic::nonblocking::PocketIc;
+use pocket_ic::Time;
 use std::convert::TryInto;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -38,6 +37,14 @@ impl LedgerEnv for PICLedger {
         self.sender
     }
 
+    fn canister_id(&self) -> Option<Principal> {
+        Some(self.canister_id)
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        self.pic.root_key().await
+    }
+
     async fn time(&self) -> std::time::SystemTime {
         self.pic
             .get_time()
@@ -46,60 +53,62 @@ impl LedgerEnv for PICLedger {
             .expect("Failed to convert PocketIC time to SystemTime")
     }
 
-    async fn query<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
-    where
-        Input: ArgumentEncoder + std::fmt::Debug,
-        Output: for<'a> ArgumentDecoder<'a>,
-    {
-        let debug_inputs = format!("{:?}", input);
-        let in_bytes = encode_args(input)
-            .with_context(|| format!("Failed to encode arguments {}", debug_inputs))?;
-        match self
-            .pic
-            .query_call(self.canister_id, self.sender, method, in_bytes)
+    fn time_control(&self) -> Option<&dyn TimeControl> {
+        Some(self)
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.query_canister_raw(self.canister_id, method, arg).await
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.pic
+            .query_call(canister_id, self.sender, method, arg)
             .await
-        {
-            Ok(bytes) => decode_args(&bytes).with_context(|| {
-                format!(
-                    "Failed to decode method {} response into type {}, bytes: {}",
-                    method,
-                    std::any::type_name::<Output>(),
-                    hex::encode(bytes)
-                )
-            }),
-            Err(reject_response) => Err(anyhow::Error::msg(format!(
-                "Query call to ledger {:?} was rejected: {}",
-                self.canister_id, reject_response.reject_message
-            ))),
-        }
+            .map_err(|reject_response| {
+                anyhow::Error::msg(format!(
+                    "Query call to canister {:?} was rejected: {}",
+                    canister_id, reject_response.reject_message
+                ))
+            })
     }
 
-    async fn update<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
-    where
-        Input: ArgumentEncoder + std::fmt::Debug,
-        Output: for<'a> ArgumentDecoder<'a>,
-    {
-        let debug_inputs = format!("{:?}", input);
-        let in_bytes = encode_args(input)
-            .with_context(|| format!("Failed to encode arguments {}", debug_inputs))?;
-        match self
-            .pic
-            .update_call(self.canister_id, self.sender, method, in_bytes)
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.pic
+            .update_call(self.canister_id, self.sender, method, arg)
             .await
-        {
-            Ok(bytes) => decode_args(&bytes).with_context(|| {
-                format!(
-                    "Failed to decode method {} response into type {}, bytes: {}",
-                    method,
-                    std::any::type_name::<Output>(),
-                    hex::encode(&bytes)
-                )
-            }),
-            Err(reject_response) => Err(anyhow::Error::msg(format!(
-                "Update call to ledger {:?} was rejected: {}",
-                self.canister_id, reject_response.reject_message
-            ))),
-        }
+            .map_err(|reject_response| {
+                anyhow::Error::msg(format!(
+                    "Update call to ledger {:?} was rejected: {}",
+                    self.canister_id, reject_response.reject_message
+                ))
+            })
+    }
+}
+
+/// Queries observe the time of the last executed round, so both methods
+/// execute a round after changing the time.
+#[async_trait(?Send)]
+impl TimeControl for PICLedger {
+    async fn advance_time(&self, duration: std::time::Duration) {
+        self.pic.advance_time(duration).await;
+        self.pic.tick().await;
+    }
+
+    async fn set_time(&self, time: std::time::SystemTime) {
+        let nanos = time
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("the time is before the UNIX epoch")
+            .as_nanos() as u64;
+        self.pic
+            .set_time(Time::from_nanos_since_unix_epoch(nanos))
+            .await;
+        self.pic.tick().await;
     }
 }
 
@@ -0,0 +1,437 @@
+//! A [LedgerEnv] decorator writing every call to a JSON-lines trace.
+
+use crate::trace::{CallRecord, ForkRecord, LedgerRecord, RootKeyRecord, TimeRecord, TraceRecord};
+use crate::{decode_output, encode_input, LedgerEnv};
+use anyhow::Context as _;
+use async_trait::async_trait;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::Principal;
+use std::cell::RefCell;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+thread_local! {
+    static CORRELATION_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Returns the correlation id of the test being polled on this thread.
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.with(|id| id.borrow().clone())
+}
+
+/// Attaches the correlation id to all calls made while polling the future.
+pub fn with_correlation_id<F: Future>(
+    id: impl Into<String>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    WithCorrelationId {
+        id: id.into(),
+        future: Box::pin(future),
+    }
+}
+
+struct WithCorrelationId<F> {
+    id: String,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithCorrelationId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let previous = CORRELATION_ID.with(|id| id.replace(Some(self.id.clone())));
+        let result = self.future.as_mut().poll(cx);
+        CORRELATION_ID.with(|id| *id.borrow_mut() = previous);
+        result
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CallKind {
+    Query,
+    Update,
+}
+
+/// Wraps a ledger environment and records each query and update call,
+/// with its arguments, reply or rejection and latency, as a line of JSON.
+///
+/// The trace also records forks, `time` and `root_key` so that
+/// [crate::ReplayingLedger] can replay it deterministically.
+/// Time changes are not part of the trace, so the decorator hides the
+/// [crate::TimeControl] of the inner environment.
+///
+/// A failure to record a fork, `time` or `root_key` is returned from the
+/// next query or update call.
+#[derive(Clone)]
+pub struct RecordingLedger<L> {
+    inner: L,
+    trace: Arc<Mutex<Box<dyn Write + Send>>>,
+    /// The first failure to record a fork, `time` or `root_key`, returned
+    /// from the next call since these methods cannot fail.
+    write_error: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+impl<L: LedgerEnv> RecordingLedger<L> {
+    /// Starts a trace by writing the canister id and the principal of the
+    /// inner environment.
+    pub fn new(inner: L, trace: impl Write + Send + 'static) -> std::io::Result<Self> {
+        let ledger = Self {
+            inner,
+            trace: Arc::new(Mutex::new(Box::new(trace))),
+            write_error: Arc::new(Mutex::new(None)),
+        };
+        ledger.write(&TraceRecord::Ledger(LedgerRecord {
+            canister_id: ledger.inner.canister_id().map(|id| id.to_text()),
+            principal: ledger.inner.principal().to_text(),
+        }))?;
+        Ok(ledger)
+    }
+
+    /// Creates the trace file, truncating it if it exists.
+    pub fn create(inner: L, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(inner, BufWriter::new(file))
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    fn write(&self, record: &TraceRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut trace = self.trace.lock().expect("failed to grab a lock");
+        trace.write_all(&line)?;
+        trace.flush()
+    }
+
+    /// Writes a record of a method that cannot fail, keeping the error for
+    /// the next call.
+    fn write_or_defer(&self, record: &TraceRecord) {
+        if let Err(err) = self.write(record) {
+            self.write_error
+                .lock()
+                .expect("failed to grab a lock")
+                .get_or_insert(err);
+        }
+    }
+
+    /// Executes and records the call on the canister, or on the ledger if
+    /// the canister id is None.
+    async fn record_call(
+        &self,
+        kind: CallKind,
+        canister_id: Option<Principal>,
+        method: &str,
+        args_debug: Option<String>,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let write_error = self
+            .write_error
+            .lock()
+            .expect("failed to grab a lock")
+            .take();
+        if let Some(err) = write_error {
+            return Err(err).context("failed to write the call trace");
+        }
+        let correlation_id = correlation_id();
+        let args_hex = hex::encode(&arg);
+        let started = Instant::now();
+        let result = match (kind, canister_id) {
+            (CallKind::Query, None) => self.inner.query_raw(method, arg).await,
+            (CallKind::Query, Some(canister_id)) => {
+                self.inner
+                    .query_canister_raw(canister_id, method, arg)
+                    .await
+            }
+            (CallKind::Update, _) => self.inner.update_raw(method, arg).await,
+        };
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let (reply_hex, reject) = match &result {
+            Ok(reply) => (Some(hex::encode(reply)), None),
+            Err(err) => (None, Some(format!("{:#}", err))),
+        };
+        let call = CallRecord {
+            correlation_id,
+            canister_id: canister_id.map(|id| id.to_text()),
+            method: method.to_string(),
+            caller: self.inner.principal().to_text(),
+            args_debug,
+            args_hex,
+            reply_hex,
+            reject,
+            latency_ms,
+        };
+        let record = match kind {
+            CallKind::Query => TraceRecord::Query(call),
+            CallKind::Update => TraceRecord::Update(call),
+        };
+        self.write(&record)
+            .context("failed to write the call trace")?;
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl<L: LedgerEnv> LedgerEnv for RecordingLedger<L> {
+    fn fork(&self) -> Self {
+        let inner = self.inner.fork();
+        self.write_or_defer(&TraceRecord::Fork(ForkRecord {
+            correlation_id: correlation_id(),
+            caller: self.inner.principal().to_text(),
+            principal: inner.principal().to_text(),
+        }));
+        Self {
+            inner,
+            trace: self.trace.clone(),
+            write_error: self.write_error.clone(),
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.inner.principal()
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        self.inner.canister_id()
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        let root_key = self.inner.root_key().await;
+        self.write_or_defer(&TraceRecord::RootKey(RootKeyRecord {
+            correlation_id: correlation_id(),
+            caller: self.inner.principal().to_text(),
+            root_key_hex: root_key.as_ref().map(hex::encode),
+        }));
+        root_key
+    }
+
+    async fn time(&self) -> SystemTime {
+        let time = self.inner.time().await;
+        let time_nanos = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("the ledger time is before the UNIX epoch")
+            .as_nanos() as u64;
+        self.write_or_defer(&TraceRecord::Time(TimeRecord {
+            correlation_id: correlation_id(),
+            caller: self.inner.principal().to_text(),
+            time_nanos,
+        }));
+        time
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.record_call(CallKind::Query, None, method, None, arg)
+            .await
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.record_call(CallKind::Query, Some(canister_id), method, None, arg)
+            .await
+    }
+
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.record_call(CallKind::Update, None, method, None, arg)
+            .await
+    }
+
+    async fn query<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let args_debug = format!("{:?}", input);
+        let in_bytes = encode_input(input)?;
+        let bytes = self
+            .record_call(CallKind::Query, None, method, Some(args_debug), in_bytes)
+            .await?;
+        decode_output(method, &bytes)
+    }
+
+    async fn query_canister<Input, Output>(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        input: Input,
+    ) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let args_debug = format!("{:?}", input);
+        let in_bytes = encode_input(input)?;
+        let bytes = self
+            .record_call(
+                CallKind::Query,
+                Some(canister_id),
+                method,
+                Some(args_debug),
+                in_bytes,
+            )
+            .await?;
+        decode_output(method, &bytes)
+    }
+
+    async fn update<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let args_debug = format!("{:?}", input);
+        let in_bytes = encode_input(input)?;
+        let bytes = self
+            .record_call(CallKind::Update, None, method, Some(args_debug), in_bytes)
+            .await?;
+        decode_output(method, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::utils::encode_args;
+    use futures::executor::block_on;
+
+    /// A ledger answering every query with its own method name and
+    /// rejecting every update.
+    #[derive(Clone)]
+    struct EchoLedger;
+
+    #[async_trait(?Send)]
+    impl LedgerEnv for EchoLedger {
+        fn fork(&self) -> Self {
+            EchoLedger
+        }
+
+        fn principal(&self) -> Principal {
+            Principal::anonymous()
+        }
+
+        fn canister_id(&self) -> Option<Principal> {
+            Some(Principal::from_slice(&[1]))
+        }
+
+        async fn time(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        async fn query_raw(&self, method: &str, _arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Ok(encode_args((method.to_string(),)).unwrap())
+        }
+
+        async fn update_raw(&self, method: &str, _arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("update {} rejected", method)
+        }
+    }
+
+    /// A writer whose contents remain accessible after the ledger takes it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_records_calls_with_correlation_ids() {
+        let buffer = SharedBuffer::default();
+        let ledger = RecordingLedger::new(EchoLedger, buffer.clone()).unwrap();
+
+        let (reply,): (String,) = block_on(with_correlation_id(
+            "test-1",
+            ledger.query("icrc1_name", ()),
+        ))
+        .unwrap();
+        assert_eq!(reply, "icrc1_name");
+        let rejected: anyhow::Result<(String,)> =
+            block_on(ledger.update("icrc1_transfer", (42u8,)));
+        assert!(rejected.is_err());
+
+        let trace = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<serde_json::Value> = trace
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0]["kind"], "ledger");
+        assert_eq!(
+            records[0]["canister_id"],
+            EchoLedger.canister_id().unwrap().to_text()
+        );
+
+        assert_eq!(records[1]["correlation_id"], "test-1");
+        assert_eq!(records[1]["kind"], "query");
+        assert!(records[1]["canister_id"].is_null());
+        assert_eq!(records[1]["method"], "icrc1_name");
+        assert_eq!(records[1]["args_debug"], "()");
+        assert_eq!(
+            records[1]["reply_hex"],
+            hex::encode(encode_args(("icrc1_name".to_string(),)).unwrap())
+        );
+        assert!(records[1]["reject"].is_null());
+
+        assert!(records[2]["correlation_id"].is_null());
+        assert_eq!(records[2]["kind"], "update");
+        assert_eq!(records[2]["args_debug"], "(42,)");
+        assert_eq!(
+            records[2]["args_hex"],
+            hex::encode(encode_args((42u8,)).unwrap())
+        );
+        assert!(records[2]["reply_hex"].is_null());
+        assert_eq!(records[2]["reject"], "update icrc1_transfer rejected");
+    }
+
+    /// A writer failing while the flag is set.
+    #[derive(Clone, Default)]
+    struct FailingWriter(Arc<Mutex<bool>>);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if *self.0.lock().unwrap() {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "disk full"));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reports_failed_writes_from_the_next_call() {
+        let writer = FailingWriter::default();
+        let ledger = RecordingLedger::new(EchoLedger, writer.clone()).unwrap();
+
+        *writer.0.lock().unwrap() = true;
+        let fork = ledger.fork();
+        block_on(ledger.time());
+        *writer.0.lock().unwrap() = false;
+
+        let err = block_on(fork.query_raw("icrc1_name", vec![])).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "failed to write the call trace: disk full"
+        );
+        assert!(block_on(ledger.query_raw("icrc1_name", vec![])).is_ok());
+    }
+}
@@ -0,0 +1,553 @@
+//! A [LedgerEnv] serving responses from a trace written by
+//! [crate::RecordingLedger].
+
+use crate::trace::TraceRecord;
+use crate::{correlation_id, decode_output, encode_input, LedgerEnv};
+use anyhow::Context;
+use async_trait::async_trait;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::Principal;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// A call that does not match any unreplayed call of the trace.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error(
+    "the call of {caller} diverged from the trace\n  expected: {}\n  actual:   {actual}",
+    .expected.as_deref().unwrap_or("no further calls")
+)]
+pub struct TraceDivergence {
+    pub caller: Principal,
+    /// The next unreplayed call of the caller in the trace.
+    pub expected: Option<String>,
+    pub actual: String,
+}
+
+/// The unreplayed records of the trace.
+struct Trace {
+    records: Vec<Option<TraceRecord>>,
+    /// The first fork, time or root key request that did not match the
+    /// trace. All later calls fail with it.
+    divergence: Option<TraceDivergence>,
+}
+
+impl Trace {
+    /// Removes and returns the first unreplayed record matching the predicate.
+    fn take(&mut self, mut predicate: impl FnMut(&TraceRecord) -> bool) -> Option<TraceRecord> {
+        self.records
+            .iter_mut()
+            .find(|record| record.as_ref().map_or(false, &mut predicate))
+            .and_then(Option::take)
+    }
+
+    /// Describes the first unreplayed query or update of the caller.
+    fn next_call_of(&self, caller: &str) -> Option<String> {
+        self.records.iter().flatten().find_map(|record| {
+            let (kind, call) = match record {
+                TraceRecord::Query(call) => (CallKind::Query, call),
+                TraceRecord::Update(call) => (CallKind::Update, call),
+                _ => return None,
+            };
+            (call.caller == caller).then(|| {
+                let args = call.args_debug.as_deref().unwrap_or(&call.args_hex);
+                describe_call(kind, call.canister_id.as_deref(), &call.method, args)
+            })
+        })
+    }
+
+    /// Describes the first unreplayed request of the event by the caller.
+    fn next_event_of(&self, caller: &str, event: Event) -> Option<String> {
+        self.records
+            .iter()
+            .flatten()
+            .find_map(|record| match Event::of(record) {
+                Some((e, correlation_id, c)) if e == event && c == caller => {
+                    Some(describe_event(event, correlation_id.as_deref()))
+                }
+                _ => None,
+            })
+    }
+}
+
+/// The requests recorded besides queries and updates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    Fork,
+    Time,
+    RootKey,
+}
+
+impl Event {
+    /// Returns the event, the correlation id and the caller of the record.
+    fn of(record: &TraceRecord) -> Option<(Event, &Option<String>, &str)> {
+        match record {
+            TraceRecord::Fork(fork) => Some((Event::Fork, &fork.correlation_id, &fork.caller)),
+            TraceRecord::Time(time) => Some((Event::Time, &time.correlation_id, &time.caller)),
+            TraceRecord::RootKey(root_key) => {
+                Some((Event::RootKey, &root_key.correlation_id, &root_key.caller))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn describe_event(event: Event, correlation_id: Option<&str>) -> String {
+    let event = match event {
+        Event::Fork => "fork",
+        Event::Time => "time",
+        Event::RootKey => "root_key",
+    };
+    match correlation_id {
+        Some(id) => format!("{} in {}", event, id),
+        None => event.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CallKind {
+    Query,
+    Update,
+}
+
+fn describe_call(kind: CallKind, canister_id: Option<&str>, method: &str, args: &str) -> String {
+    let kind = match kind {
+        CallKind::Query => "query",
+        CallKind::Update => "update",
+    };
+    format!(
+        "{} {} on {} with args {}",
+        kind,
+        method,
+        canister_id.unwrap_or("the ledger"),
+        args
+    )
+}
+
+/// Replays a trace recorded by [crate::RecordingLedger].
+///
+/// Calls are matched by their kind, canister, method, caller and encoded
+/// arguments, in the order they appear in the trace. Forks, `time` and
+/// `root_key` take the next record of the same caller and correlation id.
+///
+/// A call that does not match the trace fails with a [TraceDivergence].
+/// Forks, `time` and `root_key` cannot fail, so a mismatch is kept as
+/// [ReplayingLedger::divergence] and fails all later calls.
+#[derive(Clone)]
+pub struct ReplayingLedger {
+    trace: Arc<Mutex<Trace>>,
+    principal: Principal,
+    canister_id: Option<Principal>,
+}
+
+impl ReplayingLedger {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("failed to open trace file {}", path.display()))?;
+        Self::from_reader(BufReader::new(file))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut records = vec![];
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.context("failed to read the trace")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: TraceRecord = serde_json::from_str(&line)
+                .with_context(|| format!("failed to parse line {} of the trace", n + 1))?;
+            records.push(record);
+        }
+        let ledger = match records.first() {
+            Some(TraceRecord::Ledger(ledger)) => ledger.clone(),
+            _ => anyhow::bail!("the trace does not start with a ledger record"),
+        };
+        for record in records.iter() {
+            match record {
+                TraceRecord::Fork(fork) => {
+                    parse_principal(&fork.principal)?;
+                }
+                TraceRecord::RootKey(root_key) => {
+                    if let Some(key) = &root_key.root_key_hex {
+                        hex::decode(key)
+                            .with_context(|| format!("invalid root key {} in the trace", key))?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            trace: Arc::new(Mutex::new(Trace {
+                records: records.into_iter().skip(1).map(Some).collect(),
+                divergence: None,
+            })),
+            principal: parse_principal(&ledger.principal)?,
+            canister_id: ledger
+                .canister_id
+                .as_deref()
+                .map(parse_principal)
+                .transpose()?,
+        })
+    }
+
+    /// Returns the first fork, time or root key request that did not match
+    /// the trace.
+    pub fn divergence(&self) -> Option<TraceDivergence> {
+        self.trace
+            .lock()
+            .expect("failed to grab a lock")
+            .divergence
+            .clone()
+    }
+
+    /// Takes the next unreplayed record of the event requested by the
+    /// caller within the current test. If there is none, keeps the
+    /// divergence, which fails all later calls, and returns None.
+    fn replay_event(&self, event: Event) -> Option<TraceRecord> {
+        let caller = self.principal.to_text();
+        let correlation_id = correlation_id();
+        let mut trace = self.trace.lock().expect("failed to grab a lock");
+        let record = trace
+            .take(|record| Event::of(record) == Some((event, &correlation_id, caller.as_str())));
+        if record.is_none() {
+            let divergence = TraceDivergence {
+                caller: self.principal,
+                expected: trace.next_event_of(&caller, event),
+                actual: describe_event(event, correlation_id.as_deref()),
+            };
+            trace.divergence.get_or_insert(divergence);
+        }
+        record
+    }
+
+    /// Replays the call on the canister, or on the ledger if the canister id
+    /// is None.
+    fn replay_call(
+        &self,
+        kind: CallKind,
+        canister_id: Option<Principal>,
+        method: &str,
+        args_debug: Option<String>,
+        arg: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let caller = self.principal.to_text();
+        let canister = canister_id.map(|id| id.to_text());
+        let args_hex = hex::encode(arg);
+        let mut trace = self.trace.lock().expect("failed to grab a lock");
+        if let Some(divergence) = &trace.divergence {
+            return Err(divergence.clone().into());
+        }
+        let call = trace.take(|record| {
+            let call = match (kind, record) {
+                (CallKind::Query, TraceRecord::Query(call))
+                | (CallKind::Update, TraceRecord::Update(call)) => call,
+                _ => return false,
+            };
+            call.caller == caller
+                && call.canister_id == canister
+                && call.method == method
+                && call.args_hex == args_hex
+        });
+        let call = match call {
+            Some(TraceRecord::Query(call)) | Some(TraceRecord::Update(call)) => call,
+            _ => {
+                let args = args_debug.as_deref().unwrap_or(&args_hex);
+                return Err(TraceDivergence {
+                    caller: self.principal,
+                    expected: trace.next_call_of(&caller),
+                    actual: describe_call(kind, canister.as_deref(), method, args),
+                }
+                .into());
+            }
+        };
+        match (call.reply_hex, call.reject) {
+            (Some(reply_hex), _) => hex::decode(&reply_hex)
+                .with_context(|| format!("invalid reply in the trace: {}", reply_hex)),
+            (None, reject) => Err(anyhow::Error::msg(
+                reject.unwrap_or_else(|| "the call was rejected".to_string()),
+            )),
+        }
+    }
+}
+
+fn parse_principal(text: &str) -> anyhow::Result<Principal> {
+    Principal::from_text(text).with_context(|| format!("invalid principal {} in the trace", text))
+}
+
+#[async_trait(?Send)]
+impl LedgerEnv for ReplayingLedger {
+    fn fork(&self) -> Self {
+        // A fork missing from the trace keeps the principal of its parent;
+        // its calls fail with the divergence.
+        let principal = match self.replay_event(Event::Fork) {
+            Some(TraceRecord::Fork(fork)) => {
+                parse_principal(&fork.principal).expect("the trace was validated on load")
+            }
+            _ => self.principal,
+        };
+        Self {
+            trace: self.trace.clone(),
+            principal,
+            canister_id: self.canister_id,
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.principal
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        self.canister_id
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        match self.replay_event(Event::RootKey) {
+            Some(TraceRecord::RootKey(root_key)) => root_key
+                .root_key_hex
+                .map(|key| hex::decode(&key).expect("the trace was validated on load")),
+            _ => None,
+        }
+    }
+
+    async fn time(&self) -> SystemTime {
+        match self.replay_event(Event::Time) {
+            Some(TraceRecord::Time(time)) => {
+                SystemTime::UNIX_EPOCH + Duration::from_nanos(time.time_nanos)
+            }
+            _ => SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.replay_call(CallKind::Query, None, method, None, &arg)
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.replay_call(CallKind::Query, Some(canister_id), method, None, &arg)
+    }
+
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.replay_call(CallKind::Update, None, method, None, &arg)
+    }
+
+    async fn query<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let args_debug = format!("{:?}", input);
+        let in_bytes = encode_input(input)?;
+        let bytes = self.replay_call(CallKind::Query, None, method, Some(args_debug), &in_bytes)?;
+        decode_output(method, &bytes)
+    }
+
+    async fn query_canister<Input, Output>(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        input: Input,
+    ) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let args_debug = format!("{:?}", input);
+        let in_bytes = encode_input(input)?;
+        let bytes = self.replay_call(
+            CallKind::Query,
+            Some(canister_id),
+            method,
+            Some(args_debug),
+            &in_bytes,
+        )?;
+        decode_output(method, &bytes)
+    }
+
+    async fn update<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
+    where
+        Input: ArgumentEncoder + std::fmt::Debug,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        let args_debug = format!("{:?}", input);
+        let in_bytes = encode_input(input)?;
+        let bytes =
+            self.replay_call(CallKind::Update, None, method, Some(args_debug), &in_bytes)?;
+        decode_output(method, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc1::balance_of;
+    use crate::{with_correlation_id, Account};
+    use candid::utils::encode_args;
+    use candid::Nat;
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    fn ledger_id() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn forked_principal() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn trace() -> String {
+        let caller = forked_principal().to_text();
+        let records = [
+            json!({
+                "kind": "ledger",
+                "canister_id": ledger_id().to_text(),
+                "principal": Principal::anonymous().to_text(),
+            }),
+            json!({
+                "kind": "fork",
+                "correlation_id": "test",
+                "caller": Principal::anonymous().to_text(),
+                "principal": caller,
+            }),
+            json!({
+                "kind": "time",
+                "correlation_id": "test",
+                "caller": caller,
+                "time_nanos": 1_000_000_000u64,
+            }),
+            json!({
+                "kind": "query",
+                "correlation_id": "test",
+                "canister_id": null,
+                "method": "icrc1_balance_of",
+                "caller": caller,
+                "args_debug": null,
+                "args_hex": hex::encode(encode_args((Account::from(forked_principal()),)).unwrap()),
+                "reply_hex": hex::encode(encode_args((Nat::from(42u8),)).unwrap()),
+                "reject": null,
+                "latency_ms": 1.0,
+            }),
+            json!({
+                "kind": "update",
+                "correlation_id": "test",
+                "canister_id": null,
+                "method": "icrc1_transfer",
+                "caller": caller,
+                "args_debug": "(7,)",
+                "args_hex": hex::encode(encode_args((7u8,)).unwrap()),
+                "reply_hex": null,
+                "reject": "out of cycles",
+                "latency_ms": 1.0,
+            }),
+        ];
+        records
+            .iter()
+            .map(|record| record.to_string() + "\n")
+            .collect()
+    }
+
+    /// Runs the future as the test the records of [trace] belong to.
+    fn block_on_test<F: std::future::Future>(future: F) -> F::Output {
+        block_on(with_correlation_id("test", future))
+    }
+
+    #[test]
+    fn test_replays_recorded_calls() {
+        let ledger = ReplayingLedger::from_reader(trace().as_bytes()).unwrap();
+        assert_eq!(ledger.principal(), Principal::anonymous());
+        assert_eq!(ledger.canister_id(), Some(ledger_id()));
+
+        block_on_test(async {
+            let forked = ledger.fork();
+            assert_eq!(forked.principal(), forked_principal());
+            assert_eq!(
+                forked.time().await,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1)
+            );
+            assert_eq!(
+                balance_of(&forked, forked_principal()).await.unwrap(),
+                Nat::from(42u8)
+            );
+            let rejected: anyhow::Result<(Nat,)> = forked.update("icrc1_transfer", (7u8,)).await;
+            assert_eq!(rejected.unwrap_err().to_string(), "out of cycles");
+        });
+        assert_eq!(ledger.divergence(), None);
+    }
+
+    #[test]
+    fn test_reports_divergence() {
+        let ledger = ReplayingLedger::from_reader(trace().as_bytes()).unwrap();
+        block_on_test(async {
+            let forked = ledger.fork();
+
+            let err = balance_of(&forked, Principal::anonymous())
+                .await
+                .unwrap_err();
+            let divergence = err.downcast_ref::<TraceDivergence>().unwrap();
+            assert_eq!(divergence.caller, forked_principal());
+            assert!(divergence
+                .expected
+                .as_deref()
+                .unwrap()
+                .starts_with("query icrc1_balance_of"));
+            assert!(divergence.actual.starts_with("query icrc1_balance_of"));
+            assert_ne!(
+                divergence.expected.as_deref(),
+                Some(divergence.actual.as_str())
+            );
+
+            balance_of(&forked, forked_principal()).await.unwrap();
+            let err = balance_of(&forked, forked_principal()).await.unwrap_err();
+            let divergence = err.downcast_ref::<TraceDivergence>().unwrap();
+            assert_eq!(
+                divergence.expected.as_deref(),
+                Some("update icrc1_transfer on the ledger with args (7,)")
+            );
+        });
+    }
+
+    #[test]
+    fn test_matches_forks_and_time_by_correlation_id() {
+        let ledger = ReplayingLedger::from_reader(trace().as_bytes()).unwrap();
+        let forked = block_on(with_correlation_id("other", async { ledger.fork() }));
+        let divergence = TraceDivergence {
+            caller: Principal::anonymous(),
+            expected: Some("fork in test".to_string()),
+            actual: "fork in other".to_string(),
+        };
+        assert_eq!(ledger.divergence(), Some(divergence.clone()));
+
+        // The fork of the right test still replays, but calls fail.
+        let forked_in_test = block_on_test(async { ledger.fork() });
+        assert_eq!(forked_in_test.principal(), forked_principal());
+        assert_eq!(forked.principal(), Principal::anonymous());
+        let err = block_on_test(balance_of(&forked_in_test, forked_principal())).unwrap_err();
+        assert_eq!(err.downcast_ref::<TraceDivergence>(), Some(&divergence));
+    }
+
+    #[test]
+    fn test_reports_exhausted_time_requests() {
+        let ledger = ReplayingLedger::from_reader(trace().as_bytes()).unwrap();
+        block_on_test(async {
+            let forked = ledger.fork();
+            forked.time().await;
+            assert_eq!(forked.time().await, SystemTime::UNIX_EPOCH);
+        });
+        assert_eq!(
+            ledger.divergence(),
+            Some(TraceDivergence {
+                caller: forked_principal(),
+                expected: None,
+                actual: "time in test".to_string(),
+            })
+        );
+    }
+}
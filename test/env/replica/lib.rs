@@ -1,13 +1,15 @@
 use anyhow::Context;
 use async_trait::async_trait;
-use candid::utils::{decode_args, encode_args, ArgumentDecoder, ArgumentEncoder};
 use candid::Principal;
+use ic_agent::hash_tree::Label;
 use ic_agent::identity::BasicIdentity;
-use ic_agent::Agent;
-use icrc1_test_env::LedgerEnv;
+use ic_agent::{lookup_value, Agent};
+use icrc1_test_env::{correlation_id, decode_leb128_u64, LedgerEnv};
+use ring::hkdf;
 use ring::rand::SystemRandom;
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 pub fn fresh_identity(rand: &SystemRandom) -> BasicIdentity {
     use ring::signature::Ed25519KeyPair as KeyPair;
@@ -19,25 +21,111 @@ pub fn fresh_identity(rand: &SystemRandom) -> BasicIdentity {
     BasicIdentity::from_key_pair(key_pair)
 }
 
+/// The HKDF salt separating the keys derived for forks from other uses
+/// of the seed.
+const FORK_SALT: &[u8] = b"icrc1-test-env-replica/fork";
+
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Derives an identity from the seed with HKDF-SHA256, using `info` to
+/// tell apart the identities derived from the same seed.
+pub fn seeded_identity(seed: u64, info: &[u8]) -> BasicIdentity {
+    use ring::signature::Ed25519KeyPair as KeyPair;
+
+    let mut key_seed = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, FORK_SALT)
+        .extract(&seed.to_be_bytes())
+        .expand(&[info], KeyLen(key_seed.len()))
+        .and_then(|okm| okm.fill(&mut key_seed))
+        .expect("failed to derive an ed25519 key seed");
+
+    let key_pair = KeyPair::from_seed_unchecked(&key_seed)
+        .expect("failed to construct a key pair from a seed");
+    BasicIdentity::from_key_pair(key_pair)
+}
+
+/// Where the identities of forks come from.
+enum Identities {
+    Random(SystemRandom),
+    /// Identities derived from the seed, the correlation id of the test
+    /// forking and the number of forks that test made before. Keying the
+    /// counter by test keeps the identities stable however the concurrent
+    /// tests interleave.
+    Seeded {
+        seed: u64,
+        forks: BTreeMap<Option<String>, u64>,
+    },
+}
+
+impl Identities {
+    fn next(&mut self) -> BasicIdentity {
+        match self {
+            Identities::Random(rand) => fresh_identity(rand),
+            Identities::Seeded { seed, forks } => {
+                let test = correlation_id();
+                let counter = forks.entry(test.clone()).or_insert(0);
+                let mut info = counter.to_be_bytes().to_vec();
+                info.extend_from_slice(test.unwrap_or_default().as_bytes());
+                *counter += 1;
+                seeded_identity(*seed, &info)
+            }
+        }
+    }
+}
+
+fn skew_nanos(ledger_time: SystemTime, local_time: SystemTime) -> i128 {
+    match ledger_time.duration_since(local_time) {
+        Ok(ahead) => ahead.as_nanos() as i128,
+        Err(behind) => -(behind.duration().as_nanos() as i128),
+    }
+}
+
+/// How to fall back to the local clock when the certified time cannot be
+/// read.
+#[derive(Clone, Copy, Debug)]
+struct ClockFallback {
+    /// The largest tolerated difference between the last certified time
+    /// and the local clock.
+    max_skew: Duration,
+}
+
 #[derive(Clone)]
 pub struct ReplicaLedger {
-    rand: Arc<Mutex<SystemRandom>>,
+    identities: Arc<Mutex<Identities>>,
     agent: Arc<Agent>,
     canister_id: Principal,
+    clock_fallback: Option<ClockFallback>,
+    /// The difference between the last certified time and the local clock
+    /// in nanoseconds, positive if the replica is ahead.
+    last_skew_nanos: Arc<Mutex<Option<i128>>>,
+    /// The failure to get the ledger time without a usable fallback,
+    /// returned from the next call since `time` cannot fail.
+    time_error: Arc<Mutex<Option<anyhow::Error>>>,
 }
 
 #[async_trait(?Send)]
 impl LedgerEnv for ReplicaLedger {
     fn fork(&self) -> Self {
         let mut agent = Arc::clone(&self.agent);
-        Arc::make_mut(&mut agent).set_identity({
-            let r = self.rand.lock().expect("failed to grab a lock");
-            fresh_identity(&r)
-        });
+        Arc::make_mut(&mut agent).set_identity(
+            self.identities
+                .lock()
+                .expect("failed to grab a lock")
+                .next(),
+        );
         Self {
-            rand: Arc::clone(&self.rand),
+            identities: Arc::clone(&self.identities),
             agent,
             canister_id: self.canister_id,
+            clock_fallback: self.clock_fallback,
+            last_skew_nanos: Arc::clone(&self.last_skew_nanos),
+            time_error: Arc::clone(&self.time_error),
         }
     }
 
@@ -47,82 +135,174 @@ impl LedgerEnv for ReplicaLedger {
             .expect("failed to get agent principal")
     }
 
+    fn canister_id(&self) -> Option<Principal> {
+        Some(self.canister_id)
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        Some(self.agent.read_root_key())
+    }
+
     async fn time(&self) -> SystemTime {
-        // The replica relies on the system time by default.
-        // Unfortunately, this assumption might break during the time
-        // shifts, but it's probably good enough for tests.
-        SystemTime::now()
-    }
-
-    async fn query<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
-    where
-        Input: ArgumentEncoder + std::fmt::Debug,
-        Output: for<'a> ArgumentDecoder<'a>,
-    {
-        let debug_inputs = format!("{:?}", input);
-        let in_bytes = encode_args(input)
-            .with_context(|| format!("Failed to encode arguments {}", debug_inputs))?;
-        let bytes = self
-            .agent
-            .query(&self.canister_id, method)
-            .with_arg(in_bytes)
+        let local_time = SystemTime::now();
+        match self.certified_time().await {
+            Ok(time) => {
+                *self.last_skew_nanos.lock().expect("failed to grab a lock") =
+                    Some(skew_nanos(time, local_time));
+                time
+            }
+            Err(err) => self.fallback_time(local_time, err),
+        }
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.query_canister_raw(self.canister_id, method, arg).await
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.take_time_error()?;
+        let arg_hex = hex::encode(&arg);
+        self.agent
+            .query(&canister_id, method)
+            .with_arg(arg)
             .call()
             .await
             .with_context(|| {
                 format!(
                     "failed to call method {} on {} with args {}",
-                    method, self.canister_id, debug_inputs
+                    method, canister_id, arg_hex
                 )
-            })?;
+            })
+    }
 
-        decode_args(&bytes).with_context(|| {
-            format!(
-                "Failed to decode method {} response into type {}, bytes: {}",
-                method,
-                std::any::type_name::<Output>(),
-                hex::encode(bytes)
-            )
-        })
-    }
-
-    async fn update<Input, Output>(&self, method: &str, input: Input) -> anyhow::Result<Output>
-    where
-        Input: ArgumentEncoder + std::fmt::Debug,
-        Output: for<'a> ArgumentDecoder<'a>,
-    {
-        let debug_inputs = format!("{:?}", input);
-        let in_bytes = encode_args(input)
-            .with_context(|| format!("Failed to encode arguments {}", debug_inputs))?;
-        let bytes = self
-            .agent
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.take_time_error()?;
+        let arg_hex = hex::encode(&arg);
+        self.agent
             .update(&self.canister_id, method)
-            .with_arg(in_bytes)
+            .with_arg(arg)
             .call_and_wait()
             .await
             .with_context(|| {
                 format!(
                     "failed to call method {} on {} with args {}",
-                    method, self.canister_id, debug_inputs
+                    method, self.canister_id, arg_hex
                 )
-            })?;
+            })
+    }
+}
+
+impl ReplicaLedger {
+    /// Creates an environment whose forks get random identities.
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        Self::build(agent, canister_id, Identities::Random(SystemRandom::new()))
+    }
+
+    /// Creates an environment whose forks get identities derived from the
+    /// seed, so that a run with the same seed uses the same accounts.
+    pub fn with_seed(agent: Agent, canister_id: Principal, seed: u64) -> Self {
+        Self::build(
+            agent,
+            canister_id,
+            Identities::Seeded {
+                seed,
+                forks: BTreeMap::new(),
+            },
+        )
+    }
 
-        decode_args(&bytes).with_context(|| {
+    /// Returns the seed of the fork identities, if any.
+    pub fn seed(&self) -> Option<u64> {
+        match &*self.identities.lock().expect("failed to grab a lock") {
+            Identities::Random(_) => None,
+            Identities::Seeded { seed, .. } => Some(*seed),
+        }
+    }
+
+    /// Falls back to the local clock when the certified time cannot be
+    /// read, as long as the local clock was within `max_skew` of the last
+    /// certified time. Without a fallback, or past `max_skew`, the next call
+    /// fails with the error.
+    pub fn with_local_clock_fallback(mut self, max_skew: Duration) -> Self {
+        self.clock_fallback = Some(ClockFallback { max_skew });
+        self
+    }
+
+    /// Reads the time of the latest state certified by the replica.
+    async fn certified_time(&self) -> anyhow::Result<SystemTime> {
+        let path = vec![Label::from("time")];
+        let certificate = self
+            .agent
+            .read_state_raw(vec![path.clone()], self.canister_id)
+            .await
+            .context("failed to read the certified time")?;
+        let bytes = lookup_value(&certificate, path)
+            .context("the certificate does not contain the time")?;
+        let nanos = decode_leb128_u64(bytes).with_context(|| {
             format!(
-                "Failed to decode method {} response into type {}, bytes: {}",
-                method,
-                std::any::type_name::<Output>(),
+                "the certified time {} is not a LEB128-encoded 64-bit number",
                 hex::encode(bytes)
             )
-        })
+        })?;
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
     }
-}
 
-impl ReplicaLedger {
-    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+    /// Returns the local time in place of the unreadable certified time.
+    /// Without a fallback, or if the local clock was more than `max_skew`
+    /// off the last certified time, the error is kept for the next call.
+    fn fallback_time(&self, local_time: SystemTime, err: anyhow::Error) -> SystemTime {
+        let err = err.context("failed to get the ledger time");
+        let err =
+            match self.clock_fallback {
+                None => Some(err),
+                Some(fallback) => {
+                    match *self.last_skew_nanos.lock().expect("failed to grab a lock") {
+                    Some(skew) if skew.unsigned_abs() > fallback.max_skew.as_nanos() => {
+                        Some(err.context(format!(
+                            "the last certified time was {}ms {} the local clock, more than the tolerated {}ms",
+                            skew.unsigned_abs() / 1_000_000,
+                            if skew < 0 { "behind" } else { "ahead of" },
+                            fallback.max_skew.as_millis()
+                        )))
+                    }
+                    _ => None,
+                }
+                }
+            };
+        if let Some(err) = err {
+            self.time_error
+                .lock()
+                .expect("failed to grab a lock")
+                .get_or_insert(err);
+        }
+        local_time
+    }
+
+    fn take_time_error(&self) -> anyhow::Result<()> {
+        match self
+            .time_error
+            .lock()
+            .expect("failed to grab a lock")
+            .take()
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn build(agent: Agent, canister_id: Principal, identities: Identities) -> Self {
         Self {
-            rand: Arc::new(Mutex::new(SystemRandom::new())),
+            identities: Arc::new(Mutex::new(identities)),
             agent: Arc::new(agent),
             canister_id,
+            clock_fallback: None,
+            last_skew_nanos: Arc::new(Mutex::new(None)),
+            time_error: Arc::new(Mutex::new(None)),
         }
     }
 }
@@ -0,0 +1,465 @@
+//! A [LedgerEnv] decorator retrying failed calls with exponential backoff.
+
+use crate::delay::sleep;
+use crate::{
+    ApproveArgs, ApproveError, LedgerEnv, TimeControl, Transfer, TransferError, TransferFromArgs,
+    TransferFromError,
+};
+use async_trait::async_trait;
+use candid::utils::{decode_args, encode_args};
+use candid::{Nat, Principal};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+/// Decides whether a failed call may succeed if made again.
+pub type ErrorClassifier = fn(&anyhow::Error) -> bool;
+
+/// How many times and how often to retry a failed call.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt.
+    pub max_retries: u32,
+    /// The delay before the first retry.
+    pub initial_backoff: Duration,
+    /// The cap on the delay between retries.
+    pub max_backoff: Duration,
+    /// The factor applied to the delay after each retry.
+    pub multiplier: u32,
+    /// Separates retryable errors from fatal ones.
+    pub is_retryable: ErrorClassifier,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2,
+            is_retryable: is_retryable_error,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn is_retryable(mut self, classifier: ErrorClassifier) -> Self {
+        self.is_retryable = classifier;
+        self
+    }
+
+    /// The delay before the retry following `backoff`.
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff
+            .checked_mul(self.multiplier)
+            .map_or(self.max_backoff, |next| next.min(self.max_backoff))
+    }
+}
+
+/// The default error classifier. Transport failures, timeouts and rejects
+/// with the `SYS_TRANSIENT` code are retryable, everything else is fatal.
+pub fn is_retryable_error(err: &anyhow::Error) -> bool {
+    const RETRYABLE: &[&str] = &[
+        "timeout",
+        "timed out",
+        "transport",
+        "connection refused",
+        "connection reset",
+        "connection closed",
+        "service unavailable",
+        "too many requests",
+        "systransient",
+        "sys_transient",
+    ];
+    let message = format!("{:#}", err).to_lowercase();
+    RETRYABLE.iter().any(|pattern| message.contains(pattern))
+}
+
+/// Returns true if the reply reports that the ledger did not process the
+/// call because it is temporarily unavailable.
+fn is_temporarily_unavailable(method: &str, reply: &[u8]) -> bool {
+    macro_rules! check {
+        ($error:ident) => {
+            matches!(
+                decode_args::<(Result<Nat, $error>,)>(reply),
+                Ok((Err($error::TemporarilyUnavailable),))
+            )
+        };
+    }
+    match method {
+        "icrc1_transfer" => check!(TransferError),
+        "icrc2_approve" => check!(ApproveError),
+        "icrc2_transfer_from" => check!(TransferFromError),
+        _ => false,
+    }
+}
+
+/// Turns a reply reporting the update as a duplicate of an earlier
+/// transaction into the reply of a successful update returning that
+/// transaction. Returns None for any other reply.
+fn duplicate_as_success(method: &str, reply: &[u8]) -> Option<Vec<u8>> {
+    macro_rules! convert {
+        ($error:ident) => {
+            match decode_args::<(Result<Nat, $error>,)>(reply) {
+                Ok((Err($error::Duplicate { duplicate_of }),)) => {
+                    encode_args((Ok::<Nat, $error>(duplicate_of),)).ok()
+                }
+                _ => None,
+            }
+        };
+    }
+    match method {
+        "icrc1_transfer" => convert!(TransferError),
+        "icrc2_approve" => convert!(ApproveError),
+        "icrc2_transfer_from" => convert!(TransferFromError),
+        _ => None,
+    }
+}
+
+/// Returns true if the ledger deduplicates the update, so that a retry of
+/// an update that was applied but whose reply was lost cannot apply it
+/// twice. Only the ICRC-1 and ICRC-2 updates setting `created_at_time`
+/// qualify.
+fn is_deduplicated(method: &str, arg: &[u8]) -> bool {
+    match method {
+        "icrc1_transfer" => matches!(
+            decode_args::<(Transfer,)>(arg),
+            Ok((Transfer {
+                created_at_time: Some(_),
+                ..
+            },))
+        ),
+        "icrc2_approve" => matches!(
+            decode_args::<(ApproveArgs,)>(arg),
+            Ok((ApproveArgs {
+                created_at_time: Some(_),
+                ..
+            },))
+        ),
+        "icrc2_transfer_from" => matches!(
+            decode_args::<(TransferFromArgs,)>(arg),
+            Ok((TransferFromArgs {
+                created_at_time: Some(_),
+                ..
+            },))
+        ),
+        _ => false,
+    }
+}
+
+/// Wraps a ledger environment and retries the calls failing with a
+/// retryable error or a `TemporarilyUnavailable` reply, waiting between
+/// attempts with exponential backoff.
+///
+/// Queries are always retried. Updates are only retried if the ledger
+/// deduplicates them, i.e. transfers and approvals with `created_at_time`
+/// set, since retrying any other update could apply it twice. A retried
+/// update rejected as a `Duplicate` was applied by an earlier attempt whose
+/// reply was lost, so it succeeds with the index of the duplicate.
+#[derive(Clone)]
+pub struct RetryingLedger<L> {
+    inner: L,
+    policy: RetryPolicy,
+}
+
+impl<L: LedgerEnv> RetryingLedger<L> {
+    pub fn new(inner: L, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    async fn with_retries<F, Fut>(
+        &self,
+        method: &str,
+        may_retry: bool,
+        call: F,
+    ) -> anyhow::Result<Vec<u8>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+    {
+        let mut retries = 0;
+        let mut backoff = self.policy.initial_backoff;
+        loop {
+            let result = match call().await {
+                Ok(reply) if retries > 0 => {
+                    Ok(duplicate_as_success(method, &reply).unwrap_or(reply))
+                }
+                result => result,
+            };
+            let retryable = match &result {
+                Ok(reply) => is_temporarily_unavailable(method, reply),
+                Err(err) => (self.policy.is_retryable)(err),
+            };
+            if !retryable {
+                return result.map_err(|err| {
+                    if retries > 0 {
+                        err.context(format!(
+                            "{} failed with a fatal error after {} retries",
+                            method, retries
+                        ))
+                    } else {
+                        err
+                    }
+                });
+            }
+            if !may_retry {
+                return result.map_err(|err| {
+                    err.context(format!(
+                        "{} was not retried: the ledger does not deduplicate it without created_at_time",
+                        method
+                    ))
+                });
+            }
+            if retries >= self.policy.max_retries {
+                return result.map_err(|err| {
+                    err.context(format!("{} gave up after {} retries", method, retries))
+                });
+            }
+            sleep(backoff).await;
+            retries += 1;
+            backoff = self.policy.next_backoff(backoff);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<L: LedgerEnv> LedgerEnv for RetryingLedger<L> {
+    fn fork(&self) -> Self {
+        Self {
+            inner: self.inner.fork(),
+            policy: self.policy.clone(),
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.inner.principal()
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        self.inner.canister_id()
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        self.inner.root_key().await
+    }
+
+    async fn time(&self) -> SystemTime {
+        self.inner.time().await
+    }
+
+    fn time_control(&self) -> Option<&dyn TimeControl> {
+        self.inner.time_control()
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.with_retries(method, true, || self.inner.query_raw(method, arg.clone()))
+            .await
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.with_retries(method, true, || {
+            self.inner
+                .query_canister_raw(canister_id, method, arg.clone())
+        })
+        .await
+    }
+
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let may_retry = is_deduplicated(method, &arg);
+        self.with_retries(method, may_retry, || {
+            self.inner.update_raw(method, arg.clone())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc1::{balance_of, transfer};
+    use crate::{Account, Fault, FaultyLedger, MockLedger};
+    use futures::executor::block_on;
+
+    fn account(n: u8) -> Account {
+        Account {
+            owner: Principal::from_slice(&[n]),
+            subaccount: None,
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::default()
+            .initial_backoff(Duration::from_millis(1))
+            .max_backoff(Duration::from_millis(4))
+    }
+
+    fn transient_reject() -> Option<Fault> {
+        Some(Fault::Reject(
+            "reject code SysTransient, reject message: queue full".to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_retries_transient_failures() {
+        let mock = MockLedger::new();
+        mock.expect_update("icrc1_transfer")
+            .returning(Ok::<Nat, TransferError>(Nat::from(7u8)));
+        let ledger = RetryingLedger::new(
+            FaultyLedger::new(mock.clone(), 0).with_schedule(
+                "icrc1_transfer",
+                vec![transient_reject(), Some(Fault::TemporarilyUnavailable)],
+            ),
+            policy(),
+        );
+
+        let result = block_on(transfer(
+            &ledger,
+            Transfer::amount_to(5u8, account(2)).created_at_time(1),
+        ))
+        .unwrap();
+        assert_eq!(result, Ok(Nat::from(7u8)));
+        assert_eq!(mock.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_retried_duplicates_succeed() {
+        let mock = MockLedger::new();
+        mock.expect_update("icrc1_transfer")
+            .returning(Ok::<Nat, TransferError>(Nat::from(7u8)));
+        mock.expect_update("icrc1_transfer")
+            .returning(Err::<Nat, TransferError>(TransferError::Duplicate {
+                duplicate_of: Nat::from(7u8),
+            }));
+        let ledger = RetryingLedger::new(
+            FaultyLedger::new(mock.clone(), 0)
+                .with_schedule("icrc1_transfer", vec![Some(Fault::ReplyLost)]),
+            policy(),
+        );
+
+        let result = block_on(transfer(
+            &ledger,
+            Transfer::amount_to(5u8, account(2)).created_at_time(1),
+        ))
+        .unwrap();
+        assert_eq!(result, Ok(Nat::from(7u8)));
+        assert_eq!(mock.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_first_attempt_duplicates_fail() {
+        let mock = MockLedger::new();
+        mock.expect_update("icrc1_transfer")
+            .returning(Err::<Nat, TransferError>(TransferError::Duplicate {
+                duplicate_of: Nat::from(7u8),
+            }));
+        let ledger = RetryingLedger::new(mock.clone(), policy());
+
+        let result = block_on(transfer(
+            &ledger,
+            Transfer::amount_to(5u8, account(2)).created_at_time(1),
+        ))
+        .unwrap();
+        assert_eq!(
+            result,
+            Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(7u8)
+            })
+        );
+    }
+
+    #[test]
+    fn test_does_not_retry_updates_without_created_at_time() {
+        let mock = MockLedger::new();
+        let ledger = RetryingLedger::new(
+            FaultyLedger::new(mock.clone(), 0).with_schedule(
+                "icrc1_transfer",
+                vec![transient_reject(), Some(Fault::TemporarilyUnavailable)],
+            ),
+            policy(),
+        );
+
+        let err = block_on(transfer(&ledger, Transfer::amount_to(5u8, account(2)))).unwrap_err();
+        assert!(format!("{:#}", err).contains("created_at_time"));
+        assert_eq!(mock.verify(), Ok(()));
+
+        let result = block_on(transfer(&ledger, Transfer::amount_to(5u8, account(2))));
+        assert_eq!(result.unwrap(), Err(TransferError::TemporarilyUnavailable));
+    }
+
+    #[test]
+    fn test_records_retries_in_the_error_context() {
+        let mock = MockLedger::new();
+        let ledger = RetryingLedger::new(
+            FaultyLedger::new(mock.clone(), 0)
+                .with_schedule("icrc1_balance_of", vec![transient_reject(); 3]),
+            policy().max_retries(2),
+        );
+        let err = block_on(balance_of(&ledger, account(1))).unwrap_err();
+        assert!(format!("{:#}", err).contains("icrc1_balance_of gave up after 2 retries"));
+
+        mock.expect_query("icrc1_balance_of")
+            .returning(Nat::from(10u8));
+        assert_eq!(
+            block_on(balance_of(&ledger, account(1))).unwrap(),
+            Nat::from(10u8)
+        );
+    }
+
+    #[test]
+    fn test_does_not_retry_fatal_errors() {
+        let mock = MockLedger::new();
+        let ledger = RetryingLedger::new(
+            FaultyLedger::new(mock.clone(), 0).with_schedule(
+                "icrc1_balance_of",
+                vec![
+                    Some(Fault::Reject("reject code CanisterError".to_string())),
+                    None,
+                ],
+            ),
+            policy(),
+        );
+        let err = block_on(balance_of(&ledger, account(1))).unwrap_err();
+        assert!(!format!("{:#}", err).contains("retries"));
+        assert_eq!(mock.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = policy();
+        let backoffs: Vec<_> = std::iter::successors(Some(policy.initial_backoff), |b| {
+            Some(policy.next_backoff(*b))
+        })
+        .take(5)
+        .map(|b| b.as_millis())
+        .collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 4, 4]);
+    }
+}
@@ -0,0 +1,326 @@
+//! A [LedgerEnv] decorator returning the tokens left in forked accounts to
+//! the original identity.
+
+use crate::icrc1::{balance_of, transfer, transfer_fee};
+use crate::{Account, ApproveArgs, LedgerEnv, Subaccount, TimeControl, Transfer, TransferFromArgs};
+use async_trait::async_trait;
+use candid::utils::decode_args;
+use candid::{Nat, Principal};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The forks and the subaccounts seen in their calls.
+struct Registry<L> {
+    forks: Vec<L>,
+    subaccounts: BTreeMap<Principal, BTreeSet<Subaccount>>,
+}
+
+impl<L> Registry<L> {
+    fn add_account(&mut self, owner: Principal, subaccount: Option<Subaccount>) {
+        if let Some(subaccount) = subaccount.filter(|s| s != &[0; 32]) {
+            self.subaccounts
+                .entry(owner)
+                .or_default()
+                .insert(subaccount);
+        }
+    }
+}
+
+/// An account that could not be drained.
+#[derive(Clone, Debug)]
+pub struct SweepFailure {
+    pub account: Account,
+    pub reason: String,
+}
+
+/// The outcome of [SweepingLedger::sweep].
+#[derive(Clone, Debug)]
+pub struct SweepReport {
+    /// The account receiving the swept tokens.
+    pub recipient: Account,
+    /// The tokens returned to the recipient.
+    pub swept: Nat,
+    /// The fees paid for the transfers returning the tokens.
+    pub fees: Nat,
+    /// The number of accounts drained.
+    pub drained: usize,
+    /// The number of accounts left with a balance not covering the fee.
+    pub dust: usize,
+    pub failures: Vec<SweepFailure>,
+}
+
+impl fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "swept {} tokens from {} accounts back to {}, paying {} in fees",
+            self.swept, self.drained, self.recipient, self.fees
+        )?;
+        if self.dust > 0 {
+            write!(
+                f,
+                "; left {} accounts holding no more than the fee",
+                self.dust
+            )?;
+        }
+        for failure in self.failures.iter() {
+            write!(
+                f,
+                "\ncould not drain {}: {}",
+                failure.account, failure.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a ledger environment and keeps track of its forks, so that the
+/// tokens left in their accounts after the tests can be returned to the
+/// original identity with [SweepingLedger::sweep].
+///
+/// Besides the default account of every fork, the subaccounts of the forks
+/// appearing in transfers and approvals are swept.
+#[derive(Clone)]
+pub struct SweepingLedger<L> {
+    inner: L,
+    recipient: Principal,
+    registry: Arc<Mutex<Registry<L>>>,
+}
+
+impl<L: LedgerEnv + Clone> SweepingLedger<L> {
+    pub fn new(inner: L) -> Self {
+        Self {
+            recipient: inner.principal(),
+            inner,
+            registry: Arc::new(Mutex::new(Registry {
+                forks: vec![],
+                subaccounts: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    fn track_update(&self, method: &str, arg: &[u8]) {
+        let mut registry = self.registry.lock().expect("failed to grab a lock");
+        let caller = self.inner.principal();
+        match method {
+            "icrc1_transfer" => {
+                if let Ok((transfer,)) = decode_args::<(Transfer,)>(arg) {
+                    registry.add_account(caller, transfer.from_subaccount);
+                    registry.add_account(transfer.to.owner, transfer.to.subaccount);
+                }
+            }
+            "icrc2_approve" => {
+                if let Ok((approve,)) = decode_args::<(ApproveArgs,)>(arg) {
+                    registry.add_account(caller, approve.from_subaccount);
+                }
+            }
+            "icrc2_transfer_from" => {
+                if let Ok((transfer,)) = decode_args::<(TransferFromArgs,)>(arg) {
+                    registry.add_account(transfer.from.owner, transfer.from.subaccount);
+                    registry.add_account(transfer.to.owner, transfer.to.subaccount);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Transfers the balance of every account of every fork, minus the
+    /// fee, to the default account of the original identity. Accounts
+    /// whose balance does not exceed the fee are left alone and counted as
+    /// dust. Accounts that cannot be drained are listed in the report
+    /// instead of failing the sweep.
+    pub async fn sweep(&self) -> anyhow::Result<SweepReport> {
+        let fee = transfer_fee(&self.inner).await?;
+        let (forks, subaccounts) = {
+            let registry = self.registry.lock().expect("failed to grab a lock");
+            (registry.forks.clone(), registry.subaccounts.clone())
+        };
+        let mut report = SweepReport {
+            recipient: Account::from(self.recipient),
+            swept: Nat::from(0u8),
+            fees: Nat::from(0u8),
+            drained: 0,
+            dust: 0,
+            failures: vec![],
+        };
+        for fork in forks.iter() {
+            let owner = fork.principal();
+            let accounts = std::iter::once(None).chain(
+                subaccounts
+                    .get(&owner)
+                    .into_iter()
+                    .flatten()
+                    .map(|subaccount| Some(*subaccount)),
+            );
+            for subaccount in accounts {
+                let account = Account { owner, subaccount };
+                match drain(fork, &account, &report.recipient, &fee).await {
+                    Ok(Drain::Empty) => {}
+                    Ok(Drain::Dust) => report.dust += 1,
+                    Ok(Drain::Drained(amount)) => {
+                        report.swept += amount;
+                        report.fees += fee.clone();
+                        report.drained += 1;
+                    }
+                    Err(err) => report.failures.push(SweepFailure {
+                        account,
+                        reason: format!("{:#}", err),
+                    }),
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// What draining an account did.
+enum Drain {
+    /// The account was empty.
+    Empty,
+    /// The balance did not exceed the fee, so it was left in the account.
+    Dust,
+    /// The amount was transferred to the recipient.
+    Drained(Nat),
+}
+
+/// Transfers the balance of the account minus the fee to the recipient.
+async fn drain(
+    fork: &impl LedgerEnv,
+    account: &Account,
+    recipient: &Account,
+    fee: &Nat,
+) -> anyhow::Result<Drain> {
+    let balance = balance_of(fork, account.clone()).await?;
+    if balance == 0u8 {
+        return Ok(Drain::Empty);
+    }
+    if &balance <= fee {
+        return Ok(Drain::Dust);
+    }
+    let amount = balance - fee.clone();
+    let now = fork
+        .time()
+        .await
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("the ledger time is before the UNIX epoch")
+        .as_nanos() as u64;
+    let mut arg = Transfer::amount_to(amount.clone(), recipient.clone())
+        .fee(fee.clone())
+        .created_at_time(now);
+    arg.from_subaccount = account.subaccount;
+    transfer(fork, arg)
+        .await?
+        .map_err(|err| anyhow::anyhow!("the transfer failed: {:?}", err))?;
+    Ok(Drain::Drained(amount))
+}
+
+#[async_trait(?Send)]
+impl<L: LedgerEnv + Clone> LedgerEnv for SweepingLedger<L> {
+    fn fork(&self) -> Self {
+        let inner = self.inner.fork();
+        self.registry
+            .lock()
+            .expect("failed to grab a lock")
+            .forks
+            .push(inner.clone());
+        Self {
+            inner,
+            recipient: self.recipient,
+            registry: self.registry.clone(),
+        }
+    }
+
+    fn principal(&self) -> Principal {
+        self.inner.principal()
+    }
+
+    fn canister_id(&self) -> Option<Principal> {
+        self.inner.canister_id()
+    }
+
+    async fn root_key(&self) -> Option<Vec<u8>> {
+        self.inner.root_key().await
+    }
+
+    async fn time(&self) -> SystemTime {
+        self.inner.time().await
+    }
+
+    fn time_control(&self) -> Option<&dyn TimeControl> {
+        self.inner.time_control()
+    }
+
+    async fn query_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.inner.query_raw(method, arg).await
+    }
+
+    async fn query_canister_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.inner
+            .query_canister_raw(canister_id, method, arg)
+            .await
+    }
+
+    async fn update_raw(&self, method: &str, arg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.track_update(method, &arg);
+        self.inner.update_raw(method, arg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockLedger, TransferError};
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_sweeps_forks_and_their_subaccounts() {
+        let mock = MockLedger::new();
+        let ledger = SweepingLedger::new(mock.clone());
+        let funder = Account::from(ledger.principal());
+        let p1 = ledger.fork();
+        let p2 = ledger.fork();
+        let p1_sub = Account {
+            owner: p1.principal(),
+            subaccount: Some([1; 32]),
+        };
+
+        mock.expect_update("icrc1_transfer")
+            .returning(Ok::<Nat, TransferError>(Nat::from(0u8)));
+        block_on(transfer(&p2, Transfer::amount_to(5u8, p1_sub.clone())))
+            .unwrap()
+            .unwrap();
+
+        mock.expect_query("icrc1_fee").returning(Nat::from(10u8));
+        mock.expect_query("icrc1_balance_of")
+            .with_arg_eq(Account::from(p1.principal()))
+            .returning(Nat::from(100u8));
+        mock.expect_query("icrc1_balance_of")
+            .with_arg_eq(p1_sub.clone())
+            .returning(Nat::from(5u8));
+        mock.expect_query("icrc1_balance_of")
+            .with_arg_eq(Account::from(p2.principal()))
+            .returning(Nat::from(0u8));
+        mock.expect_update("icrc1_transfer")
+            .with_arg(move |arg: &Transfer| arg.amount == 90u8 && arg.to == funder)
+            .returning(Ok::<Nat, TransferError>(Nat::from(1u8)));
+
+        let report = block_on(ledger.sweep()).unwrap();
+        assert_eq!(report.swept, Nat::from(90u8));
+        assert_eq!(report.fees, Nat::from(10u8));
+        assert_eq!(report.drained, 1);
+        assert_eq!(report.dust, 1);
+        assert!(report.failures.is_empty());
+        assert_eq!(mock.verify(), Ok(()));
+    }
+}
@@ -0,0 +1,62 @@
+//! The JSON-lines trace format shared by [crate::RecordingLedger] and
+//! [crate::ReplayingLedger].
+
+use serde::{Deserialize, Serialize};
+
+/// A line of the trace, tagged with its kind.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum TraceRecord {
+    /// The first line of every trace, describing the recorded environment.
+    Ledger(LedgerRecord),
+    Fork(ForkRecord),
+    Time(TimeRecord),
+    RootKey(RootKeyRecord),
+    Query(CallRecord),
+    Update(CallRecord),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct LedgerRecord {
+    /// The canister id of the ledger, if the environment exposes it.
+    pub canister_id: Option<String>,
+    pub principal: String,
+}
+
+/// A new environment created by `fork`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ForkRecord {
+    pub correlation_id: Option<String>,
+    pub caller: String,
+    pub principal: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct TimeRecord {
+    pub correlation_id: Option<String>,
+    pub caller: String,
+    pub time_nanos: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RootKeyRecord {
+    pub correlation_id: Option<String>,
+    pub caller: String,
+    pub root_key_hex: Option<String>,
+}
+
+/// A query or an update call.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CallRecord {
+    pub correlation_id: Option<String>,
+    /// The called canister, or None for calls on the ledger.
+    pub canister_id: Option<String>,
+    pub method: String,
+    pub caller: String,
+    /// The Debug rendering of the arguments, unknown for raw calls.
+    pub args_debug: Option<String>,
+    pub args_hex: String,
+    pub reply_hex: Option<String>,
+    pub reject: Option<String>,
+    pub latency_ms: f64,
+}
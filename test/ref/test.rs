@@ -165,7 +165,7 @@ async fn test_replica() {
     // We need to set the identity of the agent to that of what a user would parse
     agent.set_identity(p1);
     let env = ReplicaLedger::new(agent, canister_id);
-    let tests = icrc1_test_suite::test_suite(env).await;
+    let tests = icrc1_test_suite::test_suite(env, None).await;
 
     if !icrc1_test_suite::execute_tests(tests).await {
         std::process::exit(1);
@@ -207,7 +207,7 @@ async fn test_pocket_ic() {
 
     let env = PICLedger::new(Arc::new(pic), canister_id, p1.sender().unwrap());
 
-    let tests = icrc1_test_suite::test_suite(env).await;
+    let tests = icrc1_test_suite::test_suite(env, None).await;
 
     if !icrc1_test_suite::execute_tests(tests).await {
         std::process::exit(1);
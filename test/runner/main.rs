@@ -2,10 +2,21 @@ use candid::Principal;
 use ic_agent::agent::http_transport::reqwest_transport::ReqwestHttpReplicaV2Transport;
 use ic_agent::identity::BasicIdentity;
 use ic_agent::Agent;
+use icrc1_test_env::{
+    LedgerEnv, RecordingLedger, ReplayingLedger, RetryPolicy, RetryingLedger, SweepingLedger,
+};
 use icrc1_test_env_replica::ReplicaLedger;
+use icrc1_test_suite::{
+    ExecutionConfig, FundingBudget, JUnitReporter, JsonReporter, Reporter, TapReporter, Test,
+    TestFilter,
+};
 use pico_args::Arguments;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 fn print_help() {
     println!(
@@ -16,7 +27,64 @@ Options:
   -c, --canister PRINCIPAL     The canister id of the ledger
 
   -s, --secret-key PATH        The path to the PEM file of the identity
-                               holding enough funds for the test
+                               holding enough funds for the test; the
+                               tokens left in the test accounts are returned
+                               to it after the run
+
+  --replay-blocks              Also replay the entire ICRC-3 block log and
+                               compare balances and allowances with the ledger
+
+  --trace PATH                 Record every ledger call of the tests as JSON
+                               lines in the file at PATH
+
+  --replay-trace PATH          Run the tests offline against the responses
+                               recorded in the trace at PATH instead of a
+                               replica; no other options are required
+
+  --seed N                     Derive the identities of the test accounts
+                               from the seed N instead of a random seed; the
+                               seed of every run is printed in the TAP header
+
+  --clock-fallback MS          Use the local clock when the certified ledger
+                               time cannot be read, unless the local clock
+                               was more than MS milliseconds off the last
+                               certified time; without this option, failing
+                               to read the time fails the next ledger call
+
+  --include GLOB               Only run the tests whose names match the glob,
+                               e.g. 'icrc2:*'; can be repeated
+
+  --exclude GLOB               Skip the tests whose names match the glob; can
+                               be repeated
+
+  --tag TAG                    Only run the tests with the tag; can be
+                               repeated. Tags: dedup, burn, time-sensitive,
+                               destructive
+
+  --skip-tag TAG               Skip the tests with the tag; can be repeated
+
+  --test-timeout SECS          Fail and cancel the tests running longer than
+                               SECS seconds
+
+  --deadline SECS              Fail the tests still running SECS seconds
+                               after the start of the run and skip the rest
+
+  --max-concurrency N          Run at most N tests at the same time
+
+  --retries N                  Retry ledger calls failing with transient
+                               errors up to N times with exponential backoff;
+                               updates without created_at_time are never
+                               retried
+
+  --dry-run                    Print the selected tests with their estimated
+                               cost and the funding budget without running
+                               them; fails if the funding account cannot pay
+                               for the tests
+
+  --report FORMAT[:PATH]       Write the results in the format to the file at
+                               PATH, or to stdout without a PATH; can be
+                               repeated. Formats: tap, junit, json. Defaults
+                               to tap on stdout
 "#,
         std::env::args().next().unwrap()
     )
@@ -30,6 +98,95 @@ async fn main() {
         std::process::exit(0);
     }
 
+    let replay_blocks = args.contains("--replay-blocks");
+    let dry_run = args.contains("--dry-run");
+
+    let filter = parse_filter(&mut args).unwrap_or_else(|e| {
+        eprintln!("Failed to parse test filters: {}", e);
+        print_help();
+        std::process::exit(1);
+    });
+    let execution = parse_execution_config(&mut args).unwrap_or_else(|e| {
+        eprintln!("Failed to parse execution limits: {}", e);
+        print_help();
+        std::process::exit(1);
+    });
+    let mut reports: Vec<ReportSpec> = args.values_from_str("--report").unwrap_or_else(|e| {
+        eprintln!("Failed to parse reports: {}", e);
+        print_help();
+        std::process::exit(1);
+    });
+    if reports.is_empty() {
+        reports.push(ReportSpec {
+            format: ReportFormat::Tap,
+            path: None,
+        });
+    }
+    if reports
+        .iter()
+        .filter(|report| report.path.is_none())
+        .count()
+        > 1
+    {
+        eprintln!("At most one report can be written to stdout");
+        print_help();
+        std::process::exit(1);
+    }
+    let mut options = RunOptions {
+        replay_blocks,
+        dry_run,
+        filter,
+        execution,
+        reports,
+    };
+
+    let trace_path: Option<PathBuf> = args.opt_value_from_str("--trace").unwrap_or_else(|e| {
+        eprintln!("Failed to parse trace path: {}", e);
+        print_help();
+        std::process::exit(1);
+    });
+
+    let seed: Option<u64> = args.opt_value_from_str("--seed").unwrap_or_else(|e| {
+        eprintln!("Failed to parse seed: {}", e);
+        print_help();
+        std::process::exit(1);
+    });
+
+    let clock_fallback: Option<u64> =
+        args.opt_value_from_str("--clock-fallback")
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to parse the tolerated clock skew: {}", e);
+                print_help();
+                std::process::exit(1);
+            });
+
+    let retries: Option<u32> = args.opt_value_from_str("--retries").unwrap_or_else(|e| {
+        eprintln!("Failed to parse the number of retries: {}", e);
+        print_help();
+        std::process::exit(1);
+    });
+
+    let replay_trace: Option<PathBuf> =
+        args.opt_value_from_str("--replay-trace")
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to parse replay trace path: {}", e);
+                print_help();
+                std::process::exit(1);
+            });
+    if let Some(path) = replay_trace {
+        let env = ReplayingLedger::load(&path)
+            .unwrap_or_else(|e| panic!("failed to load trace file {}: {:#}", path.display(), e));
+        let success = run_tests(env.clone(), &options).await;
+        if let Some(divergence) = env.divergence() {
+            eprintln!("The run diverged from the trace: {}", divergence);
+            std::process::exit(1);
+        }
+        if !success {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let canister_id = args
         .value_from_fn(["-c", "--canister"], |s: &str| Principal::from_text(s))
         .unwrap_or_else(|e| {
@@ -80,10 +237,213 @@ async fn main() {
         .await
         .expect("agent failed to fetch the root key");
 
-    let env = ReplicaLedger::new(agent, canister_id);
-    let tests = icrc1_test_suite::test_suite(env).await;
+    let seed = seed.unwrap_or_else(rand::random);
+    let mut env = ReplicaLedger::with_seed(agent, canister_id, seed);
+    if let Some(max_skew_ms) = clock_fallback {
+        env = env.with_local_clock_fallback(Duration::from_millis(max_skew_ms));
+    }
+    options
+        .execution
+        .header
+        .push(format!("seed: {} (rerun with --seed {})", seed, seed));
+    let success = match retries {
+        Some(max_retries) => {
+            let env = RetryingLedger::new(env, RetryPolicy::default().max_retries(max_retries));
+            run_and_sweep(env, trace_path, &options).await
+        }
+        None => run_and_sweep(env, trace_path, &options).await,
+    };
 
-    if !icrc1_test_suite::execute_tests(tests).await {
+    if !success {
         std::process::exit(1);
     }
 }
+
+/// Runs the tests, then returns the tokens left in the test accounts to
+/// the funding account. The sweep does not change the exit status but
+/// lists the accounts it could not drain.
+async fn run_and_sweep(
+    env: impl LedgerEnv + Clone + 'static,
+    trace_path: Option<PathBuf>,
+    options: &RunOptions,
+) -> bool {
+    let env = SweepingLedger::new(env);
+    let success = run_recorded_tests(env.clone(), trace_path, options).await;
+    if !options.dry_run {
+        match env.sweep().await {
+            Ok(report) => eprintln!("{}", report),
+            Err(e) => eprintln!("Failed to sweep the test accounts: {:#}", e),
+        }
+    }
+    success
+}
+
+/// Runs the tests, recording the calls to the trace file if there is one.
+/// The recording sees only the outcome of the last attempt of retried
+/// calls, so that the trace replays like a run without failures.
+async fn run_recorded_tests(
+    env: impl LedgerEnv + Clone + 'static,
+    trace_path: Option<PathBuf>,
+    options: &RunOptions,
+) -> bool {
+    match trace_path {
+        Some(path) => {
+            let env = RecordingLedger::create(env, &path).unwrap_or_else(|e| {
+                panic!("failed to create trace file {}: {}", path.display(), e)
+            });
+            run_tests(env, options).await
+        }
+        None => run_tests(env, options).await,
+    }
+}
+
+/// How to select and report the tests.
+struct RunOptions {
+    replay_blocks: bool,
+    dry_run: bool,
+    filter: TestFilter,
+    execution: ExecutionConfig,
+    reports: Vec<ReportSpec>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ReportFormat {
+    Tap,
+    JUnit,
+    Json,
+}
+
+/// A report to write, parsed from `FORMAT[:PATH]`.
+#[derive(Clone, Debug)]
+struct ReportSpec {
+    format: ReportFormat,
+    path: Option<PathBuf>,
+}
+
+impl FromStr for ReportSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, path) = match s.split_once(':') {
+            Some((format, path)) => (format, Some(PathBuf::from(path))),
+            None => (s, None),
+        };
+        let format = match format {
+            "tap" => ReportFormat::Tap,
+            "junit" => ReportFormat::JUnit,
+            "json" => ReportFormat::Json,
+            _ => {
+                return Err(format!(
+                    "unknown report format {}, expected one of tap, junit, json",
+                    format
+                ))
+            }
+        };
+        Ok(Self { format, path })
+    }
+}
+
+impl ReportSpec {
+    fn reporter(&self) -> io::Result<Box<dyn Reporter>> {
+        let out: Box<dyn Write> = match &self.path {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        };
+        Ok(match self.format {
+            ReportFormat::Tap => Box::new(TapReporter::new(out)),
+            ReportFormat::JUnit => Box::new(JUnitReporter::new(out)),
+            ReportFormat::Json => Box::new(JsonReporter::new(out)),
+        })
+    }
+}
+
+fn parse_filter(args: &mut Arguments) -> Result<TestFilter, pico_args::Error> {
+    Ok(TestFilter {
+        include: args.values_from_str("--include")?,
+        exclude: args.values_from_str("--exclude")?,
+        include_tags: args.values_from_str("--tag")?,
+        exclude_tags: args.values_from_str("--skip-tag")?,
+    })
+}
+
+fn parse_execution_config(args: &mut Arguments) -> Result<ExecutionConfig, pico_args::Error> {
+    let secs = |secs: Option<u64>| secs.map(Duration::from_secs);
+    let max_concurrency: Option<usize> = args.opt_value_from_str("--max-concurrency")?;
+    if max_concurrency == Some(0) {
+        return Err(pico_args::Error::ArgumentParsingFailed {
+            cause: "the maximum concurrency must be positive".to_string(),
+        });
+    }
+    Ok(ExecutionConfig {
+        test_timeout: secs(args.opt_value_from_str("--test-timeout")?),
+        deadline: secs(args.opt_value_from_str("--deadline")?),
+        max_concurrency,
+        header: vec![],
+    })
+}
+
+async fn run_tests(env: impl LedgerEnv + Clone + 'static, options: &RunOptions) -> bool {
+    let mut tests = icrc1_test_suite::test_suite(env.clone(), Some(&options.filter)).await;
+    if options.replay_blocks {
+        tests.append(
+            &mut options
+                .filter
+                .apply(icrc1_test_suite::optional_test_suite(env.clone())),
+        );
+    }
+
+    let budget = match FundingBudget::compute(&env, &tests).await {
+        Ok(budget) => budget,
+        Err(e) => {
+            eprintln!("Failed to compute the funding budget: {:#}", e);
+            return false;
+        }
+    };
+    if options.dry_run {
+        print_plan(&tests, &budget);
+        return budget.is_sufficient();
+    }
+    if let Err(e) = budget.check() {
+        eprintln!("{:#}", e);
+        return false;
+    }
+    let execution = options
+        .execution
+        .clone()
+        .header_line(format!("budget: {}", budget));
+
+    let mut reporters: Vec<Box<dyn Reporter>> = options
+        .reports
+        .iter()
+        .map(|report| {
+            report.reporter().unwrap_or_else(|e| {
+                let path = report.path.as_ref().expect("stdout is always writable");
+                panic!("failed to create report file {}: {}", path.display(), e)
+            })
+        })
+        .collect();
+    icrc1_test_suite::execute_tests_with_reporters(tests, &execution, &mut reporters).await
+}
+
+/// Prints the tests that would run with their estimated cost, followed by
+/// the budget.
+fn print_plan(tests: &[Test], budget: &FundingBudget) {
+    println!("{} tests selected", tests.len());
+    for test in tests {
+        let cost = test.metadata().cost;
+        println!(
+            "  {}: {} tokens plus {} fees = {}",
+            test.name(),
+            cost.tokens,
+            cost.fees,
+            cost.total(&budget.fee)
+        );
+    }
+    println!("budget: {}", budget);
+    if !budget.is_sufficient() {
+        println!(
+            "insufficient funds: {} more tokens are needed",
+            budget.required.clone() - budget.available.clone()
+        );
+    }
+}
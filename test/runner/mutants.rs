@@ -0,0 +1,144 @@
+//! Runs the test suite against intentionally broken in-memory ledgers and
+//! reports the mutants that survive, i.e. the bugs the suite does not catch.
+
+use candid::{Nat, Principal};
+use icrc1_test_env::Account;
+use icrc1_test_env_in_memory::{InMemoryLedger, InitArgs, Mutation};
+use icrc1_test_suite::{
+    execute_tests_with_reporters, ExecutionConfig, Reporter, TapReporter, TestCase, TestFailure,
+    TestMetadata,
+};
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+fn init_args(sender: Principal) -> InitArgs {
+    InitArgs {
+        initial_mints: vec![(Account::from(sender), Nat::from(100_000_000_000u64))],
+        minting_account: Account::from(Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1])),
+        token_name: "Mutant Token".to_string(),
+        token_symbol: "MUT".to_string(),
+        decimals: 8,
+        transfer_fee: Nat::from(10_000u32),
+    }
+}
+
+/// Collects the names of the failed tests instead of printing the results.
+struct FailedTests(Rc<RefCell<Vec<String>>>);
+
+impl Reporter for FailedTests {
+    fn run_started(&mut self, _tests: usize, _header: &[String]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn test_passed(&mut self, _test: &TestCase<'_>, _duration: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn test_skipped(
+        &mut self,
+        _test: &TestCase<'_>,
+        _reason: &str,
+        _duration: Duration,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn test_failed(
+        &mut self,
+        test: &TestCase<'_>,
+        _failure: &TestFailure,
+        _duration: Duration,
+    ) -> io::Result<()> {
+        self.0.borrow_mut().push(test.name.to_string());
+        Ok(())
+    }
+
+    fn run_finished(&mut self, _duration: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the full test suite, including the optional tests, and returns
+/// the names of the failed tests.
+async fn run_suite(ledger: InMemoryLedger) -> Vec<String> {
+    let mut tests = icrc1_test_suite::test_suite(ledger.clone(), None).await;
+    tests.append(&mut icrc1_test_suite::optional_test_suite(ledger));
+    let failed = Rc::new(RefCell::new(vec![]));
+    let mut reporters: Vec<Box<dyn Reporter>> = vec![Box::new(FailedTests(failed.clone()))];
+    execute_tests_with_reporters(tests, &ExecutionConfig::default(), &mut reporters).await;
+    let failed = failed.borrow().clone();
+    failed
+}
+
+/// Prints a single TAP report with the unmutated ledger as the first test
+/// and every mutant as a further test, which passes if the mutant is
+/// killed. Exits with a non-zero status if the suite fails against the
+/// unmutated ledger or a mutant survives.
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> io::Result<()> {
+    let sender = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 3, 1, 1]);
+    let metadata = TestMetadata::default();
+    let mut report = TapReporter::stdout();
+    let started = Instant::now();
+    let header = ["a mutant is killed if at least one test fails against it".to_string()];
+    report.run_started(Mutation::ALL.len() + 1, &header)?;
+
+    let case = TestCase {
+        number: 1,
+        name: "unmutated ledger",
+        metadata: &metadata,
+    };
+    let run_started = Instant::now();
+    let failed = run_suite(InMemoryLedger::new(init_args(sender), sender)).await;
+    let unmutated_passes = failed.is_empty();
+    if unmutated_passes {
+        report.test_passed(&case, run_started.elapsed())?;
+    } else {
+        let failure = TestFailure {
+            chain: vec![format!(
+                "the test suite fails against the unmutated ledger: {}",
+                failed.join(", ")
+            )],
+        };
+        report.test_failed(&case, &failure, run_started.elapsed())?;
+    }
+
+    let mut survivors = 0;
+    for (i, mutation) in Mutation::ALL.iter().enumerate() {
+        let name = format!("mutant {}", mutation);
+        let case = TestCase {
+            number: i + 2,
+            name: &name,
+            metadata: &metadata,
+        };
+        if !unmutated_passes {
+            report.test_skipped(
+                &case,
+                "mutants cannot be assessed while the unmutated ledger fails",
+                Duration::from_secs(0),
+            )?;
+            continue;
+        }
+        let run_started = Instant::now();
+        let ledger = InMemoryLedger::with_mutation(init_args(sender), *mutation, sender);
+        let failed = run_suite(ledger).await;
+        if failed.is_empty() {
+            survivors += 1;
+            let failure = TestFailure {
+                chain: vec!["the mutant survived: no test fails against it".to_string()],
+            };
+            report.test_failed(&case, &failure, run_started.elapsed())?;
+        } else {
+            report.test_passed(&case, run_started.elapsed())?;
+        }
+    }
+    report.run_finished(started.elapsed())?;
+
+    if !unmutated_passes || survivors > 0 {
+        eprintln!("{} of {} mutants survived", survivors, Mutation::ALL.len());
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! Running the tests and printing their results.
+
+use crate::report::{Reporter, TapReporter, TestCase, TestFailure};
+use crate::{Outcome, Test, TestMetadata, TestResult};
+use futures::future::{select, Either};
+use futures::StreamExt;
+use icrc1_test_env::{sleep, with_correlation_id};
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Limits on the execution of the tests.
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionConfig {
+    /// The time after which a test is reported as failed and cancelled.
+    pub test_timeout: Option<Duration>,
+    /// The time after which the tests still running are reported as
+    /// failed and cancelled and the remaining tests are skipped.
+    pub deadline: Option<Duration>,
+    /// The maximum number of tests running at the same time.
+    pub max_concurrency: Option<usize>,
+    /// The lines printed as TAP comments before the results, e.g. to record
+    /// how to reproduce the run.
+    pub header: Vec<String>,
+}
+
+impl ExecutionConfig {
+    pub fn test_timeout(mut self, timeout: Duration) -> Self {
+        self.test_timeout = Some(timeout);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        assert!(
+            max_concurrency > 0,
+            "the maximum concurrency must be positive"
+        );
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
+    pub fn header_line(mut self, line: impl Into<String>) -> Self {
+        self.header.push(line.into());
+        self
+    }
+}
+
+/// Executes the list of tests concurrently and prints results using
+/// the TAP protocol (https://testanything.org/). Exclusive tests run one
+/// at a time after all other tests.
+///
+/// Calls made by a test carry the test name as their correlation id.
+pub async fn execute_tests(tests: Vec<Test>) -> bool {
+    execute_tests_with_config(tests, &ExecutionConfig::default()).await
+}
+
+/// Same as [execute_tests], but within the limits of the configuration.
+/// A test exceeding its timeout is reported as `not ok` while the other
+/// tests keep running.
+pub async fn execute_tests_with_config(tests: Vec<Test>, config: &ExecutionConfig) -> bool {
+    let mut reporters: Vec<Box<dyn Reporter>> = vec![Box::new(TapReporter::stdout())];
+    execute_tests_with_reporters(tests, config, &mut reporters).await
+}
+
+/// Same as [execute_tests_with_config], but reports the results to the
+/// reporters instead of printing TAP. Returns false if a test failed or a
+/// reporter failed to write its report.
+pub async fn execute_tests_with_reporters(
+    tests: Vec<Test>,
+    config: &ExecutionConfig,
+    reporters: &mut [Box<dyn Reporter>],
+) -> bool {
+    let started = Instant::now();
+    let reporters = Reporters::new(reporters);
+    let (exclusive, concurrent): (Vec<Test>, Vec<Test>) =
+        tests.into_iter().partition(|test| test.exclusive);
+    let concurrent_count = concurrent.len();
+
+    reporters.emit(|r| r.run_started(concurrent_count + exclusive.len(), &config.header));
+
+    let run = |number: usize, test: Test| {
+        let reporters = &reporters;
+        async move {
+            let case = TestCase {
+                number,
+                name: &test.name,
+                metadata: &test.metadata,
+            };
+            reporters.emit(|r| r.test_started(&case));
+            let test_started = Instant::now();
+            let result = run_test(test.name.clone(), test.action, config, started).await;
+            let duration = test_started.elapsed();
+            (number, test.name, test.metadata, result, duration)
+        }
+    };
+
+    let mut results = futures::stream::iter(concurrent.into_iter().enumerate())
+        .map(|(idx, test)| run(idx + 1, test))
+        .buffer_unordered(config.max_concurrency.unwrap_or(usize::MAX));
+
+    let mut success = true;
+    while let Some((number, name, metadata, result, duration)) = results.next().await {
+        success &= reporters.finished(number, &name, &metadata, result, duration);
+    }
+    for (idx, test) in exclusive.into_iter().enumerate() {
+        let (number, name, metadata, result, duration) =
+            run(concurrent_count + idx + 1, test).await;
+        success &= reporters.finished(number, &name, &metadata, result, duration);
+    }
+
+    reporters.emit(|r| r.run_finished(started.elapsed()));
+    success && reporters.ok.get()
+}
+
+/// Dispatches the events to the reporters from the concurrently running
+/// tests, remembering whether any reporter failed.
+struct Reporters<'a> {
+    reporters: RefCell<&'a mut [Box<dyn Reporter>]>,
+    ok: Cell<bool>,
+}
+
+impl<'a> Reporters<'a> {
+    fn new(reporters: &'a mut [Box<dyn Reporter>]) -> Self {
+        Self {
+            reporters: RefCell::new(reporters),
+            ok: Cell::new(true),
+        }
+    }
+
+    fn emit(&self, mut event: impl FnMut(&mut dyn Reporter) -> io::Result<()>) {
+        for reporter in self.reporters.borrow_mut().iter_mut() {
+            if let Err(err) = event(reporter.as_mut()) {
+                eprintln!("failed to write the test report: {}", err);
+                self.ok.set(false);
+            }
+        }
+    }
+
+    /// Reports the result of a test and returns whether the test passed.
+    fn finished(
+        &self,
+        number: usize,
+        name: &str,
+        metadata: &TestMetadata,
+        result: TestResult,
+        duration: Duration,
+    ) -> bool {
+        let case = TestCase {
+            number,
+            name,
+            metadata,
+        };
+        match result {
+            Ok(Outcome::Passed) => {
+                self.emit(|r| r.test_passed(&case, duration));
+                true
+            }
+            Ok(Outcome::Skipped { reason }) => {
+                self.emit(|r| r.test_skipped(&case, &reason, duration));
+                true
+            }
+            Err(err) => {
+                let failure = TestFailure::from(&err);
+                self.emit(|r| r.test_failed(&case, &failure, duration));
+                false
+            }
+        }
+    }
+}
+
+/// Runs the test within the smaller of the test timeout and the time
+/// left until the deadline.
+async fn run_test(
+    name: String,
+    action: impl Future<Output = TestResult>,
+    config: &ExecutionConfig,
+    started: Instant,
+) -> TestResult {
+    let mut limit = config.test_timeout.map(|timeout| {
+        (
+            timeout,
+            format!("the test timed out after {}s", timeout.as_secs_f64()),
+        )
+    });
+    if let Some(deadline) = config.deadline {
+        let left = deadline.saturating_sub(started.elapsed());
+        if left == Duration::ZERO {
+            return Ok(Outcome::Skipped {
+                reason: format!(
+                    "the run deadline of {}s passed before the test started",
+                    deadline.as_secs_f64()
+                ),
+            });
+        }
+        if limit.as_ref().map_or(true, |(timeout, _)| left < *timeout) {
+            limit = Some((
+                left,
+                format!("the run deadline of {}s passed", deadline.as_secs_f64()),
+            ));
+        }
+    }
+
+    let action = with_correlation_id(name, action);
+    match limit {
+        None => action.await,
+        Some((limit, diagnostic)) => match select(Box::pin(action), Box::pin(sleep(limit))).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(anyhow::Error::msg(format!(
+                "{}; the test was cancelled",
+                diagnostic
+            ))),
+        },
+    }
+}
@@ -0,0 +1,71 @@
+//! The tokens a test run takes from the funding account.
+
+use crate::{Cost, Test};
+use anyhow::bail;
+use candid::{Nat, Principal};
+use icrc1_test_env::icrc1::{balance_of, transfer_fee};
+use icrc1_test_env::LedgerEnv;
+use std::fmt;
+
+/// The estimated cost of the selected tests compared with the balance of
+/// the funding account.
+#[derive(Clone, Debug)]
+pub struct FundingBudget {
+    /// The owner of the funding account.
+    pub funder: Principal,
+    /// The summed cost of the tests. It covers the fees of the transfers
+    /// funding the test accounts.
+    pub cost: Cost,
+    pub fee: Nat,
+    /// The cost of the tests in tokens.
+    pub required: Nat,
+    /// The balance of the funding account.
+    pub available: Nat,
+}
+
+impl FundingBudget {
+    /// Computes the budget of the tests from their declared costs, the
+    /// transfer fee and the balance of the funding account. Only queries
+    /// the ledger.
+    pub async fn compute(env: &impl LedgerEnv, tests: &[Test]) -> anyhow::Result<Self> {
+        let cost: Cost = tests.iter().map(|test| test.metadata().cost).sum();
+        let fee = transfer_fee(env).await?;
+        let available = balance_of(env, env.principal()).await?;
+        Ok(Self {
+            funder: env.principal(),
+            cost,
+            required: cost.total(&fee),
+            fee,
+            available,
+        })
+    }
+
+    pub fn is_sufficient(&self) -> bool {
+        self.available >= self.required
+    }
+
+    /// Fails with the required and available balance if the funding
+    /// account cannot pay for the tests.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if !self.is_sufficient() {
+            bail!(
+                "insufficient funds: the tests need {} tokens but the funding account {} holds {}; transfer at least {} more tokens to it",
+                self.required,
+                self.funder,
+                self.available,
+                self.required.clone() - self.available.clone()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FundingBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "required {} tokens ({} tokens plus {} fees of {}), available {} in the funding account {}",
+            self.required, self.cost.tokens, self.cost.fees, self.fee, self.available, self.funder
+        )
+    }
+}
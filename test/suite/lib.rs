@@ -1,18 +1,38 @@
 use anyhow::{bail, Context};
-use candid::Nat;
+use candid::{Nat, Principal};
 use futures::StreamExt;
 use icrc1_test_env::icrc1::{
     balance_of, metadata, minting_account, supported_standards, token_decimals, token_name,
-    token_symbol, transfer, transfer_fee,
+    token_symbol, transfer_fee,
 };
-use icrc1_test_env::icrc2::{allowance, approve, transfer_from};
+use icrc1_test_env::icrc2::allowance;
+use icrc1_test_env::icrc3::{get_blocks, get_tip_certificate, supported_block_types};
 use icrc1_test_env::ApproveArgs;
 use icrc1_test_env::TransferFromArgs;
-use icrc1_test_env::{Account, LedgerEnv, Transfer, TransferError, Value};
+use icrc1_test_env::{block_stream, icrc1, icrc2};
+use icrc1_test_env::{verify_tip_certificate, Account, LedgerEnv, Transfer, TransferError, Value};
 use icrc1_test_env::{AllowanceArgs, ApproveError, TransferFromError};
+use icrc1_test_env::{Block, BlockInfo, BlockWithId, GetBlocksArgs, GetBlocksRequest};
+use std::cell::Cell;
+use std::convert::TryFrom;
 use std::future::Future;
 use std::pin::Pin;
-use std::time::SystemTime;
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+
+mod execution;
+mod funding;
+mod metadata;
+mod replay;
+mod report;
+
+pub use execution::{
+    execute_tests, execute_tests_with_config, execute_tests_with_reporters, ExecutionConfig,
+};
+pub use funding::FundingBudget;
+pub use metadata::{glob_match, Cost, Standard, Tag, TestFilter, TestMetadata};
+pub use replay::{replay_block_log, ReplayMismatch};
+pub use report::{JUnitReporter, JsonReporter, Reporter, TapReporter, TestCase, TestFailure};
 
 pub enum Outcome {
     Passed,
@@ -24,15 +44,79 @@ pub type TestResult = anyhow::Result<Outcome>;
 pub struct Test {
     name: String,
     action: Pin<Box<dyn Future<Output = TestResult>>>,
+    exclusive: bool,
+    metadata: TestMetadata,
+}
+
+impl Test {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn metadata(&self) -> &TestMetadata {
+        &self.metadata
+    }
+
+    /// Sets the standard the test checks and links the section of the
+    /// standard, e.g. `transfer_method`.
+    pub fn spec(mut self, standard: Standard, section: &str) -> Self {
+        self.metadata.standard = Some(standard);
+        self.metadata.spec = Some(standard.spec_link(section));
+        self
+    }
+
+    pub fn tags(mut self, tags: &[Tag]) -> Self {
+        self.metadata.tags.extend_from_slice(tags);
+        self
+    }
+
+    /// Sets the estimated cost of the test: the tokens it takes from the
+    /// funding account, besides `fees` transfer fees.
+    pub fn cost(mut self, tokens: u64, fees: u64) -> Self {
+        self.metadata.cost = Cost::new(tokens, fees);
+        self
+    }
+
+    /// Makes the test check the block recording every transfer, approval
+    /// and transfer from it makes, which requires ICRC-3 support.
+    pub fn check_blocks(mut self, enabled: bool) -> Self {
+        self.action = Box::pin(with_block_checks(enabled, self.action));
+        self
+    }
 }
 
 pub fn test(name: impl Into<String>, body: impl Future<Output = TestResult> + 'static) -> Test {
     Test {
         name: name.into(),
         action: Box::pin(body),
+        exclusive: false,
+        metadata: TestMetadata::default(),
     }
 }
 
+/// Creates a test that runs alone after all other tests, e.g. because it
+/// moves the ledger time.
+pub fn exclusive_test(
+    name: impl Into<String>,
+    body: impl Future<Output = TestResult> + 'static,
+) -> Test {
+    Test {
+        exclusive: true,
+        ..test(name, body)
+    }
+}
+
+/// The deduplication window of the reference ledgers, in nanoseconds.
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The permitted drift of `created_at_time` of the reference ledgers, in
+/// nanoseconds.
+const PERMITTED_DRIFT: u64 = 60 * 1_000_000_000;
+
+/// The margin kept around time boundaries, absorbing the time the ledger
+/// takes to execute a call.
+const TIME_MARGIN: u64 = 1_000_000_000;
+
 fn lookup<'a, K, V, U>(meta: &'a [(K, V)], key: &U) -> Option<&'a V>
 where
     K: PartialEq<U>,
@@ -55,6 +139,393 @@ fn assert_not_equal<T: PartialEq + std::fmt::Debug>(lhs: T, rhs: T) -> anyhow::R
     Ok(())
 }
 
+fn nat_to_u64(n: &Nat) -> anyhow::Result<u64> {
+    u64::try_from(&n.0).map_err(|_| anyhow::anyhow!("{} does not fit into u64", n))
+}
+
+fn blocks_request(start: impl Into<Nat>, length: impl Into<Nat>) -> GetBlocksArgs {
+    vec![GetBlocksRequest {
+        start: start.into(),
+        length: length.into(),
+    }]
+}
+
+async fn log_length(ledger_env: &impl LedgerEnv) -> anyhow::Result<u64> {
+    let result = get_blocks(ledger_env, blocks_request(0u8, 0u8)).await?;
+    nat_to_u64(&result.log_length)
+}
+
+/// Returns the block type of an ICRC-3 block, falling back to the
+/// `tx.op` field for blocks using the legacy ICRC-1/ICRC-2 schema.
+fn block_type(block: &Value) -> anyhow::Result<String> {
+    match block.get("btype") {
+        Some(Value::Text(btype)) => Ok(btype.clone()),
+        Some(other) => bail!("btype is not a Text: {:?}", other),
+        None => {
+            let tx = block.get("tx");
+            let btype = match tx.and_then(|tx| tx.get("op")).and_then(Value::as_text) {
+                Some("mint") => "1mint",
+                Some("burn") => "1burn",
+                Some("xfer") if tx.and_then(|tx| tx.get("spender")).is_some() => "2xfer",
+                Some("xfer") => "1xfer",
+                Some("approve") => "2approve",
+                op => bail!("the block has neither btype nor a known tx.op: {:?}", op),
+            };
+            Ok(btype.to_string())
+        }
+    }
+}
+
+/// Checks the generic ICRC-3 block schema and, for ICRC-1 and ICRC-2
+/// blocks, the corresponding block schema.
+fn check_block(
+    id: u64,
+    block: &Value,
+    parent: Option<&Value>,
+    supported_block_types: &[String],
+) -> anyhow::Result<()> {
+    if block.as_map().is_none() {
+        bail!("block {} is not a Map: {:?}", id, block);
+    }
+
+    match (id, block.get("phash")) {
+        (0, None) => {}
+        (0, Some(phash)) => bail!("block 0 has a parent hash: {:?}", phash),
+        (_, None) => bail!("block {} does not have a parent hash", id),
+        (_, Some(Value::Blob(phash))) => {
+            if let Some(parent) = parent {
+                if phash[..] != parent.hash()[..] {
+                    bail!(
+                        "the parent hash of block {} is {}, but the hash of block {} is {}",
+                        id,
+                        hex::encode(phash),
+                        id - 1,
+                        hex::encode(parent.hash())
+                    );
+                }
+            }
+        }
+        (_, Some(phash)) => bail!("the parent hash of block {} is not a Blob: {:?}", id, phash),
+    }
+
+    let btype = block_type(block).with_context(|| format!("invalid block {}", id))?;
+    if !supported_block_types.contains(&btype) {
+        bail!(
+            "block {} has type {} which is not in the supported block types {:?}",
+            id,
+            btype,
+            supported_block_types
+        );
+    }
+    if ["1mint", "1burn", "1xfer", "2xfer", "2approve"].contains(&btype.as_str()) {
+        Block::try_from(block)
+            .with_context(|| format!("block {} does not match the {} schema", id, btype))?;
+    }
+    Ok(())
+}
+
+thread_local! {
+    /// Whether the test being polled on this thread checks the blocks
+    /// recording its transactions.
+    static CHECK_BLOCKS: Cell<bool> = Cell::new(false);
+}
+
+/// Checks the blocks recording the transactions made while polling the
+/// future if `enabled` is true.
+fn with_block_checks<F: Future>(enabled: bool, future: F) -> impl Future<Output = F::Output> {
+    WithBlockChecks {
+        enabled,
+        future: Box::pin(future),
+    }
+}
+
+struct WithBlockChecks<F> {
+    enabled: bool,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithBlockChecks<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<F::Output> {
+        let previous = CHECK_BLOCKS.with(|check| check.replace(self.enabled));
+        let result = self.future.as_mut().poll(cx);
+        CHECK_BLOCKS.with(|check| check.set(previous));
+        result
+    }
+}
+
+fn checks_blocks() -> bool {
+    CHECK_BLOCKS.with(Cell::get)
+}
+
+async fn supports_icrc3(ledger_env: &impl LedgerEnv) -> anyhow::Result<bool> {
+    let stds = supported_standards(ledger_env).await?;
+    Ok(stds.iter().any(|std| std.name == "ICRC-3"))
+}
+
+/// Fetches and decodes the block with the specified index, following the
+/// archives if the ledger no longer holds the block.
+async fn get_block(ledger_env: &impl LedgerEnv, index: &Nat) -> anyhow::Result<Block> {
+    match block_stream(ledger_env, index.clone()).next().await {
+        Some(Ok((id, block))) if &id == index => {
+            Block::try_from(&block).with_context(|| format!("failed to decode block {}", index))
+        }
+        Some(Ok((id, _))) => bail!("expected block {}, got block {}", index, id),
+        Some(Err(err)) => Err(err.context(format!("failed to fetch block {}", index))),
+        None => bail!("the ledger did not return block {}", index),
+    }
+}
+
+/// Submits the transfer and, if the ledger accepts it, checks that the
+/// block with the returned index records it.
+async fn transfer(
+    ledger_env: &impl LedgerEnv,
+    args: Transfer,
+) -> anyhow::Result<Result<Nat, TransferError>> {
+    let result = icrc1::transfer(ledger_env, args.clone()).await?;
+    if let Ok(index) = &result {
+        check_transfer_block(ledger_env, index, ledger_env.principal(), &args).await?;
+    }
+    Ok(result)
+}
+
+/// Submits the approval and, if the ledger accepts it, checks that the
+/// block with the returned index records it.
+async fn approve(
+    ledger_env: &impl LedgerEnv,
+    args: ApproveArgs,
+) -> anyhow::Result<Result<Nat, ApproveError>> {
+    let result = icrc2::approve(ledger_env, args.clone()).await?;
+    if let Ok(index) = &result {
+        check_approve_block(ledger_env, index, ledger_env.principal(), &args).await?;
+    }
+    Ok(result)
+}
+
+/// Submits the transfer from and, if the ledger accepts it, checks that
+/// the block with the returned index records it.
+async fn transfer_from(
+    ledger_env: &impl LedgerEnv,
+    args: TransferFromArgs,
+) -> anyhow::Result<Result<Nat, TransferFromError>> {
+    let result = icrc2::transfer_from(ledger_env, args.clone()).await?;
+    if let Ok(index) = &result {
+        check_transfer_from_block(ledger_env, index, ledger_env.principal(), &args).await?;
+    }
+    Ok(result)
+}
+
+/// Treats the default subaccount and a missing subaccount as the same account.
+fn normalize(account: &Account) -> Account {
+    Account {
+        owner: account.owner,
+        subaccount: account.subaccount.filter(|s| s != &[0; 32]),
+    }
+}
+
+fn assert_block_field<T: PartialEq + std::fmt::Debug>(
+    field: &str,
+    expected: T,
+    actual: T,
+) -> anyhow::Result<()> {
+    assert_equal(expected, actual)
+        .with_context(|| format!("block field {} does not match the transaction", field))
+}
+
+fn check_block_info(
+    info: &BlockInfo,
+    ledger_fee: &Nat,
+    fee: &Option<Nat>,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> anyhow::Result<()> {
+    assert_block_field("tx.memo", memo.as_ref(), info.memo.as_ref())?;
+    assert_block_field("tx.ts", created_at_time, info.created_at_time)?;
+    if fee.is_some() {
+        assert_block_field("tx.fee", fee.as_ref(), info.tx_fee.as_ref())?;
+    }
+    assert_block_field("fee", Some(ledger_fee), info.effective_fee())
+}
+
+fn check_burn_or_mint_info(
+    info: &BlockInfo,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> anyhow::Result<()> {
+    assert_block_field("tx.memo", memo.as_ref(), info.memo.as_ref())?;
+    assert_block_field("tx.ts", created_at_time, info.created_at_time)?;
+    assert_block_field("fee", None, info.effective_fee())
+}
+
+/// Checks that the block with the specified index records the transfer
+/// submitted by the caller. Does nothing unless the test checks blocks, see
+/// [Test::check_blocks].
+async fn check_transfer_block(
+    ledger_env: &impl LedgerEnv,
+    index: &Nat,
+    caller: Principal,
+    args: &Transfer,
+) -> anyhow::Result<()> {
+    if !checks_blocks() {
+        return Ok(());
+    }
+    let ledger_fee = transfer_fee(ledger_env).await?;
+    let block = get_block(ledger_env, index).await?;
+    let expected_from = Account {
+        owner: caller,
+        subaccount: args.from_subaccount,
+    };
+    match &block {
+        Block::Transfer {
+            info,
+            from,
+            to,
+            amount,
+            ..
+        } => {
+            assert_block_field("tx.from", normalize(&expected_from), normalize(from))?;
+            assert_block_field("tx.to", normalize(&args.to), normalize(to))?;
+            assert_block_field("tx.amt", &args.amount, amount)?;
+            check_block_info(
+                info,
+                &ledger_fee,
+                &args.fee,
+                &args.memo,
+                args.created_at_time,
+            )
+        }
+        // Transfers to the minting account burn tokens and transfers from
+        // it mint tokens, both without a fee.
+        Block::Burn {
+            info, from, amount, ..
+        } => {
+            let minting_account = minting_account(ledger_env).await?;
+            assert_block_field(
+                "minting account",
+                Some(normalize(&args.to)),
+                minting_account.as_ref().map(normalize),
+            )?;
+            assert_block_field("tx.from", normalize(&expected_from), normalize(from))?;
+            assert_block_field("tx.amt", &args.amount, amount)?;
+            check_burn_or_mint_info(info, &args.memo, args.created_at_time)
+        }
+        Block::Mint { info, to, amount } => {
+            let minting_account = minting_account(ledger_env).await?;
+            assert_block_field(
+                "minting account",
+                Some(normalize(&expected_from)),
+                minting_account.as_ref().map(normalize),
+            )?;
+            assert_block_field("tx.to", normalize(&args.to), normalize(to))?;
+            assert_block_field("tx.amt", &args.amount, amount)?;
+            check_burn_or_mint_info(info, &args.memo, args.created_at_time)
+        }
+        other => bail!("expected block {} to be a transfer, got {:?}", index, other),
+    }
+    .with_context(|| format!("block {} does not match transfer {:?}", index, args))
+}
+
+/// Checks that the block with the specified index records the approval
+/// submitted by the caller. Does nothing unless the test checks blocks, see
+/// [Test::check_blocks].
+async fn check_approve_block(
+    ledger_env: &impl LedgerEnv,
+    index: &Nat,
+    caller: Principal,
+    args: &ApproveArgs,
+) -> anyhow::Result<()> {
+    if !checks_blocks() {
+        return Ok(());
+    }
+    let ledger_fee = transfer_fee(ledger_env).await?;
+    let block = get_block(ledger_env, index).await?;
+    let expected_from = Account {
+        owner: caller,
+        subaccount: args.from_subaccount,
+    };
+    match &block {
+        Block::Approve {
+            info,
+            from,
+            spender,
+            amount,
+            expected_allowance,
+            expires_at,
+        } => {
+            assert_block_field("tx.from", normalize(&expected_from), normalize(from))?;
+            assert_block_field("tx.spender", normalize(&args.spender), normalize(spender))?;
+            assert_block_field("tx.amt", &args.amount, amount)?;
+            assert_block_field(
+                "tx.expected_allowance",
+                &args.expected_allowance,
+                expected_allowance,
+            )?;
+            assert_block_field("tx.expires_at", &args.expires_at, expires_at)?;
+            check_block_info(
+                info,
+                &ledger_fee,
+                &args.fee,
+                &args.memo,
+                args.created_at_time,
+            )
+        }
+        other => bail!(
+            "expected block {} to be an approval, got {:?}",
+            index,
+            other
+        ),
+    }
+    .with_context(|| format!("block {} does not match approval {:?}", index, args))
+}
+
+/// Checks that the block with the specified index records the transfer
+/// from submitted by the caller. Does nothing unless the test checks blocks, see
+/// [Test::check_blocks].
+async fn check_transfer_from_block(
+    ledger_env: &impl LedgerEnv,
+    index: &Nat,
+    caller: Principal,
+    args: &TransferFromArgs,
+) -> anyhow::Result<()> {
+    if !checks_blocks() {
+        return Ok(());
+    }
+    let ledger_fee = transfer_fee(ledger_env).await?;
+    let block = get_block(ledger_env, index).await?;
+    let expected_spender = Account {
+        owner: caller,
+        subaccount: args.spender_subaccount,
+    };
+    match &block {
+        Block::Transfer {
+            info,
+            from,
+            to,
+            spender,
+            amount,
+        } => {
+            assert_block_field("tx.from", normalize(&args.from), normalize(from))?;
+            assert_block_field("tx.to", normalize(&args.to), normalize(to))?;
+            assert_block_field(
+                "tx.spender",
+                Some(normalize(&expected_spender)),
+                spender.as_ref().map(normalize),
+            )?;
+            assert_block_field("tx.amt", &args.amount, amount)?;
+            check_block_info(
+                info,
+                &ledger_fee,
+                &args.fee,
+                &args.memo,
+                args.created_at_time,
+            )
+        }
+        other => bail!("expected block {} to be a transfer, got {:?}", index, other),
+    }
+    .with_context(|| format!("block {} does not match transfer from {:?}", index, args))
+}
+
 async fn time_nanos(ledger_env: &impl LedgerEnv) -> u64 {
     ledger_env
         .time()
@@ -122,7 +593,15 @@ async fn setup_test_account(
     amount: Nat,
 ) -> anyhow::Result<impl LedgerEnv> {
     let balance = balance_of(ledger_env, ledger_env.principal()).await?;
-    assert!(balance >= amount.clone() + transfer_fee(ledger_env).await?);
+    let required = amount.clone() + transfer_fee(ledger_env).await?;
+    if balance < required {
+        bail!(
+            "insufficient funds: funding the test account needs {} tokens but the funding account {} holds {}",
+            required,
+            ledger_env.principal(),
+            balance
+        );
+    }
     let receiver_env = ledger_env.fork();
     let receiver = receiver_env.principal();
     assert_balance(&receiver_env, receiver, 0u8).await?;
@@ -151,11 +630,8 @@ pub async fn icrc1_test_transfer(ledger_env: impl LedgerEnv) -> TestResult {
     let balance_p1 = balance_of(&p1_env, p1_env.principal()).await?;
     let balance_p2 = balance_of(&p2_env, p2_env.principal()).await?;
 
-    let _tx = transfer(
-        &p1_env,
-        Transfer::amount_to(transfer_amount.clone(), p2_env.principal()),
-    )
-    .await??;
+    let transfer_args = Transfer::amount_to(transfer_amount.clone(), p2_env.principal());
+    transfer(&p1_env, transfer_args).await??;
 
     assert_balance(
         &ledger_env,
@@ -294,11 +770,8 @@ pub async fn icrc2_test_approve(ledger_env: impl LedgerEnv) -> anyhow::Result<Ou
     };
     let approve_amount = fee.clone();
 
-    approve(
-        &p1_env,
-        ApproveArgs::approve_amount(approve_amount.clone(), p2_env.principal()),
-    )
-    .await??;
+    let approve_args = ApproveArgs::approve_amount(approve_amount.clone(), p2_env.principal());
+    approve(&p1_env, approve_args).await??;
 
     assert_allowance(
         &p1_env,
@@ -445,6 +918,51 @@ pub async fn icrc2_test_approve_expiration(ledger_env: impl LedgerEnv) -> anyhow
     Ok(Outcome::Passed)
 }
 
+/// Checks that an approval lapses once the ledger time passes its
+/// expiration.
+pub async fn icrc2_test_approval_lapses(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
+    let time_control = match ledger_env.time_control() {
+        Some(time_control) => time_control,
+        None => return Ok(no_time_control()),
+    };
+    let fee = transfer_fee(&ledger_env).await?;
+    let transfer_amount = Nat::from(10_000u16);
+    let initial_balance: Nat = transfer_amount.clone() + fee.clone() * 2u8;
+    let p1_env = setup_test_account(&ledger_env, initial_balance).await?;
+    let p2_env = ledger_env.fork();
+
+    let expires_at = time_nanos(&ledger_env).await + PERMITTED_DRIFT;
+    approve(
+        &p1_env,
+        ApproveArgs::approve_amount(transfer_amount.clone() + fee, p2_env.principal())
+            .expires_at(expires_at),
+    )
+    .await??;
+
+    time_control
+        .set_time(SystemTime::UNIX_EPOCH + Duration::from_nanos(expires_at + TIME_MARGIN))
+        .await;
+
+    assert_allowance(&p1_env, p1_env.principal(), p2_env.principal(), 0u8, None)
+        .await
+        .context("expected the approval to lapse after its expiration")?;
+
+    match transfer_from(
+        &p2_env,
+        TransferFromArgs::transfer_from(transfer_amount, p2_env.principal(), p1_env.principal()),
+    )
+    .await?
+    {
+        Ok(_) => bail!("expected TransferFromError::InsufficientAllowance, got Ok result"),
+        Err(TransferFromError::InsufficientAllowance { allowance }) => {
+            assert_equal(allowance, Nat::from(0u8))?
+        }
+        Err(e) => return Err(e).context("expected TransferFromError::InsufficientAllowance"),
+    }
+
+    Ok(Outcome::Passed)
+}
+
 /// Checks the ICRC-2 approve endpoint for correct handling of the expected allowance functionality.
 pub async fn icrc2_test_approve_expected_allowance(
     ledger_env: impl LedgerEnv,
@@ -529,15 +1047,12 @@ pub async fn icrc2_test_transfer_from(ledger_env: impl LedgerEnv) -> anyhow::Res
 
     // Transferred amount has to be smaller than the approved amount minus the fee for transfering tokens
     let transfer_amount = approve_amount - fee.clone() - Nat::from(1u8);
-    transfer_from(
-        &p2_env,
-        TransferFromArgs::transfer_from(
-            transfer_amount.clone(),
-            p3_env.principal(),
-            p1_env.principal(),
-        ),
-    )
-    .await??;
+    let transfer_from_args = TransferFromArgs::transfer_from(
+        transfer_amount.clone(),
+        p3_env.principal(),
+        p1_env.principal(),
+    );
+    transfer_from(&p2_env, transfer_from_args).await??;
 
     assert_balance(
         &ledger_env,
@@ -828,6 +1343,97 @@ pub async fn icrc1_test_tx_deduplication(ledger_env: impl LedgerEnv) -> anyhow::
     Ok(Outcome::Passed)
 }
 
+fn no_time_control() -> Outcome {
+    Outcome::Skipped {
+        reason: "the environment cannot move the ledger time".to_string(),
+    }
+}
+
+/// Checks that deduplication holds until the end of the transaction window
+/// and that the ledger rejects the transaction as too old afterwards.
+pub async fn icrc1_test_tx_deduplication_window(
+    ledger_env: impl LedgerEnv,
+) -> anyhow::Result<Outcome> {
+    let time_control = match ledger_env.time_control() {
+        Some(time_control) => time_control,
+        None => return Ok(no_time_control()),
+    };
+    let fee = transfer_fee(&ledger_env).await?;
+    let transfer_amount = Nat::from(10_000u16);
+    let p1_env = setup_test_account(&ledger_env, transfer_amount.clone() + fee).await?;
+    let p2_env = p1_env.fork();
+
+    let created_at_time = time_nanos(&ledger_env).await;
+    let transfer_args = Transfer::amount_to(transfer_amount.clone(), p2_env.principal())
+        .created_at_time(created_at_time);
+    let txid = transfer(&p1_env, transfer_args.clone())
+        .await?
+        .context("failed to execute the dedup transfer")?;
+
+    time_control
+        .set_time(SystemTime::UNIX_EPOCH + Duration::from_nanos(created_at_time + TX_WINDOW))
+        .await;
+    assert_equal(
+        Err(TransferError::Duplicate { duplicate_of: txid }),
+        transfer(&p1_env, transfer_args.clone()).await?,
+    )
+    .context("expected deduplication at the end of the transaction window")?;
+
+    time_control
+        .advance_time(Duration::from_nanos(PERMITTED_DRIFT + TIME_MARGIN))
+        .await;
+    assert_equal(
+        Err(TransferError::TooOld),
+        transfer(&p1_env, transfer_args).await?,
+    )
+    .context("expected the transfer to be too old after the transaction window")?;
+
+    assert_balance(&p1_env, p2_env.principal(), transfer_amount).await?;
+
+    Ok(Outcome::Passed)
+}
+
+/// Checks that the ledger starts rejecting transactions as too old once
+/// their `created_at_time` is more than the transaction window and the
+/// permitted drift in the past.
+pub async fn icrc1_test_too_old_boundary(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
+    let time_control = match ledger_env.time_control() {
+        Some(time_control) => time_control,
+        None => return Ok(no_time_control()),
+    };
+    let fee = transfer_fee(&ledger_env).await?;
+    let transfer_amount = Nat::from(10_000u16);
+    let initial_balance: Nat = (transfer_amount.clone() + fee) * 2u8;
+    let p1_env = setup_test_account(&ledger_env, initial_balance).await?;
+    let p2_env = p1_env.fork();
+
+    let created_at_time = time_nanos(&ledger_env).await;
+    let transfer_args = Transfer::amount_to(transfer_amount.clone(), p2_env.principal())
+        .created_at_time(created_at_time);
+
+    time_control
+        .advance_time(Duration::from_nanos(
+            TX_WINDOW + PERMITTED_DRIFT - TIME_MARGIN,
+        ))
+        .await;
+    transfer(&p1_env, transfer_args.clone().memo(vec![1]))
+        .await?
+        .context("expected the transfer to succeed just before the boundary")?;
+
+    time_control
+        .advance_time(Duration::from_nanos(2 * TIME_MARGIN))
+        .await;
+    assert_equal(
+        Err(TransferError::TooOld),
+        transfer(&p1_env, transfer_args.memo(vec![2])).await?,
+    )
+    .context("expected the transfer to be too old just after the boundary")?;
+
+    assert_balance(&p1_env, p2_env.principal(), transfer_amount).await?;
+
+    Ok(Outcome::Passed)
+}
+
 /// Checks the ICRC-2 transfer from endpoint for correct handling of the insufficient bad fee error.
 pub async fn icrc1_test_bad_fee(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
     let fee = transfer_fee(&ledger_env).await?;
@@ -896,65 +1502,380 @@ pub async fn icrc1_test_memo_bytes_length(ledger_env: impl LedgerEnv) -> anyhow:
     }
 }
 
+/// The maximum number of most recent blocks checked by the ICRC-3 tests.
+const ICRC3_BLOCKS_TO_CHECK: u64 = 100;
+
+/// Checks that the ledger serves its most recent blocks as ICRC-3 blocks
+/// with valid parent hashes and supported block types.
+pub async fn icrc3_test_get_blocks(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
+    // Make sure the block log is not empty.
+    let fee = transfer_fee(&ledger_env).await?;
+    setup_test_account(&ledger_env, fee).await?;
+
+    let supported_block_types: Vec<String> = supported_block_types(&ledger_env)
+        .await?
+        .into_iter()
+        .map(|t| t.block_type)
+        .collect();
+
+    let log_length = log_length(&ledger_env).await?;
+    if log_length == 0 {
+        bail!("the block log is empty after a transfer");
+    }
+
+    // Read the blocks through the stream following the archives, so that
+    // archived blocks are checked too.
+    let start = log_length.saturating_sub(ICRC3_BLOCKS_TO_CHECK);
+    let mut blocks = block_stream(&ledger_env, Nat::from(start));
+
+    let mut parent: Option<(u64, Value)> = None;
+    while let Some(item) = blocks.next().await {
+        let (id, block) = item?;
+        let id = nat_to_u64(&id)?;
+        if id < start {
+            bail!("requested blocks from {}, got block {}", start, id);
+        }
+        if id >= log_length {
+            break;
+        }
+        let parent_block = match &parent {
+            Some((parent_id, _)) if id <= *parent_id => bail!(
+                "blocks are not sorted by index: block {} follows block {}",
+                id,
+                parent_id
+            ),
+            Some((parent_id, parent_block)) if id == parent_id + 1 => Some(parent_block),
+            _ => None,
+        };
+        check_block(id, &block, parent_block, &supported_block_types)?;
+        parent = Some((id, block));
+    }
+    match parent {
+        Some((last, _)) if last + 1 == log_length => {}
+        Some((last, _)) => bail!(
+            "the block log ends at block {}, expected blocks up to {}",
+            last,
+            log_length - 1
+        ),
+        None => bail!(
+            "the ledger returned none of the blocks [{}, {})",
+            start,
+            log_length
+        ),
+    }
+
+    Ok(Outcome::Passed)
+}
+
+/// Checks that the length of the block log grows with every transaction.
+pub async fn icrc3_test_log_length(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
+    let fee = transfer_fee(&ledger_env).await?;
+    let log_length_before = log_length(&ledger_env).await?;
+
+    let p1_env = setup_test_account(&ledger_env, fee.clone() * 2u8).await?;
+    let p2_env = ledger_env.fork();
+    let log_length_after_setup = log_length(&ledger_env).await?;
+    if log_length_after_setup <= log_length_before {
+        bail!(
+            "the log length did not grow after a transfer: {} -> {}",
+            log_length_before,
+            log_length_after_setup
+        );
+    }
+
+    let block_index = transfer(&p1_env, Transfer::amount_to(fee, p2_env.principal())).await??;
+    let block_index = nat_to_u64(&block_index)?;
+    let log_length_after = log_length(&ledger_env).await?;
+    if block_index < log_length_after_setup || block_index >= log_length_after {
+        bail!(
+            "the transfer was recorded at index {}, expected an index in [{}, {})",
+            block_index,
+            log_length_after_setup,
+            log_length_after
+        );
+    }
+
+    Ok(Outcome::Passed)
+}
+
+/// Checks that the ledger handles block requests outside of the block log.
+pub async fn icrc3_test_get_blocks_out_of_range(
+    ledger_env: impl LedgerEnv,
+) -> anyhow::Result<Outcome> {
+    let log_length = log_length(&ledger_env).await?;
+
+    // Other tests add blocks concurrently, so start far past the end of the log.
+    let start = log_length + 1_000_000_000;
+    let result = get_blocks(&ledger_env, blocks_request(start, 10u8)).await?;
+    if !result.blocks.is_empty() || !result.archived_blocks.is_empty() {
+        bail!(
+            "expected no blocks past the end of the log, got {} blocks and {} archived ranges",
+            result.blocks.len(),
+            result.archived_blocks.len()
+        );
+    }
+    if nat_to_u64(&result.log_length)? < log_length {
+        bail!(
+            "the log length decreased from {} to {}",
+            log_length,
+            result.log_length
+        );
+    }
+
+    let result = get_blocks(&ledger_env, blocks_request(0u8, 0u8)).await?;
+    if !result.blocks.is_empty() {
+        bail!(
+            "expected no blocks for an empty range, got {} blocks",
+            result.blocks.len()
+        );
+    }
+
+    if log_length > 0 {
+        let start = log_length - 1;
+        let result = get_blocks(&ledger_env, blocks_request(start, 10u8)).await?;
+        let current_log_length = nat_to_u64(&result.log_length)?;
+        for BlockWithId { id, .. } in result.blocks {
+            let id = nat_to_u64(&id)?;
+            if id < start || id >= current_log_length {
+                bail!(
+                    "requested blocks from {} with log length {}, got block {}",
+                    start,
+                    current_log_length,
+                    id
+                );
+            }
+        }
+    }
+
+    Ok(Outcome::Passed)
+}
+
+/// Checks that the tip certificate is signed by the network and certifies
+/// the hash of the last block in the block log.
+pub async fn icrc3_test_tip_certificate(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
+    let canister_id = match ledger_env.canister_id() {
+        Some(canister_id) => canister_id,
+        None => {
+            return Ok(Outcome::Skipped {
+                reason: "the environment does not provide the ledger canister id".to_string(),
+            })
+        }
+    };
+    let root_key = match ledger_env.root_key().await {
+        Some(root_key) => root_key,
+        None => {
+            return Ok(Outcome::Skipped {
+                reason: "the environment does not provide the root key".to_string(),
+            })
+        }
+    };
+
+    // Make sure the block log is not empty.
+    let fee = transfer_fee(&ledger_env).await?;
+    setup_test_account(&ledger_env, fee).await?;
+
+    let certificate = match get_tip_certificate(&ledger_env).await? {
+        Some(certificate) => certificate,
+        None => {
+            return Ok(Outcome::Skipped {
+                reason: "the ledger returned no tip certificate".to_string(),
+            })
+        }
+    };
+    let tip = verify_tip_certificate(&certificate, canister_id, &root_key)
+        .context("failed to verify the tip certificate")?;
+
+    let index = Nat::from(tip.last_block_index);
+    let result = get_blocks(&ledger_env, blocks_request(index.clone(), 1u8)).await?;
+    let block = match result.blocks.into_iter().find(|b| b.id == index) {
+        Some(BlockWithId { block, .. }) => block,
+        None => bail!(
+            "the ledger did not return the certified tip block {}",
+            tip.last_block_index
+        ),
+    };
+    if block.hash() != tip.last_block_hash {
+        bail!(
+            "the certified hash {} does not match the hash {} of block {}",
+            hex::encode(tip.last_block_hash),
+            hex::encode(block.hash()),
+            tip.last_block_index
+        );
+    }
+
+    Ok(Outcome::Passed)
+}
+
+/// Checks that replaying the block log yields the balances and allowances
+/// reported by the ledger.
+pub async fn icrc3_test_replay_block_log(ledger_env: impl LedgerEnv) -> anyhow::Result<Outcome> {
+    if !supports_icrc3(&ledger_env).await? {
+        return Ok(Outcome::Skipped {
+            reason: "the ledger does not support ICRC-3".to_string(),
+        });
+    }
+
+    let mismatches = replay_block_log(&ledger_env).await?;
+    if !mismatches.is_empty() {
+        let lines: Vec<String> = mismatches.iter().map(ToString::to_string).collect();
+        bail!(
+            "{} accounts do not match the block log:\n{}",
+            mismatches.len(),
+            lines.join("\n")
+        );
+    }
+
+    Ok(Outcome::Passed)
+}
+
 /// Returns the entire list of icrc1 tests.
 pub fn icrc1_test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
+    use Standard::Icrc1;
+    use Tag::*;
+
     vec![
-        test("icrc1:transfer", icrc1_test_transfer(env.clone())),
-        test("icrc1:burn", icrc1_test_burn(env.clone())),
-        test("icrc1:metadata", icrc1_test_metadata(env.clone())),
+        test("icrc1:transfer", icrc1_test_transfer(env.clone()))
+            .spec(Icrc1, "transfer_method")
+            .cost(10_000, 2),
+        test("icrc1:burn", icrc1_test_burn(env.clone()))
+            .spec(Icrc1, "minting_account")
+            .tags(&[Burn])
+            .cost(10_000, 1),
+        test("icrc1:metadata", icrc1_test_metadata(env.clone())).spec(Icrc1, "metadata_method"),
         test(
             "icrc1:supported_standards",
             icrc1_test_supported_standards(env.clone()),
-        ),
+        )
+        .spec(Icrc1, "icrc1_supported_standards"),
         test(
             "icrc1:tx_deduplication",
             icrc1_test_tx_deduplication(env.clone()),
-        ),
+        )
+        .spec(Icrc1, "transaction_deduplication")
+        .tags(&[Dedup, TimeSensitive])
+        .cost(70_000, 8),
         test(
             "icrc1:memo_bytes_length",
             icrc1_test_memo_bytes_length(env.clone()),
-        ),
+        )
+        .spec(Icrc1, "transfer_method")
+        .cost(20_000, 3),
         test(
             "icrc1:future_transfers",
             icrc1_test_future_transfer(env.clone()),
-        ),
-        test("icrc1:bad_fee", icrc1_test_bad_fee(env)),
+        )
+        .spec(Icrc1, "transaction_deduplication")
+        .tags(&[TimeSensitive])
+        .cost(10_000, 2),
+        test("icrc1:bad_fee", icrc1_test_bad_fee(env.clone()))
+            .spec(Icrc1, "transfer_method")
+            .cost(10_000, 2),
+        exclusive_test(
+            "icrc1:tx_deduplication_window",
+            icrc1_test_tx_deduplication_window(env.clone()),
+        )
+        .spec(Icrc1, "transaction_deduplication")
+        .tags(&[Dedup, TimeSensitive, Destructive])
+        .cost(10_000, 2),
+        exclusive_test("icrc1:too_old_boundary", icrc1_test_too_old_boundary(env))
+            .spec(Icrc1, "transaction_deduplication")
+            .tags(&[Dedup, TimeSensitive, Destructive])
+            .cost(20_000, 3),
     ]
 }
 
 /// Returns the entire list of icrc2 tests.
 pub fn icrc2_test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
+    use Standard::Icrc2;
+    use Tag::*;
+
     vec![
         test(
             "icrc2:supported_standards",
             icrc2_test_supported_standards(env.clone()),
-        ),
-        test("icrc2:approve", icrc2_test_approve(env.clone())),
+        )
+        .spec(Icrc2, "icrc1_supported_standards"),
+        test("icrc2:approve", icrc2_test_approve(env.clone()))
+            .spec(Icrc2, "icrc2_approve")
+            .cost(0, 3),
         test(
             "icrc2:approve_expiration",
             icrc2_test_approve_expiration(env.clone()),
-        ),
+        )
+        .spec(Icrc2, "icrc2_approve")
+        .tags(&[TimeSensitive])
+        .cost(0, 3),
         test(
             "icrc2:approve_expected_allowance",
             icrc2_test_approve_expected_allowance(env.clone()),
-        ),
-        test("icrc2:transfer_from", icrc2_test_transfer_from(env.clone())),
+        )
+        .spec(Icrc2, "icrc2_approve")
+        .cost(0, 3),
+        test("icrc2:transfer_from", icrc2_test_transfer_from(env.clone()))
+            .spec(Icrc2, "icrc2_transfer_from")
+            .cost(0, 5),
         test(
             "icrc2:transfer_from_insufficient_funds",
             icrc2_test_transfer_from_insufficient_funds(env.clone()),
-        ),
+        )
+        .spec(Icrc2, "icrc2_transfer_from")
+        .cost(0, 3),
         test(
             "icrc2:transfer_from_insufficient_allowance",
             icrc2_test_transfer_from_insufficient_allowance(env.clone()),
-        ),
+        )
+        .spec(Icrc2, "icrc2_transfer_from")
+        .cost(0, 3),
         test(
             "icrc2:transfer_from_self",
             icrc2_test_transfer_from_self(env.clone()),
-        ),
+        )
+        .spec(Icrc2, "icrc2_transfer_from")
+        .cost(0, 3),
+        exclusive_test("icrc2:approval_lapses", icrc2_test_approval_lapses(env))
+            .spec(Icrc2, "icrc2_allowance")
+            .tags(&[TimeSensitive, Destructive])
+            .cost(10_000, 3),
+    ]
+}
+
+/// Returns the entire list of icrc3 tests.
+pub fn icrc3_test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
+    use Standard::Icrc3;
+
+    vec![
+        test("icrc3:get_blocks", icrc3_test_get_blocks(env.clone()))
+            .spec(Icrc3, "icrc3_get_blocks")
+            .cost(0, 2),
+        test("icrc3:log_length", icrc3_test_log_length(env.clone()))
+            .spec(Icrc3, "icrc3_get_blocks")
+            .cost(0, 3),
+        test(
+            "icrc3:get_blocks_out_of_range",
+            icrc3_test_get_blocks_out_of_range(env.clone()),
+        )
+        .spec(Icrc3, "icrc3_get_blocks"),
+        test("icrc3:tip_certificate", icrc3_test_tip_certificate(env))
+            .spec(Icrc3, "icrc3_get_tip_certificate")
+            .cost(0, 2),
+    ]
+}
+
+/// Returns the list of tests that are too expensive to run by default
+/// because they read the entire block log.
+pub fn optional_test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
+    vec![
+        test("icrc3:replay_block_log", icrc3_test_replay_block_log(env))
+            .spec(Standard::Icrc3, "blocks-verification"),
     ]
 }
 
-pub async fn test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
+/// Returns the tests of the standards the ledger supports, keeping only
+/// those matching the filter if one is given.
+pub async fn test_suite(
+    env: impl LedgerEnv + 'static + Clone,
+    filter: Option<&TestFilter>,
+) -> Vec<Test> {
     match supported_standards(&env).await {
         Ok(standard) => {
             let mut tests = vec![];
@@ -962,9 +1883,19 @@ pub async fn test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
                 tests.append(&mut icrc1_test_suite(env.clone()));
             }
             if standard.iter().any(|std| std.name == "ICRC-2") {
-                tests.append(&mut icrc2_test_suite(env));
+                tests.append(&mut icrc2_test_suite(env.clone()));
+            }
+            let supports_icrc3 = standard.iter().any(|std| std.name == "ICRC-3");
+            if supports_icrc3 {
+                tests.append(&mut icrc3_test_suite(env));
+            }
+            if let Some(filter) = filter {
+                tests = filter.apply(tests);
             }
             tests
+                .into_iter()
+                .map(|test| test.check_blocks(supports_icrc3))
+                .collect()
         }
         Err(_) => {
             println!("No standard is supported by the given ledger: Is the endpoint 'icrc1_supported_standards' implemented correctly?");
@@ -972,44 +1903,3 @@ pub async fn test_suite(env: impl LedgerEnv + 'static + Clone) -> Vec<Test> {
         }
     }
 }
-/// Executes the list of tests concurrently and prints results using
-/// the TAP protocol (https://testanything.org/).
-pub async fn execute_tests(tests: Vec<Test>) -> bool {
-    use futures::stream::FuturesOrdered;
-
-    let mut names = Vec::new();
-    let mut futures = FuturesOrdered::new();
-
-    for test in tests.into_iter() {
-        names.push(test.name);
-        futures.push_back(test.action);
-    }
-
-    println!("TAP version 14");
-    println!("1..{}", futures.len());
-
-    let mut idx = 0;
-    let mut success = true;
-    while let Some(result) = futures.next().await {
-        match result {
-            Ok(Outcome::Passed) => {
-                println!("ok {} - {}", idx + 1, names[idx]);
-            }
-            Ok(Outcome::Skipped { reason }) => {
-                println!("ok {} - {} # SKIP {}", idx + 1, names[idx], reason);
-            }
-            Err(err) => {
-                success = false;
-
-                for line in format!("{:?}", err).lines() {
-                    println!("# {}", line);
-                }
-
-                println!("not ok {} - {}", idx + 1, names[idx]);
-            }
-        }
-        idx += 1;
-    }
-
-    success
-}
@@ -0,0 +1,311 @@
+//! Test metadata and the filters selecting tests by name and tag.
+
+use crate::Test;
+use candid::Nat;
+use std::fmt;
+use std::str::FromStr;
+
+const SPEC_URL: &str = "https://github.com/dfinity/ICRC-1/blob/main/standards";
+
+/// The standard a test checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Standard {
+    Icrc1,
+    Icrc2,
+    Icrc3,
+}
+
+impl Standard {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Standard::Icrc1 => "ICRC-1",
+            Standard::Icrc2 => "ICRC-2",
+            Standard::Icrc3 => "ICRC-3",
+        }
+    }
+
+    /// Returns the link to the section of the standard.
+    pub fn spec_link(&self, section: &str) -> String {
+        format!("{}/{}/README.md#{}", SPEC_URL, self.name(), section)
+    }
+}
+
+impl fmt::Display for Standard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A property of a test relevant for deciding whether to run it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tag {
+    /// The test checks transaction deduplication.
+    Dedup,
+    /// The test burns tokens.
+    Burn,
+    /// The test depends on the ledger time, e.g. through `created_at_time`.
+    TimeSensitive,
+    /// The test affects other users of the ledger, e.g. by moving its time.
+    Destructive,
+}
+
+impl Tag {
+    pub const ALL: [Tag; 4] = [Tag::Dedup, Tag::Burn, Tag::TimeSensitive, Tag::Destructive];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tag::Dedup => "dedup",
+            Tag::Burn => "burn",
+            Tag::TimeSensitive => "time-sensitive",
+            Tag::Destructive => "destructive",
+        }
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Tag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Tag::ALL
+            .iter()
+            .find(|tag| tag.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<_> = Tag::ALL.iter().map(Tag::name).collect();
+                format!("unknown tag {}, expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// The estimated amount of tokens a test takes from the funding account:
+/// a number of tokens plus a number of transfer fees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cost {
+    pub tokens: u64,
+    pub fees: u64,
+}
+
+impl Cost {
+    pub fn new(tokens: u64, fees: u64) -> Self {
+        Self { tokens, fees }
+    }
+
+    /// Returns the cost in tokens given the transfer fee.
+    pub fn total(&self, fee: &Nat) -> Nat {
+        Nat::from(self.tokens) + fee.clone() * self.fees
+    }
+}
+
+impl std::ops::Add for Cost {
+    type Output = Cost;
+
+    fn add(self, other: Cost) -> Cost {
+        Cost::new(self.tokens + other.tokens, self.fees + other.fees)
+    }
+}
+
+impl std::iter::Sum for Cost {
+    fn sum<I: Iterator<Item = Cost>>(costs: I) -> Cost {
+        costs.fold(Cost::default(), |total, cost| total + cost)
+    }
+}
+
+/// The description of a test.
+#[derive(Clone, Debug, Default)]
+pub struct TestMetadata {
+    pub standard: Option<Standard>,
+    pub tags: Vec<Tag>,
+    pub cost: Cost,
+    /// The link to the section of the standard the test checks.
+    pub spec: Option<String>,
+}
+
+/// Returns true if the name matches the glob pattern, where `*` matches
+/// any sequence of characters and `?` any single character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // The positions to resume from when a `*` has to match more characters.
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Selects tests by name and tag. A test runs if its name matches one of
+/// the included globs and it has one of the included tags, where empty
+/// lists include every test, and it matches none of the exclusions.
+#[derive(Clone, Debug, Default)]
+pub struct TestFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub include_tags: Vec<Tag>,
+    pub exclude_tags: Vec<Tag>,
+}
+
+impl TestFilter {
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.include.push(glob.into());
+        self
+    }
+
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.exclude.push(glob.into());
+        self
+    }
+
+    pub fn include_tag(mut self, tag: Tag) -> Self {
+        self.include_tags.push(tag);
+        self
+    }
+
+    pub fn exclude_tag(mut self, tag: Tag) -> Self {
+        self.exclude_tags.push(tag);
+        self
+    }
+
+    /// Returns true if the test with the name and the metadata should run.
+    pub fn matches(&self, name: &str, metadata: &TestMetadata) -> bool {
+        let has_tag = |tags: &[Tag]| tags.iter().any(|tag| metadata.tags.contains(tag));
+        (self.include.is_empty() || self.include.iter().any(|glob| glob_match(glob, name)))
+            && (self.include_tags.is_empty() || has_tag(&self.include_tags))
+            && !self.exclude.iter().any(|glob| glob_match(glob, name))
+            && !has_tag(&self.exclude_tags)
+    }
+
+    /// Returns the tests that should run, in the original order.
+    pub fn apply(&self, tests: Vec<Test>) -> Vec<Test> {
+        tests
+            .into_iter()
+            .filter(|test| self.matches(&test.name, &test.metadata))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(tags: &[Tag]) -> TestMetadata {
+        TestMetadata {
+            tags: tags.to_vec(),
+            ..TestMetadata::default()
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("icrc1:transfer", "icrc1:transfer"));
+        assert!(!glob_match("icrc1:transfer", "icrc1:transfers"));
+        assert!(!glob_match("icrc1:transfers", "icrc1:transfer"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "icrc2:approve"));
+        assert!(glob_match("icrc2:*", "icrc2:approve"));
+        assert!(glob_match("icrc2:*", "icrc2:"));
+        assert!(!glob_match("icrc2:*", "icrc1:transfer"));
+        assert!(glob_match("icrc?:*", "icrc3:get_blocks"));
+        assert!(!glob_match("icrc?", "icrc"));
+        assert!(!glob_match("icrc?", "icrc12"));
+        assert!(glob_match("**", "a"));
+    }
+
+    #[test]
+    fn test_glob_match_backtracks() {
+        // The first `*` must give back the characters it matched for the
+        // rest of the pattern to match.
+        assert!(glob_match("*fee", "icrc1:bad_fee_fee"));
+        assert!(glob_match("*_fee*", "icrc2:transfer_from_bad_fee"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYcZ"));
+        assert!(glob_match("*a?c", "abcaxc"));
+        assert!(!glob_match("*a?c", "abcaxd"));
+        assert!(glob_match("*:*:*", "a:b:c:d"));
+        assert!(!glob_match("*:*:*", "a:b"));
+    }
+
+    #[test]
+    fn test_filter_without_rules_matches_everything() {
+        let filter = TestFilter::default();
+        assert!(filter.matches("icrc1:transfer", &tagged(&[])));
+        assert!(filter.matches("icrc1:burn", &tagged(&[Tag::Burn])));
+    }
+
+    #[test]
+    fn test_filter_exclusions_take_precedence() {
+        let filter = TestFilter::default()
+            .include("icrc1:*")
+            .exclude("icrc1:tx_*");
+        assert!(filter.matches("icrc1:transfer", &tagged(&[])));
+        assert!(!filter.matches("icrc1:tx_deduplication", &tagged(&[])));
+        assert!(!filter.matches("icrc2:approve", &tagged(&[])));
+
+        let filter = TestFilter::default()
+            .include_tag(Tag::Dedup)
+            .exclude_tag(Tag::Destructive);
+        assert!(filter.matches("icrc1:tx_deduplication", &tagged(&[Tag::Dedup])));
+        assert!(!filter.matches(
+            "icrc1:tx_deduplication_window",
+            &tagged(&[Tag::Dedup, Tag::Destructive])
+        ));
+
+        let filter = TestFilter::default()
+            .include("icrc1:burn")
+            .exclude_tag(Tag::Burn);
+        assert!(!filter.matches("icrc1:burn", &tagged(&[Tag::Burn])));
+    }
+
+    #[test]
+    fn test_filter_by_tags() {
+        let filter = TestFilter::default()
+            .include_tag(Tag::Burn)
+            .include_tag(Tag::Dedup);
+        assert!(filter.matches("icrc1:burn", &tagged(&[Tag::Burn])));
+        assert!(filter.matches(
+            "icrc1:tx_deduplication",
+            &tagged(&[Tag::TimeSensitive, Tag::Dedup])
+        ));
+        assert!(!filter.matches("icrc1:transfer", &tagged(&[])));
+        assert!(!filter.matches("icrc1:time", &tagged(&[Tag::TimeSensitive])));
+
+        let filter = TestFilter::default().exclude_tag(Tag::Destructive);
+        assert!(filter.matches("icrc1:transfer", &tagged(&[])));
+        assert!(!filter.matches("icrc1:too_old_boundary", &tagged(&[Tag::Destructive])));
+    }
+
+    #[test]
+    fn test_filter_requires_both_a_name_and_a_tag() {
+        let filter = TestFilter::default()
+            .include("icrc2:*")
+            .include_tag(Tag::TimeSensitive);
+        assert!(filter.matches("icrc2:approval_expiry", &tagged(&[Tag::TimeSensitive])));
+        assert!(!filter.matches("icrc2:approve", &tagged(&[])));
+        assert!(!filter.matches("icrc1:time", &tagged(&[Tag::TimeSensitive])));
+    }
+}
@@ -0,0 +1,716 @@
+//! Reconstruction of balances and allowances from the ICRC-3 block log.
+
+use crate::{nat_to_u64, normalize, time_nanos};
+use anyhow::{bail, Context};
+use candid::Nat;
+use futures::StreamExt;
+use icrc1_test_env::icrc1::balance_of;
+use icrc1_test_env::icrc2::allowance;
+use icrc1_test_env::{
+    block_stream, Account, Allowance, AllowanceArgs, Block, BlockInfo, LedgerEnv,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A balance or an allowance that differs between the replayed block log
+/// and the ledger.
+#[derive(Clone, Debug)]
+pub enum ReplayMismatch {
+    Balance {
+        account: Account,
+        replayed: Nat,
+        actual: Nat,
+        /// The indices of the blocks touching the account.
+        blocks: Vec<u64>,
+    },
+    Allowance {
+        account: Account,
+        spender: Account,
+        replayed: Allowance,
+        actual: Allowance,
+        /// The indices of the blocks touching the account.
+        blocks: Vec<u64>,
+    },
+}
+
+impl fmt::Display for ReplayMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blocks = match self {
+            ReplayMismatch::Balance {
+                account,
+                replayed,
+                actual,
+                blocks,
+            } => {
+                write!(
+                    f,
+                    "balance of {}: replayed {}, ledger {}",
+                    account, replayed, actual
+                )?;
+                blocks
+            }
+            ReplayMismatch::Allowance {
+                account,
+                spender,
+                replayed,
+                actual,
+                blocks,
+            } => {
+                write!(
+                    f,
+                    "allowance of {} for {}: replayed {:?}, ledger {:?}",
+                    account, spender, replayed, actual
+                )?;
+                blocks
+            }
+        };
+        let blocks: Vec<String> = blocks.iter().map(u64::to_string).collect();
+        write!(f, " (blocks {})", blocks.join(", "))
+    }
+}
+
+/// Balances and allowances obtained by applying blocks in order.
+///
+/// Accounts are keyed by their textual encoding, which treats the default
+/// subaccount and a missing subaccount as the same account.
+#[derive(Default)]
+struct ReplayedLedger {
+    accounts: BTreeMap<String, Account>,
+    balances: BTreeMap<String, Nat>,
+    allowances: BTreeMap<(String, String), Allowance>,
+    /// The (account, spender) pairs of all approvals and spends, including
+    /// those whose allowance dropped to zero.
+    allowance_pairs: BTreeSet<(String, String)>,
+    touched_by: BTreeMap<String, Vec<u64>>,
+    fee_collectors: BTreeMap<u64, Account>,
+}
+
+impl ReplayedLedger {
+    fn touch(&mut self, index: u64, account: &Account) -> String {
+        let account = normalize(account);
+        let key = account.to_string();
+        let blocks = self.touched_by.entry(key.clone()).or_default();
+        if blocks.last() != Some(&index) {
+            blocks.push(index);
+        }
+        self.accounts.entry(key.clone()).or_insert(account);
+        key
+    }
+
+    fn credit(&mut self, key: &str, amount: &Nat) {
+        let balance = self
+            .balances
+            .entry(key.to_string())
+            .or_insert_with(|| Nat::from(0u8));
+        *balance = balance.clone() + amount.clone();
+    }
+
+    fn debit(&mut self, index: u64, key: &str, amount: &Nat) -> anyhow::Result<()> {
+        let balance = self
+            .balances
+            .entry(key.to_string())
+            .or_insert_with(|| Nat::from(0u8));
+        if *balance < *amount {
+            bail!(
+                "block {} debits {} from {} which holds only {}",
+                index,
+                amount,
+                key,
+                balance
+            );
+        }
+        *balance = balance.clone() - amount.clone();
+        Ok(())
+    }
+
+    fn use_allowance(
+        &mut self,
+        index: u64,
+        timestamp: u64,
+        from: &str,
+        spender: &str,
+        amount: &Nat,
+    ) -> anyhow::Result<()> {
+        let pair = (from.to_string(), spender.to_string());
+        self.allowance_pairs.insert(pair.clone());
+        let remaining = match self.allowances.get(&pair) {
+            Some(allowance) if !is_expired(allowance, timestamp) => &allowance.allowance,
+            _ => bail!(
+                "block {} spends {} on behalf of {} by {} without an allowance",
+                index,
+                amount,
+                from,
+                spender
+            ),
+        };
+        if remaining < amount {
+            bail!(
+                "block {} spends {} on behalf of {} by {} with an allowance of {}",
+                index,
+                amount,
+                from,
+                spender,
+                remaining
+            );
+        }
+        let remaining = remaining.clone() - amount.clone();
+        self.set_allowance(pair, remaining, None);
+        Ok(())
+    }
+
+    fn set_allowance(&mut self, pair: (String, String), amount: Nat, expires_at: Option<u64>) {
+        if amount == 0u8 {
+            self.allowances.remove(&pair);
+            return;
+        }
+        let expires_at = expires_at.or_else(|| {
+            self.allowances
+                .get(&pair)
+                .and_then(|allowance| allowance.expires_at)
+        });
+        self.allowances.insert(
+            pair,
+            Allowance {
+                allowance: amount,
+                expires_at,
+            },
+        );
+    }
+
+    /// Returns the fee collector of the block, following `fee_collector_block`,
+    /// and remembers the fee collector the block sets.
+    fn resolve_fee_collector(
+        &mut self,
+        index: u64,
+        info: &BlockInfo,
+    ) -> anyhow::Result<Option<Account>> {
+        let fee_collector = match (&info.fee_collector, info.fee_collector_block) {
+            (Some(fee_collector), _) => Some(fee_collector.clone()),
+            (None, Some(fee_collector_block)) => Some(
+                self.fee_collectors
+                    .get(&fee_collector_block)
+                    .cloned()
+                    .with_context(|| {
+                        format!(
+                            "block {} refers to block {} which does not set a fee collector",
+                            index, fee_collector_block
+                        )
+                    })?,
+            ),
+            (None, None) => None,
+        };
+        if let Some(fee_collector) = &info.fee_collector {
+            self.fee_collectors.insert(index, fee_collector.clone());
+        }
+        Ok(fee_collector)
+    }
+
+    fn apply(&mut self, index: u64, block: &Block) -> anyhow::Result<()> {
+        let info = block.info();
+        let fee = info
+            .effective_fee()
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0u8));
+        let fee_collector = self.resolve_fee_collector(index, info)?;
+
+        match block {
+            Block::Mint { to, amount, .. } => {
+                let to = self.touch(index, to);
+                self.credit(&to, amount);
+            }
+            Block::Burn {
+                from,
+                spender,
+                amount,
+                ..
+            } => {
+                let from = self.touch(index, from);
+                let total = amount.clone() + fee;
+                self.debit(index, &from, &total)?;
+                if let Some(spender) = spender {
+                    let spender = self.touch(index, spender);
+                    // Spending from one's own account does not need an allowance.
+                    if spender != from {
+                        self.use_allowance(index, info.timestamp, &from, &spender, &total)?;
+                    }
+                }
+            }
+            Block::Transfer {
+                from,
+                to,
+                spender,
+                amount,
+                ..
+            } => {
+                let from = self.touch(index, from);
+                let to = self.touch(index, to);
+                let total = amount.clone() + fee.clone();
+                self.debit(index, &from, &total)?;
+                self.credit(&to, amount);
+                if let Some(spender) = spender {
+                    let spender = self.touch(index, spender);
+                    // Spending from one's own account does not need an allowance.
+                    if spender != from {
+                        self.use_allowance(index, info.timestamp, &from, &spender, &total)?;
+                    }
+                }
+                // Transfer fees go to the fee collector if there is one and are burned otherwise.
+                if let Some(fee_collector) = fee_collector {
+                    let fee_collector = self.touch(index, &fee_collector);
+                    self.credit(&fee_collector, &fee);
+                }
+            }
+            Block::Approve {
+                from,
+                spender,
+                amount,
+                expires_at,
+                ..
+            } => {
+                let from = self.touch(index, from);
+                let spender = self.touch(index, spender);
+                self.debit(index, &from, &fee)?;
+                self.allowance_pairs.insert((from.clone(), spender.clone()));
+                self.allowances.remove(&(from.clone(), spender.clone()));
+                self.set_allowance((from, spender), amount.clone(), *expires_at);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_expired(allowance: &Allowance, now: u64) -> bool {
+    allowance
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= now)
+}
+
+/// Returns the accounts whose balances or allowances the block changes,
+/// given the fee collector the block credits.
+fn touched_accounts<'a>(block: &'a Block, fee_collector: Option<&'a Account>) -> Vec<&'a Account> {
+    let mut accounts = match block {
+        Block::Mint { to, .. } => vec![to],
+        Block::Burn { from, spender, .. } => std::iter::once(from).chain(spender).collect(),
+        Block::Transfer {
+            from, to, spender, ..
+        } => vec![from, to].into_iter().chain(spender).collect(),
+        Block::Approve { from, spender, .. } => vec![from, spender],
+    };
+    accounts.extend(fee_collector);
+    accounts
+}
+
+/// Replays the entire block log of the ledger into a table of balances and
+/// allowances and compares it with `icrc1_balance_of` and `icrc2_allowance`
+/// for every account and every (account, spender) pair that appears in the
+/// log. Pairs whose allowance was used up or reset must have no allowance.
+///
+/// Accounts touched by blocks added while the function queries the ledger
+/// are not compared.
+pub async fn replay_block_log(ledger_env: &impl LedgerEnv) -> anyhow::Result<Vec<ReplayMismatch>> {
+    let mut replayed = ReplayedLedger::default();
+    let mut log_length = 0;
+    let mut blocks = block_stream(ledger_env, Nat::from(0u8));
+    while let Some(block) = blocks.next().await {
+        let (index, block) = block?;
+        let index = nat_to_u64(&index)?;
+        let block =
+            Block::try_from(&block).with_context(|| format!("failed to decode block {}", index))?;
+        replayed
+            .apply(index, &block)
+            .with_context(|| format!("failed to replay block {}", index))?;
+        log_length = index + 1;
+    }
+
+    let mut mismatches = vec![];
+    for (key, account) in replayed.accounts.iter() {
+        let actual = balance_of(ledger_env, account.clone()).await?;
+        let expected = replayed
+            .balances
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0u8));
+        if actual != expected {
+            mismatches.push(ReplayMismatch::Balance {
+                account: account.clone(),
+                replayed: expected,
+                actual,
+                blocks: replayed.touched_by[key].clone(),
+            });
+        }
+    }
+
+    let now = time_nanos(ledger_env).await;
+    for (from, spender) in replayed.allowance_pairs.iter() {
+        let expected = replayed
+            .allowances
+            .get(&(from.clone(), spender.clone()))
+            .cloned()
+            .unwrap_or(Allowance {
+                allowance: Nat::from(0u8),
+                expires_at: None,
+            });
+        let actual = allowance(
+            ledger_env,
+            AllowanceArgs {
+                account: replayed.accounts[from].clone(),
+                spender: replayed.accounts[spender].clone(),
+            },
+        )
+        .await?;
+        // The expiration of an allowance that dropped to zero does not matter.
+        let matches = if is_expired(&expected, now) || expected.allowance == 0u8 {
+            actual.allowance == 0u8
+        } else {
+            actual == expected
+        };
+        if !matches {
+            mismatches.push(ReplayMismatch::Allowance {
+                account: replayed.accounts[from].clone(),
+                spender: replayed.accounts[spender].clone(),
+                replayed: expected,
+                actual,
+                blocks: replayed.touched_by[from].clone(),
+            });
+        }
+    }
+
+    // Other tests keep adding blocks, so skip the accounts whose balances
+    // and allowances could have changed after the replay.
+    let mut changed = BTreeSet::new();
+    let mut blocks = block_stream(ledger_env, Nat::from(log_length));
+    while let Some(block) = blocks.next().await {
+        let (index, block) = block?;
+        let block =
+            Block::try_from(&block).with_context(|| format!("failed to decode block {}", index))?;
+        let fee_collector = replayed.resolve_fee_collector(nat_to_u64(&index)?, block.info())?;
+        changed.extend(
+            touched_accounts(&block, fee_collector.as_ref())
+                .into_iter()
+                .map(|account| normalize(account).to_string()),
+        );
+    }
+    mismatches.retain(|mismatch| match mismatch {
+        ReplayMismatch::Balance { account, .. } => !changed.contains(&account.to_string()),
+        ReplayMismatch::Allowance {
+            account, spender, ..
+        } => !changed.contains(&account.to_string()) && !changed.contains(&spender.to_string()),
+    });
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use icrc1_test_env::BlockInfo;
+
+    const FEE: u64 = 10;
+
+    fn account(n: u8) -> Account {
+        Account::from(Principal::from_slice(&[n]))
+    }
+
+    fn info(timestamp: u64, fee: u64) -> BlockInfo {
+        BlockInfo {
+            btype: None,
+            parent_hash: None,
+            timestamp,
+            fee: Some(Nat::from(fee)),
+            tx_fee: None,
+            fee_collector: None,
+            fee_collector_block: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn mint(to: u8, amount: u64) -> Block {
+        Block::Mint {
+            info: info(0, 0),
+            to: account(to),
+            amount: Nat::from(amount),
+        }
+    }
+
+    fn burn(from: u8, spender: Option<u8>, amount: u64) -> Block {
+        Block::Burn {
+            info: info(0, 0),
+            from: account(from),
+            spender: spender.map(account),
+            amount: Nat::from(amount),
+        }
+    }
+
+    fn transfer(from: u8, to: u8, amount: u64) -> Block {
+        transfer_at(0, from, to, None, amount)
+    }
+
+    fn transfer_from(spender: u8, from: u8, to: u8, amount: u64) -> Block {
+        transfer_at(0, from, to, Some(spender), amount)
+    }
+
+    fn transfer_at(timestamp: u64, from: u8, to: u8, spender: Option<u8>, amount: u64) -> Block {
+        Block::Transfer {
+            info: info(timestamp, FEE),
+            from: account(from),
+            to: account(to),
+            spender: spender.map(account),
+            amount: Nat::from(amount),
+        }
+    }
+
+    fn approve(from: u8, spender: u8, amount: u64, expires_at: Option<u64>) -> Block {
+        Block::Approve {
+            info: info(0, FEE),
+            from: account(from),
+            spender: account(spender),
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at,
+        }
+    }
+
+    fn with_fee_collector(mut block: Block, fee_collector: u8) -> Block {
+        if let Block::Transfer { info, .. } = &mut block {
+            info.fee_collector = Some(account(fee_collector));
+        }
+        block
+    }
+
+    struct Case {
+        name: &'static str,
+        blocks: Vec<Block>,
+        /// The expected non-zero balances, or the expected error.
+        balances: Result<Vec<(u8, u64)>, &'static str>,
+        allowances: Vec<(u8, u8, u64)>,
+    }
+
+    fn replay(blocks: &[Block]) -> anyhow::Result<ReplayedLedger> {
+        let mut replayed = ReplayedLedger::default();
+        for (index, block) in blocks.iter().enumerate() {
+            replayed.apply(index as u64, block)?;
+        }
+        Ok(replayed)
+    }
+
+    #[test]
+    fn test_replayed_ledger() {
+        let cases = vec![
+            Case {
+                name: "mint credits the account",
+                blocks: vec![mint(1, 100), mint(1, 50)],
+                balances: Ok(vec![(1, 150)]),
+                allowances: vec![],
+            },
+            Case {
+                name: "burn debits the account",
+                blocks: vec![mint(1, 100), burn(1, None, 40)],
+                balances: Ok(vec![(1, 60)]),
+                allowances: vec![],
+            },
+            Case {
+                name: "burn cannot exceed the balance",
+                blocks: vec![mint(1, 100), burn(1, None, 101)],
+                balances: Err("which holds only 100"),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer burns the fee without a fee collector",
+                blocks: vec![mint(1, 100), transfer(1, 2, 30)],
+                balances: Ok(vec![(1, 60), (2, 30)]),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer credits the fee to the fee collector",
+                blocks: vec![mint(1, 100), with_fee_collector(transfer(1, 2, 30), 9)],
+                balances: Ok(vec![(1, 60), (2, 30), (9, 10)]),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer cannot exceed the balance with the fee",
+                blocks: vec![mint(1, 100), transfer(1, 2, 95)],
+                balances: Err("debits 105"),
+                allowances: vec![],
+            },
+            Case {
+                name: "approve charges the fee and sets the allowance",
+                blocks: vec![mint(1, 100), approve(1, 2, 50, None)],
+                balances: Ok(vec![(1, 90)]),
+                allowances: vec![(1, 2, 50)],
+            },
+            Case {
+                name: "approve replaces the allowance",
+                blocks: vec![
+                    mint(1, 100),
+                    approve(1, 2, 50, None),
+                    approve(1, 2, 20, None),
+                ],
+                balances: Ok(vec![(1, 80)]),
+                allowances: vec![(1, 2, 20)],
+            },
+            Case {
+                name: "transfer_from spends the amount and the fee from the allowance",
+                blocks: vec![
+                    mint(1, 100),
+                    approve(1, 2, 50, None),
+                    transfer_from(2, 1, 3, 20),
+                ],
+                balances: Ok(vec![(1, 60), (3, 20)]),
+                allowances: vec![(1, 2, 20)],
+            },
+            Case {
+                name: "transfer_from removes a used up allowance",
+                blocks: vec![
+                    mint(1, 100),
+                    approve(1, 2, 30, None),
+                    transfer_from(2, 1, 3, 20),
+                ],
+                balances: Ok(vec![(1, 60), (3, 20)]),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer_from cannot exceed the allowance",
+                blocks: vec![
+                    mint(1, 100),
+                    approve(1, 2, 25, None),
+                    transfer_from(2, 1, 3, 20),
+                ],
+                balances: Err("with an allowance of 25"),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer_from needs an allowance",
+                blocks: vec![mint(1, 100), transfer_from(2, 1, 3, 20)],
+                balances: Err("without an allowance"),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer_from cannot use an expired allowance",
+                blocks: vec![
+                    mint(1, 100),
+                    approve(1, 2, 50, Some(1_000)),
+                    transfer_at(1_000, 1, 3, Some(2), 20),
+                ],
+                balances: Err("without an allowance"),
+                allowances: vec![],
+            },
+            Case {
+                name: "transfer_from uses an allowance before it expires",
+                blocks: vec![
+                    mint(1, 100),
+                    approve(1, 2, 50, Some(1_000)),
+                    transfer_at(999, 1, 3, Some(2), 20),
+                ],
+                balances: Ok(vec![(1, 60), (3, 20)]),
+                allowances: vec![(1, 2, 20)],
+            },
+            Case {
+                name: "transfer_from by the owner needs no allowance",
+                blocks: vec![mint(1, 100), transfer_from(1, 1, 3, 20)],
+                balances: Ok(vec![(1, 70), (3, 20)]),
+                allowances: vec![],
+            },
+            Case {
+                name: "burn by a spender uses the allowance",
+                blocks: vec![mint(1, 100), approve(1, 2, 50, None), burn(1, Some(2), 20)],
+                balances: Ok(vec![(1, 70)]),
+                allowances: vec![(1, 2, 30)],
+            },
+            Case {
+                name: "burn by the owner as spender needs no allowance",
+                blocks: vec![mint(1, 100), burn(1, Some(1), 20)],
+                balances: Ok(vec![(1, 80)]),
+                allowances: vec![],
+            },
+        ];
+
+        for case in cases {
+            let name = case.name;
+            let replayed = replay(&case.blocks);
+            let expected_balances = match case.balances {
+                Ok(balances) => balances,
+                Err(expected) => {
+                    let err = replayed.err().unwrap_or_else(|| {
+                        panic!("{}: expected an error containing {:?}", case.name, expected)
+                    });
+                    assert!(
+                        err.to_string().contains(expected),
+                        "{}: expected an error containing {:?}, got {}",
+                        case.name,
+                        expected,
+                        err
+                    );
+                    continue;
+                }
+            };
+            let replayed =
+                replayed.unwrap_or_else(|e| panic!("{}: unexpected error {:#}", name, e));
+
+            let balances: BTreeMap<String, Nat> = replayed
+                .balances
+                .iter()
+                .filter(|(_, balance)| **balance != 0u8)
+                .map(|(key, balance)| (key.clone(), balance.clone()))
+                .collect();
+            let expected: BTreeMap<String, Nat> = expected_balances
+                .into_iter()
+                .map(|(n, balance)| (account(n).to_string(), Nat::from(balance)))
+                .collect();
+            assert_eq!(balances, expected, "{}: balances", case.name);
+
+            let allowances: BTreeMap<(String, String), Nat> = replayed
+                .allowances
+                .iter()
+                .map(|(pair, allowance)| (pair.clone(), allowance.allowance.clone()))
+                .collect();
+            let expected: BTreeMap<(String, String), Nat> = case
+                .allowances
+                .into_iter()
+                .map(|(from, spender, amount)| {
+                    (
+                        (account(from).to_string(), account(spender).to_string()),
+                        Nat::from(amount),
+                    )
+                })
+                .collect();
+            assert_eq!(allowances, expected, "{}: allowances", case.name);
+        }
+    }
+
+    #[test]
+    fn test_replayed_ledger_keeps_the_pairs_of_zero_allowances() {
+        let replayed = replay(&[
+            mint(1, 100),
+            approve(1, 2, 50, None),
+            approve(1, 2, 0, None),
+            approve(1, 3, 30, None),
+            transfer_from(3, 1, 4, 20),
+        ])
+        .unwrap();
+        assert!(replayed.allowances.is_empty());
+        let pairs: Vec<(String, String)> = replayed.allowance_pairs.into_iter().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (account(1).to_string(), account(2).to_string()),
+                (account(1).to_string(), account(3).to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_touched_accounts_follow_the_fee_collector_block() {
+        let mut replayed =
+            replay(&[mint(1, 100), with_fee_collector(transfer(1, 2, 30), 9)]).unwrap();
+        let mut block = transfer(1, 2, 30);
+        if let Block::Transfer { info, .. } = &mut block {
+            info.fee_collector_block = Some(1);
+        }
+        let fee_collector = replayed.resolve_fee_collector(2, block.info()).unwrap();
+        assert_eq!(fee_collector, Some(account(9)));
+        assert!(touched_accounts(&block, fee_collector.as_ref()).contains(&&account(9)));
+    }
+}
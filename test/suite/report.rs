@@ -0,0 +1,672 @@
+//! Reporters writing the test results in TAP, JUnit XML and JSON.
+
+use crate::{Standard, TestMetadata};
+use serde_json::json;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// The test an event is about.
+#[derive(Clone, Copy, Debug)]
+pub struct TestCase<'a> {
+    /// The position of the test in the run, starting from 1.
+    pub number: usize,
+    pub name: &'a str,
+    pub metadata: &'a TestMetadata,
+}
+
+/// Why a test failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestFailure {
+    /// The error followed by its causes, outermost first.
+    pub chain: Vec<String>,
+}
+
+impl TestFailure {
+    pub fn message(&self) -> &str {
+        self.chain.first().map_or("", String::as_str)
+    }
+}
+
+impl From<&anyhow::Error> for TestFailure {
+    fn from(err: &anyhow::Error) -> Self {
+        Self {
+            chain: err.chain().map(|cause| cause.to_string()).collect(),
+        }
+    }
+}
+
+/// Receives the events of a test run.
+///
+/// Tests start and finish in any order when they run concurrently, and
+/// each result is reported as soon as the test finishes.
+pub trait Reporter {
+    /// Called once before any test starts, with the number of tests and the
+    /// header lines describing the run.
+    fn run_started(&mut self, tests: usize, header: &[String]) -> io::Result<()>;
+
+    fn test_started(&mut self, _test: &TestCase<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn test_passed(&mut self, test: &TestCase<'_>, duration: Duration) -> io::Result<()>;
+
+    fn test_skipped(
+        &mut self,
+        test: &TestCase<'_>,
+        reason: &str,
+        duration: Duration,
+    ) -> io::Result<()>;
+
+    fn test_failed(
+        &mut self,
+        test: &TestCase<'_>,
+        failure: &TestFailure,
+        duration: Duration,
+    ) -> io::Result<()>;
+
+    /// Called once after all tests finished.
+    fn run_finished(&mut self, duration: Duration) -> io::Result<()>;
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Quotes the string as a JSON string, which is also a valid YAML scalar.
+fn quote(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+/// Escapes the string for a TAP test point description or directive, which
+/// end at a `#` and at the end of the line.
+fn escape_tap(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '#' => escaped.push_str("\\#"),
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes the results using the TAP 14 protocol
+/// (https://testanything.org/), with the details of failures in YAML
+/// diagnostic blocks.
+pub struct TapReporter<W> {
+    out: W,
+}
+
+impl<W: Write> TapReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl TapReporter<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write> Reporter for TapReporter<W> {
+    fn run_started(&mut self, tests: usize, header: &[String]) -> io::Result<()> {
+        writeln!(self.out, "TAP version 14")?;
+        for line in header {
+            writeln!(self.out, "# {}", line)?;
+        }
+        writeln!(self.out, "1..{}", tests)?;
+        self.out.flush()
+    }
+
+    fn test_passed(&mut self, test: &TestCase<'_>, _duration: Duration) -> io::Result<()> {
+        writeln!(self.out, "ok {} - {}", test.number, escape_tap(test.name))?;
+        self.out.flush()
+    }
+
+    fn test_skipped(
+        &mut self,
+        test: &TestCase<'_>,
+        reason: &str,
+        _duration: Duration,
+    ) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "ok {} - {} # SKIP {}",
+            test.number,
+            escape_tap(test.name),
+            escape_tap(reason)
+        )?;
+        self.out.flush()
+    }
+
+    fn test_failed(
+        &mut self,
+        test: &TestCase<'_>,
+        failure: &TestFailure,
+        duration: Duration,
+    ) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "not ok {} - {}",
+            test.number,
+            escape_tap(test.name)
+        )?;
+        writeln!(self.out, "  ---")?;
+        writeln!(self.out, "  message: {}", quote(failure.message()))?;
+        writeln!(self.out, "  severity: fail")?;
+        writeln!(self.out, "  duration_ms: {:.3}", millis(duration))?;
+        if failure.chain.len() > 1 {
+            writeln!(self.out, "  caused_by:")?;
+            for cause in failure.chain.iter().skip(1) {
+                writeln!(self.out, "    - {}", quote(cause))?;
+            }
+        }
+        if let Some(spec) = &test.metadata.spec {
+            writeln!(self.out, "  spec: {}", quote(spec))?;
+        }
+        writeln!(self.out, "  ...")?;
+        self.out.flush()
+    }
+
+    fn run_finished(&mut self, _duration: Duration) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The result of a finished test, kept by the reporters writing the whole
+/// report at the end of the run.
+#[derive(Clone, Debug)]
+struct FinishedTest {
+    number: usize,
+    name: String,
+    metadata: TestMetadata,
+    duration: Duration,
+    status: Status,
+}
+
+#[derive(Clone, Debug)]
+enum Status {
+    Passed,
+    Skipped(String),
+    Failed(TestFailure),
+}
+
+/// Collects the finished tests of a run in the order of the test numbers.
+#[derive(Default)]
+struct Collected {
+    header: Vec<String>,
+    tests: Vec<FinishedTest>,
+}
+
+impl Collected {
+    fn record(
+        &mut self,
+        test: &TestCase<'_>,
+        duration: Duration,
+        status: Status,
+    ) -> io::Result<()> {
+        self.tests.push(FinishedTest {
+            number: test.number,
+            name: test.name.to_string(),
+            metadata: test.metadata.clone(),
+            duration,
+            status,
+        });
+        self.tests.sort_by_key(|test| test.number);
+        Ok(())
+    }
+
+    fn count(&self, f: impl Fn(&Status) -> bool) -> usize {
+        self.tests.iter().filter(|test| f(&test.status)).count()
+    }
+
+    fn failures(&self) -> usize {
+        self.count(|status| matches!(status, Status::Failed(_)))
+    }
+
+    fn skipped(&self) -> usize {
+        self.count(|status| matches!(status, Status::Skipped(_)))
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes the results as a JUnit XML report at the end of the run.
+pub struct JUnitReporter<W> {
+    out: W,
+    collected: Collected,
+}
+
+impl<W: Write> JUnitReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            collected: Collected::default(),
+        }
+    }
+}
+
+impl<W: Write> Reporter for JUnitReporter<W> {
+    fn run_started(&mut self, _tests: usize, header: &[String]) -> io::Result<()> {
+        self.collected.header = header.to_vec();
+        Ok(())
+    }
+
+    fn test_passed(&mut self, test: &TestCase<'_>, duration: Duration) -> io::Result<()> {
+        self.collected.record(test, duration, Status::Passed)
+    }
+
+    fn test_skipped(
+        &mut self,
+        test: &TestCase<'_>,
+        reason: &str,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let status = Status::Skipped(reason.to_string());
+        self.collected.record(test, duration, status)
+    }
+
+    fn test_failed(
+        &mut self,
+        test: &TestCase<'_>,
+        failure: &TestFailure,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let status = Status::Failed(failure.clone());
+        self.collected.record(test, duration, status)
+    }
+
+    fn run_finished(&mut self, duration: Duration) -> io::Result<()> {
+        let collected = &self.collected;
+        let out = &mut self.out;
+        let counts = format!(
+            r#"tests="{}" failures="{}" errors="0" skipped="{}" time="{:.3}""#,
+            collected.tests.len(),
+            collected.failures(),
+            collected.skipped(),
+            duration.as_secs_f64()
+        );
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(out, r#"<testsuites name="icrc1-test-suite" {}>"#, counts)?;
+        writeln!(out, r#"  <testsuite name="icrc1-test-suite" {}>"#, counts)?;
+        if !collected.header.is_empty() {
+            writeln!(out, "    <properties>")?;
+            for line in collected.header.iter() {
+                writeln!(
+                    out,
+                    r#"      <property name="header" value="{}"/>"#,
+                    escape_xml(line)
+                )?;
+            }
+            writeln!(out, "    </properties>")?;
+        }
+        for test in collected.tests.iter() {
+            let classname = test
+                .metadata
+                .standard
+                .map_or("icrc1-test-suite", |standard| standard.name());
+            write!(
+                out,
+                r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+                escape_xml(&test.name),
+                classname,
+                test.duration.as_secs_f64()
+            )?;
+            match &test.status {
+                Status::Passed => writeln!(out, "/>")?,
+                Status::Skipped(reason) => {
+                    writeln!(out, ">")?;
+                    writeln!(out, r#"      <skipped message="{}"/>"#, escape_xml(reason))?;
+                    writeln!(out, "    </testcase>")?;
+                }
+                Status::Failed(failure) => {
+                    writeln!(out, ">")?;
+                    writeln!(
+                        out,
+                        r#"      <failure message="{}">{}</failure>"#,
+                        escape_xml(failure.message()),
+                        escape_xml(&failure.chain.join("\nCaused by: "))
+                    )?;
+                    writeln!(out, "    </testcase>")?;
+                }
+            }
+        }
+        writeln!(out, "  </testsuite>")?;
+        writeln!(out, "</testsuites>")?;
+        out.flush()
+    }
+}
+
+/// Writes the results as a JSON document at the end of the run.
+pub struct JsonReporter<W> {
+    out: W,
+    collected: Collected,
+}
+
+impl<W: Write> JsonReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            collected: Collected::default(),
+        }
+    }
+}
+
+impl<W: Write> Reporter for JsonReporter<W> {
+    fn run_started(&mut self, _tests: usize, header: &[String]) -> io::Result<()> {
+        self.collected.header = header.to_vec();
+        Ok(())
+    }
+
+    fn test_passed(&mut self, test: &TestCase<'_>, duration: Duration) -> io::Result<()> {
+        self.collected.record(test, duration, Status::Passed)
+    }
+
+    fn test_skipped(
+        &mut self,
+        test: &TestCase<'_>,
+        reason: &str,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let status = Status::Skipped(reason.to_string());
+        self.collected.record(test, duration, status)
+    }
+
+    fn test_failed(
+        &mut self,
+        test: &TestCase<'_>,
+        failure: &TestFailure,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let status = Status::Failed(failure.clone());
+        self.collected.record(test, duration, status)
+    }
+
+    fn run_finished(&mut self, duration: Duration) -> io::Result<()> {
+        let collected = &self.collected;
+        let tests: Vec<_> = collected
+            .tests
+            .iter()
+            .map(|test| {
+                let mut entry = json!({
+                    "number": test.number,
+                    "name": test.name,
+                    "standard": test.metadata.standard.as_ref().map(Standard::name),
+                    "tags": test.metadata.tags.iter().map(|tag| tag.name()).collect::<Vec<_>>(),
+                    "spec": test.metadata.spec,
+                    "duration_ms": millis(test.duration),
+                });
+                match &test.status {
+                    Status::Passed => entry["status"] = json!("passed"),
+                    Status::Skipped(reason) => {
+                        entry["status"] = json!("skipped");
+                        entry["reason"] = json!(reason);
+                    }
+                    Status::Failed(failure) => {
+                        entry["status"] = json!("failed");
+                        entry["error_chain"] = json!(failure.chain);
+                    }
+                }
+                entry
+            })
+            .collect();
+        let failed = collected.failures();
+        let skipped = collected.skipped();
+        let report = json!({
+            "header": collected.header,
+            "duration_ms": millis(duration),
+            "summary": {
+                "passed": collected.tests.len() - failed - skipped,
+                "skipped": skipped,
+                "failed": failed,
+            },
+            "tests": tests,
+        });
+        serde_json::to_writer_pretty(&mut self.out, &report)?;
+        writeln!(self.out)?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Tag;
+
+    /// Reports a passed, a skipped and a failed test whose names, messages
+    /// and header need quoting or escaping, and returns the output.
+    fn report(reporter: impl FnOnce(&mut Vec<u8>) -> Box<dyn Reporter + '_>) -> String {
+        let passed = TestMetadata {
+            standard: Some(Standard::Icrc1),
+            spec: Some(Standard::Icrc1.spec_link("icrc1_transfer")),
+            ..TestMetadata::default()
+        };
+        let skipped = TestMetadata {
+            standard: Some(Standard::Icrc1),
+            tags: vec![Tag::Burn],
+            ..TestMetadata::default()
+        };
+        let failed = TestMetadata {
+            standard: Some(Standard::Icrc2),
+            tags: vec![Tag::Dedup, Tag::TimeSensitive],
+            spec: Some(Standard::Icrc2.spec_link("icrc2_approve")),
+            ..TestMetadata::default()
+        };
+        let failure = TestFailure {
+            chain: vec![
+                "expected deduplication: Ok(1) ≠ Err(Duplicate)".to_string(),
+                "the ledger said: \"no\" # <a> & 'b'\nsecond line".to_string(),
+            ],
+        };
+        let header = vec!["seed: 7".to_string(), "ledger <a> & \"b\"".to_string()];
+
+        let mut out = vec![];
+        {
+            let mut reporter = reporter(&mut out);
+            let case = |number, name, metadata| TestCase {
+                number,
+                name,
+                metadata,
+            };
+            reporter.run_started(3, &header).unwrap();
+            reporter
+                .test_passed(
+                    &case(1, "icrc1:transfer", &passed),
+                    Duration::from_micros(1500),
+                )
+                .unwrap();
+            reporter
+                .test_skipped(
+                    &case(2, "icrc1:burn", &skipped),
+                    "no minting account: <none> & \"nothing\"",
+                    Duration::from_millis(2),
+                )
+                .unwrap();
+            reporter
+                .test_failed(
+                    &case(3, "icrc2:approve<&>", &failed),
+                    &failure,
+                    Duration::from_millis(3),
+                )
+                .unwrap();
+            reporter.run_finished(Duration::from_millis(10)).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_tap_report() {
+        let report = report(|out| Box::new(TapReporter::new(out)));
+        assert_eq!(
+            report,
+            r#"TAP version 14
+# seed: 7
+# ledger <a> & "b"
+1..3
+ok 1 - icrc1:transfer
+ok 2 - icrc1:burn # SKIP no minting account: <none> & "nothing"
+not ok 3 - icrc2:approve<&>
+  ---
+  message: "expected deduplication: Ok(1) ≠ Err(Duplicate)"
+  severity: fail
+  duration_ms: 3.000
+  caused_by:
+    - "the ledger said: \"no\" # <a> & 'b'\nsecond line"
+  spec: "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-2/README.md#icrc2_approve"
+  ...
+"#
+        );
+    }
+
+    #[test]
+    fn test_tap_report_escapes_descriptions() {
+        let metadata = TestMetadata::default();
+        let case = |number, name| TestCase {
+            number,
+            name,
+            metadata: &metadata,
+        };
+        let mut out = vec![];
+        {
+            let mut reporter = TapReporter::new(&mut out);
+            reporter.run_started(3, &[]).unwrap();
+            reporter
+                .test_passed(&case(1, "icrc1:#1 \\ta"), Duration::from_millis(1))
+                .unwrap();
+            reporter
+                .test_skipped(
+                    &case(2, "icrc1:two\nlines"),
+                    "# TODO C:\\ledger\r\nnext",
+                    Duration::from_millis(1),
+                )
+                .unwrap();
+            reporter
+                .test_failed(
+                    &case(3, "icrc2:# SKIP"),
+                    &TestFailure {
+                        chain: vec!["failed".to_string()],
+                    },
+                    Duration::from_millis(1),
+                )
+                .unwrap();
+            reporter.run_finished(Duration::from_millis(3)).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"TAP version 14
+1..3
+ok 1 - icrc1:\#1 \\ta
+ok 2 - icrc1:two lines # SKIP \# TODO C:\\ledger  next
+not ok 3 - icrc2:\# SKIP
+  ---
+  message: "failed"
+  severity: fail
+  duration_ms: 1.000
+  ...
+"#
+        );
+    }
+
+    #[test]
+    fn test_junit_report() {
+        let report = report(|out| Box::new(JUnitReporter::new(out)));
+        assert_eq!(
+            report,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="icrc1-test-suite" tests="3" failures="1" errors="0" skipped="1" time="0.010">
+  <testsuite name="icrc1-test-suite" tests="3" failures="1" errors="0" skipped="1" time="0.010">
+    <properties>
+      <property name="header" value="seed: 7"/>
+      <property name="header" value="ledger &lt;a&gt; &amp; &quot;b&quot;"/>
+    </properties>
+    <testcase name="icrc1:transfer" classname="ICRC-1" time="0.002"/>
+    <testcase name="icrc1:burn" classname="ICRC-1" time="0.002">
+      <skipped message="no minting account: &lt;none&gt; &amp; &quot;nothing&quot;"/>
+    </testcase>
+    <testcase name="icrc2:approve&lt;&amp;&gt;" classname="ICRC-2" time="0.003">
+      <failure message="expected deduplication: Ok(1) ≠ Err(Duplicate)">expected deduplication: Ok(1) ≠ Err(Duplicate)
+Caused by: the ledger said: &quot;no&quot; # &lt;a&gt; &amp; &apos;b&apos;
+second line</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn test_json_report() {
+        let report = report(|out| Box::new(JsonReporter::new(out)));
+        assert_eq!(
+            report,
+            r#"{
+  "duration_ms": 10.0,
+  "header": [
+    "seed: 7",
+    "ledger <a> & \"b\""
+  ],
+  "summary": {
+    "failed": 1,
+    "passed": 1,
+    "skipped": 1
+  },
+  "tests": [
+    {
+      "duration_ms": 1.5,
+      "name": "icrc1:transfer",
+      "number": 1,
+      "spec": "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-1/README.md#icrc1_transfer",
+      "standard": "ICRC-1",
+      "status": "passed",
+      "tags": []
+    },
+    {
+      "duration_ms": 2.0,
+      "name": "icrc1:burn",
+      "number": 2,
+      "reason": "no minting account: <none> & \"nothing\"",
+      "spec": null,
+      "standard": "ICRC-1",
+      "status": "skipped",
+      "tags": [
+        "burn"
+      ]
+    },
+    {
+      "duration_ms": 3.0,
+      "error_chain": [
+        "expected deduplication: Ok(1) ≠ Err(Duplicate)",
+        "the ledger said: \"no\" # <a> & 'b'\nsecond line"
+      ],
+      "name": "icrc2:approve<&>",
+      "number": 3,
+      "spec": "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-2/README.md#icrc2_approve",
+      "standard": "ICRC-2",
+      "status": "failed",
+      "tags": [
+        "dedup",
+        "time-sensitive"
+      ]
+    }
+  ]
+}
+"#
+        );
+    }
+}